@@ -1,536 +1,9144 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::curve25519::edwards::{validate_edwards, PodEdwardsPoint};
+use anchor_lang::solana_program::hash::hash;
+use anchor_lang::solana_program::instruction::{get_stack_height, AccountMeta, Instruction, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::program::{invoke, set_return_data};
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::solana_program::sysvar::instructions::{
+    self as instructions_sysvar, load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::system_program;
+use anchor_spl::token::{self, CloseAccount, Mint, Token, TokenAccount, TransferChecked};
+use pyth_sdk_solana::state::{load_price_account, PriceStatus};
 use pyth_sdk_solana::{load_price_feed_from_account_info, Price, PriceFeed};
-use std::mem::size_of;
 
 declare_id!("EhUtRgu9iEbZXXRpEvDj6n1wnQRjMi2SERDo3c6bmN2c");
 
+// Denominator for every basis-point quantity (fees, PnL percentages, funding index)
+#[constant]
+pub const BPS_SCALE: u64 = 10_000;
+
+// Trading fee charged on position size when a position is closed or liquidated (0.1%)
+#[constant]
+pub const CLOSE_FEE_BPS: u64 = 10;
+
+// Share of a liquidated position's collateral paid to the liquidator (3%)
+#[constant]
+pub const LIQUIDATION_REWARD_BPS: u64 = 300;
+
+// Upper bound on a market's max_leverage
+#[constant]
+pub const MAX_LEVERAGE_BOUND: u16 = 100;
+
+// Denominator for Market.liquidation_threshold, which is a percentage of collateral
+#[constant]
+pub const LIQUIDATION_THRESHOLD_SCALE: i64 = 100;
+
+const _: () = assert!(CLOSE_FEE_BPS < BPS_SCALE);
+const _: () = assert!(LIQUIDATION_REWARD_BPS < BPS_SCALE);
+
+// Default minimum time between funding cranks (1 hour)
+const DEFAULT_FUNDING_INTERVAL: i64 = 3600;
+
+// Number of records kept in a trader's history ring buffer
+const TRADER_HISTORY_CAPACITY: usize = 32;
+
+// Number of AMM programs that can be whitelisted for settlement swaps
+const MAX_SWAP_PROGRAMS: usize = 4;
+
+// Number of integrator programs whose CPIs are exempt from the per-slot open limit
+const MAX_COMPOSER_PROGRAMS: usize = 4;
+
+// Number of position-size brackets a market can raise its liquidation threshold by
+#[constant]
+pub const MAX_THRESHOLD_BRACKETS: usize = 4;
+
+// Number of parameter sets kept by each market's ParamHistory ring buffer
+#[constant]
+pub const PARAM_HISTORY_CAPACITY: usize = 16;
+
+// Capability bits reported by get_version and stored in ProgramConfig.enabled_features
+pub const FEATURE_TRIGGER_ORDERS: u64 = 1 << 0;
+pub const FEATURE_CROSS_MARGIN: u64 = 1 << 1;
+pub const FEATURE_TOKEN_2022: u64 = 1 << 2;
+pub const FEATURE_COMPACT_EVENTS: u64 = 1 << 3;
+
+// Capabilities this build implements
+const SUPPORTED_FEATURES: u64 = FEATURE_COMPACT_EVENTS;
+
+// Layout version of the AsterEvent payloads emitted when FEATURE_COMPACT_EVENTS is enabled
+#[constant]
+pub const COMPACT_EVENT_VERSION: u8 = 1;
+
+// Upper bound on a compact event payload, in bytes
+#[constant]
+pub const MAX_COMPACT_PAYLOAD: usize = 200;
+
+// Layout version written into every MarketStats account
+const MARKET_STATS_VERSION: u8 = 4;
+
+// Version of the price fields in events and MarketStats: the raw oracle mantissa with its exponent, plus the
+// same price normalized to NORMALIZED_PRICE_EXPONENT
+#[constant]
+pub const PRICE_FORMAT_VERSION: u8 = 1;
+
+// Exponent of the normalized prices published next to raw oracle prices (1e6 fixed point)
+#[constant]
+pub const NORMALIZED_PRICE_EXPONENT: i32 = -6;
+
+// Length of the rolling window used for market volume (24 hours)
+const VOLUME_WINDOW: i64 = 86_400;
+
+// Number of recent client order ids remembered per trader to reject duplicate opens
+const CLIENT_ORDER_ID_WINDOW: usize = 16;
+
+// Number of per-market balances a trader can have waiting in claim_pending
+const MAX_PENDING_CLAIMS: usize = 4;
+
+// Number of keys that can be allowlisted to create markets
+const MAX_MARKET_CREATORS: usize = 8;
+
+// Layout version of the PositionSnapshot written by attest_position
+#[constant]
+pub const ATTESTATION_VERSION: u8 = 1;
+
+// Number of market keys held by each page of the market registry
+#[constant]
+pub const MARKET_PAGE_CAPACITY: usize = 32;
+
+// Seconds a newly registered recovery account must sit before emergency_sweep can send funds to it
+#[constant]
+pub const RECOVERY_ACCOUNT_TIMELOCK: i64 = 7 * 24 * 60 * 60;
+
+// Length of the window the per-market treasury withdrawal limits apply to
+#[constant]
+pub const WITHDRAWAL_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+// Fixed-point scale for the base quantities tracked in the market PnL aggregates
+const PNL_QUANTITY_PRECISION: u128 = 1_000_000_000;
+
+// Maximum age of the oracle price accepted when validating a feed at market initialization (seconds)
+const MAX_ORACLE_INIT_AGE: u64 = 60;
+
+// Largest vault balance discrepancy, in collateral base units, tolerated by verify_vault_accounting
+const VAULT_RECONCILIATION_TOLERANCE: u64 = 0;
+
+// Shortest claim window fund_rebate_epoch accepts, measured from funding (1 day)
+#[constant]
+pub const MIN_REBATE_CLAIM_WINDOW_SECS: i64 = 24 * 60 * 60;
+
 #[program]
 pub mod aster_dex {
     use super::*;
 
-    pub fn initialize_market(
-        ctx: Context<InitializeMarket>,
-        market_id: [u8; 32],
-        min_collateral: u64,
-        max_leverage: u16,
-        liquidation_threshold: u16,
-    ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
-        market.admin = ctx.accounts.admin.key();
-        market.oracle = ctx.accounts.price_feed.key();
-        market.market_id = market_id;
-        market.min_collateral = min_collateral;
-        market.max_leverage = max_leverage;
-        market.liquidation_threshold = liquidation_threshold;
-        market.is_active = true;
+    pub fn initialize_config(ctx: Context<InitializeConfig>, pyth_program_id: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.super_admin = ctx.accounts.super_admin.key();
+        config.pyth_program_id = pyth_program_id;
+        config.bump = ctx.bumps.config;
 
         Ok(())
     }
 
-    pub fn update_market(
-        ctx: Context<UpdateMarket>,
-        min_collateral: Option<u64>,
-        max_leverage: Option<u16>,
-        liquidation_threshold: Option<u16>,
-        is_active: Option<bool>,
+    pub fn update_config(
+        ctx: Context<UpdateConfig>,
+        pyth_program_id: Option<Pubkey>,
+        max_total_open_positions: Option<u32>,
+        guardian: Option<Pubkey>,
+        epoch_length: Option<i64>,
+        enabled_features: Option<u64>,
+        strict_admin_mode: Option<bool>,
+        max_opens_per_slot: Option<u16>,
+        min_position_size: Option<u64>,
+        distribution_destination: Option<Pubkey>,
+        distribution_bps: Option<u16>,
+        distribution_interval: Option<i64>,
+        convert_liquidation_rewards: Option<bool>,
+        liquidation_reward_discount_bps: Option<u16>,
+        min_volume_accrual_secs: Option<i64>,
     ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
+        let config = &mut ctx.accounts.config;
 
-        if let Some(min_col) = min_collateral {
-            market.min_collateral = min_col;
+        if let Some(pyth_program) = pyth_program_id {
+            config.pyth_program_id = pyth_program;
         }
 
-        if let Some(max_lev) = max_leverage {
-            require!(max_lev >= 1 && max_lev <= 100, AsterDexError::InvalidLeverage);
-            market.max_leverage = max_lev;
+        if let Some(position_cap) = max_total_open_positions {
+            config.max_total_open_positions = position_cap;
         }
 
-        if let Some(liq_threshold) = liquidation_threshold {
-            require!(liq_threshold > 0 && liq_threshold < 100, AsterDexError::InvalidLiquidationThreshold);
-            market.liquidation_threshold = liq_threshold;
+        if let Some(new_guardian) = guardian {
+            config.guardian = new_guardian;
         }
 
-        if let Some(active_state) = is_active {
-            market.is_active = active_state;
+        if let Some(length) = epoch_length {
+            require!(length >= 0, AsterDexError::InvalidEpochLength);
+            config.epoch_length = length;
+
+            // The first epoch starts when epochs are first enabled
+            if config.current_epoch_start == 0 {
+                config.current_epoch_start = Clock::get()?.unix_timestamp;
+            }
+        }
+
+        if let Some(features) = enabled_features {
+            require!(features & !SUPPORTED_FEATURES == 0, AsterDexError::UnsupportedFeature);
+            config.enabled_features = features;
+        }
+
+        if let Some(strict) = strict_admin_mode {
+            config.strict_admin_mode = strict;
+        }
+
+        if let Some(opens) = max_opens_per_slot {
+            config.max_opens_per_slot = opens;
+        }
+
+        if let Some(size) = min_position_size {
+            config.min_position_size = size;
+        }
+
+        if let Some(destination) = distribution_destination {
+            config.distribution_destination = destination;
+        }
+
+        if let Some(share) = distribution_bps {
+            require_lte("distribution_bps", share as u64, "max", BPS_SCALE, AsterDexError::InvalidDistributionShare)?;
+            config.distribution_bps = share;
+        }
+
+        if let Some(interval) = distribution_interval {
+            require!(interval >= 0, AsterDexError::InvalidFundingInterval);
+            config.distribution_interval = interval;
+        }
+
+        if let Some(convert) = convert_liquidation_rewards {
+            require!(!convert || config.reward_mint != Pubkey::default(), AsterDexError::RewardTreasuryNotInitialized);
+            config.convert_liquidation_rewards = convert;
+        }
+
+        if let Some(discount) = liquidation_reward_discount_bps {
+            require_lte("liquidation_reward_discount_bps", discount as u64, "max", BPS_SCALE, AsterDexError::InvalidRewardDiscount)?;
+            config.liquidation_reward_discount_bps = discount;
+        }
+
+        if let Some(secs) = min_volume_accrual_secs {
+            require!(secs >= 0, AsterDexError::InvalidVolumeAccrualWindow);
+            config.min_volume_accrual_secs = secs;
         }
 
         Ok(())
     }
 
-    pub fn open_position(
-        ctx: Context<OpenPosition>,
-        market_id: [u8; 32],
-        is_long: bool,
-        collateral_amount: u64,
-        leverage: u16,
-        max_slippage_bps: u16,
-    ) -> Result<()> {
-        let market = &ctx.accounts.market;
-        require!(market.is_active, AsterDexError::MarketInactive);
-        require!(leverage >= 1 && leverage <= market.max_leverage, AsterDexError::InvalidLeverage);
-        require!(collateral_amount >= market.min_collateral, AsterDexError::InsufficientCollateral);
+    pub fn init_reward_treasury(ctx: Context<InitRewardTreasury>) -> Result<()> {
+        // The treasury pays converted liquidation rewards; it is funded by plain transfers into it
+        let config = &mut ctx.accounts.config;
+        config.reward_mint = ctx.accounts.reward_mint.key();
+        config.reward_treasury_bump = ctx.bumps.reward_treasury;
 
-        // Get price from Pyth oracle
-        let price_feed: PriceFeed = load_price_feed_from_account_info(&ctx.accounts.price_feed).unwrap();
-        let price: Price = price_feed.get_price_unchecked();
-        let current_price = price.price as u64;
+        Ok(())
+    }
 
-        // Transfer collateral from user to vault
-        let transfer_ctx = CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.user_token_account.to_account_info(),
-                to: ctx.accounts.vault.to_account_info(),
-                authority: ctx.accounts.user.to_account_info(),
-            },
-        );
-        token::transfer(transfer_ctx, collateral_amount)?;
+    pub fn get_version(ctx: Context<GetVersion>) -> Result<ProgramVersion> {
+        Ok(ProgramVersion {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("GIT_HASH").unwrap_or("unknown").to_string(),
+            supported_features: SUPPORTED_FEATURES,
+            enabled_features: ctx.accounts.config.enabled_features,
+        })
+    }
 
-        // Calculate position size
-        let position_size = collateral_amount as u128 * leverage as u128;
+    pub fn add_swap_program(ctx: Context<UpdateConfig>, swap_program: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(swap_program != Pubkey::default(), AsterDexError::SwapProgramNotWhitelisted);
 
-        // Create position account
-        let position = &mut ctx.accounts.position;
-        position.trader = ctx.accounts.user.key();
-        position.market_id = market_id;
-        position.collateral = collateral_amount;
-        position.size = position_size as u64;
-        position.is_long = is_long;
-        position.entry_price = current_price;
-        position.leverage = leverage;
-        position.open_time = Clock::get()?.unix_timestamp;
-        position.collateral_mint = ctx.accounts.collateral_mint.key();
-        position.last_funding_index = 0; // In a real implementation, get the current funding index
+        if config.swap_programs.contains(&swap_program) {
+            return Ok(());
+        }
 
-        emit!(PositionOpened {
-            position: ctx.accounts.position.key(),
-            trader: ctx.accounts.user.key(),
-            market_id,
-            is_long,
-            collateral_amount,
-            position_size: position_size as u64,
-            entry_price: current_price,
-            leverage,
+        let slot = config
+            .swap_programs
+            .iter_mut()
+            .find(|program| **program == Pubkey::default())
+            .ok_or(AsterDexError::SwapProgramListFull)?;
+        *slot = swap_program;
+
+        Ok(())
+    }
+
+    pub fn remove_swap_program(ctx: Context<UpdateConfig>, swap_program: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+
+        for program in config.swap_programs.iter_mut() {
+            if *program == swap_program {
+                *program = Pubkey::default();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_composer_program(ctx: Context<UpdateConfig>, composer_program: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(composer_program != Pubkey::default(), AsterDexError::ComposerProgramNotAllowed);
+
+        if config.composer_programs.contains(&composer_program) {
+            return Ok(());
+        }
+
+        let slot = config
+            .composer_programs
+            .iter_mut()
+            .find(|program| **program == Pubkey::default())
+            .ok_or(AsterDexError::ComposerProgramListFull)?;
+        *slot = composer_program;
+
+        emit!(ComposerProgramChanged {
+            composer_program,
+            allowed: true,
         });
 
         Ok(())
     }
 
-    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
-        let position = &ctx.accounts.position;
-        require!(position.size > 0, AsterDexError::InvalidPosition);
+    pub fn remove_composer_program(ctx: Context<UpdateConfig>, composer_program: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
 
-        // Get price from Pyth oracle
-        let price_feed: PriceFeed = load_price_feed_from_account_info(&ctx.accounts.price_feed).unwrap();
-        let price: Price = price_feed.get_price_unchecked();
-        let current_price = price.price as u64;
+        for program in config.composer_programs.iter_mut() {
+            if *program == composer_program {
+                *program = Pubkey::default();
+            }
+        }
 
-        // Calculate PnL
-        let (pnl, fee) = calculate_pnl(position, current_price);
+        emit!(ComposerProgramChanged {
+            composer_program,
+            allowed: false,
+        });
 
-        // Calculate return amount
-        let return_amount: u64;
-        if pnl >= 0 {
-            return_amount = position.collateral + pnl as u64 - fee;
-        } else {
-            let remaining = position.collateral as i64 + pnl - fee as i64;
-            return_amount = if remaining > 0 { remaining as u64 } else { 0 };
+        Ok(())
+    }
+
+    pub fn add_market_creator(ctx: Context<UpdateConfig>, creator: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(creator != Pubkey::default(), AsterDexError::MarketCreatorNotAllowed);
+
+        if config.market_creators.contains(&creator) {
+            return Ok(());
         }
 
-        // Transfer funds back to user if any
-        if return_amount > 0 {
-            let seeds = &[
-                b"vault".as_ref(),
-                ctx.accounts.market.to_account_info().key.as_ref(),
-                &[ctx.accounts.market.bump],
-            ];
-            let signer = &[&seeds[..]];
-            
-            let transfer_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault.to_account_info(),
-                    to: ctx.accounts.user_token_account.to_account_info(),
-                    authority: ctx.accounts.vault.to_account_info(),
-                },
-                signer,
-            );
-            token::transfer(transfer_ctx, return_amount)?;
+        let slot = config
+            .market_creators
+            .iter_mut()
+            .find(|allowed| **allowed == Pubkey::default())
+            .ok_or(AsterDexError::MarketCreatorListFull)?;
+        *slot = creator;
+
+        emit!(MarketCreatorAdded {
+            creator,
+            authority: ctx.accounts.super_admin.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_market_creator(ctx: Context<UpdateConfig>, creator: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+
+        // Removal only stops new listings; markets the creator already administers are unaffected
+        for allowed in config.market_creators.iter_mut() {
+            if *allowed == creator {
+                *allowed = Pubkey::default();
+            }
         }
 
-        emit!(PositionClosed {
-            position: ctx.accounts.position.key(),
-            trader: position.trader,
-            close_price: current_price,
-            pnl,
-            fee,
+        emit!(MarketCreatorRemoved {
+            creator,
+            authority: ctx.accounts.super_admin.key(),
         });
 
-        // Close the position account
-        let position_account_info = ctx.accounts.position.to_account_info();
-        let destination = ctx.accounts.user.to_account_info();
-        
-        let dest_starting_lamports = destination.lamports();
-        **destination.lamports.borrow_mut() = dest_starting_lamports.checked_add(position_account_info.lamports()).unwrap();
-        **position_account_info.lamports.borrow_mut() = 0;
-        
         Ok(())
     }
 
-    pub fn liquidate_position(ctx: Context<LiquidatePosition>) -> Result<()> {
-        let position = &ctx.accounts.position;
-        require!(position.size > 0, AsterDexError::InvalidPosition);
+    pub fn set_global_pause(ctx: Context<SetGlobalPause>, paused: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
 
-        // Get price from Pyth oracle
-        let price_feed: PriceFeed = load_price_feed_from_account_info(&ctx.accounts.price_feed).unwrap();
-        let price: Price = price_feed.get_price_unchecked();
-        let current_price = price.price as u64;
+        // The guardian can only pause; lifting the pause is reserved for the super admin
+        require!(
+            paused || ctx.accounts.authority.key() == config.super_admin,
+            AsterDexError::Unauthorized
+        );
 
-        // Calculate PnL
-        let (pnl, _) = calculate_pnl(position, current_price);
+        // Remember when the pause flipped so liquidations can be held back right after an unpause
+        if paused != config.paused {
+            let now = Clock::get()?.unix_timestamp;
+            if paused {
+                config.last_pause_time = now;
+            } else {
+                config.cumulative_paused_secs = total_paused_secs(config, now);
+                config.last_unpause_time = now;
+            }
+        }
+        config.paused = paused;
 
-        // Check if position is liquidatable
-        let equity_percentage = ((position.collateral as i64 + pnl) * 100) / position.collateral as i64;
-        let market = &ctx.accounts.market;
-        
+        emit!(GlobalPauseSet {
+            authority: ctx.accounts.authority.key(),
+            paused,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_market_frozen(ctx: Context<SetMarketFrozen>, frozen: bool) -> Result<()> {
+        // The guardian can only freeze; unfreezing is reserved for the super admin
         require!(
-            equity_percentage <= market.liquidation_threshold as i64,
-            AsterDexError::CannotLiquidateYet
+            frozen || ctx.accounts.authority.key() == ctx.accounts.config.super_admin,
+            AsterDexError::Unauthorized
         );
+        ctx.accounts.market.opens_frozen = frozen;
 
-        // Calculate liquidator reward (e.g., 3% of remaining collateral)
-        let liquidation_fee = position.collateral * 3 / 100;
+        emit!(MarketFrozenSet {
+            market: ctx.accounts.market.key(),
+            authority: ctx.accounts.authority.key(),
+            frozen,
+            liquidations_frozen: ctx.accounts.market.liquidations_frozen,
+        });
 
-        // Transfer reward to liquidator
-        if liquidation_fee > 0 {
+        Ok(())
+    }
+
+    pub fn pause_all_markets<'info>(
+        ctx: Context<'_, '_, 'info, 'info, PauseAllMarkets<'info>>,
+        pause_liquidations: bool,
+    ) -> Result<()> {
+        // Markets come in through remaining_accounts, one registry page per call
+        let (changed, skipped) = set_markets_paused(
+            ctx.remaining_accounts,
+            &ctx.accounts.market_page,
+            ctx.accounts.authority.key(),
+            true,
+            pause_liquidations,
+            ctx.program_id,
+        )?;
+
+        emit!(MarketsPauseSet {
+            page_index: ctx.accounts.market_page.page_index,
+            authority: ctx.accounts.authority.key(),
+            paused: true,
+            pause_liquidations,
+            changed,
+            skipped,
+        });
+
+        Ok(())
+    }
+
+    pub fn resume_all_markets<'info>(ctx: Context<'_, '_, 'info, 'info, ResumeAllMarkets<'info>>) -> Result<()> {
+        let (changed, skipped) = set_markets_paused(
+            ctx.remaining_accounts,
+            &ctx.accounts.market_page,
+            ctx.accounts.super_admin.key(),
+            false,
+            false,
+            ctx.program_id,
+        )?;
+
+        emit!(MarketsPauseSet {
+            page_index: ctx.accounts.market_page.page_index,
+            authority: ctx.accounts.super_admin.key(),
+            paused: false,
+            pause_liquidations: false,
+            changed,
+            skipped,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_recovery_account(ctx: Context<SetRecoveryAccount>, recovery_account: Pubkey) -> Result<()> {
+        // Every change restarts the timelock, so a compromised key cannot redirect a sweep on short notice
+        let market = &mut ctx.accounts.market;
+        require!(!market.emergency_swept, AsterDexError::MarketSwept);
+        market.recovery_account = recovery_account;
+        market.recovery_account_set_at = Clock::get()?.unix_timestamp;
+
+        emit!(RecoveryAccountSet {
+            market: market.key(),
+            recovery_account,
+            usable_at: market.recovery_account_set_at.saturating_add(RECOVERY_ACCOUNT_TIMELOCK),
+        });
+
+        Ok(())
+    }
+
+    pub fn emergency_sweep(ctx: Context<EmergencySweep>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now >= ctx.accounts.market.recovery_account_set_at.saturating_add(RECOVERY_ACCOUNT_TIMELOCK),
+            AsterDexError::RecoveryAccountTimelocked
+        );
+
+        let amount = ctx.accounts.vault.amount;
+        if amount > 0 {
             let seeds = &[
                 b"vault".as_ref(),
                 ctx.accounts.market.to_account_info().key.as_ref(),
-                &[ctx.accounts.market.bump],
+                &[ctx.accounts.market.vault_bump],
             ];
             let signer = &[&seeds[..]];
-            
+
             let transfer_ctx = CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.vault.to_account_info(),
-                    to: ctx.accounts.liquidator_token_account.to_account_info(),
+                    mint: ctx.accounts.collateral_mint.to_account_info(),
+                    to: ctx.accounts.recovery_account.to_account_info(),
                     authority: ctx.accounts.vault.to_account_info(),
                 },
                 signer,
             );
-            token::transfer(transfer_ctx, liquidation_fee)?;
+            token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
         }
 
-        emit!(PositionLiquidated {
-            position: ctx.accounts.position.key(),
-            trader: position.trader,
-            liquidator: ctx.accounts.liquidator.key(),
-            liquidation_price: current_price,
-            fee: liquidation_fee,
+        // From here on the market only settles positions into claims against the recovery account
+        let market = &mut ctx.accounts.market;
+        market.emergency_swept = true;
+        market.swept_at = now;
+        market.swept_amount = amount;
+        market.sweep_price = market.last_good_price;
+        market.opens_frozen = true;
+
+        emit!(EmergencySweepExecuted {
+            market: market.key(),
+            recovery_account: ctx.accounts.recovery_account.key(),
+            amount,
+            sweep_price: market.sweep_price,
+            super_admin: ctx.accounts.super_admin.key(),
+            guardian: ctx.accounts.guardian.key(),
+            timestamp: now,
         });
 
-        // Close the position account
-        let position_account_info = ctx.accounts.position.to_account_info();
-        let destination = ctx.accounts.liquidator.to_account_info();
-        
-        let dest_starting_lamports = destination.lamports();
-        **destination.lamports.borrow_mut() = dest_starting_lamports.checked_add(position_account_info.lamports()).unwrap();
-        **position_account_info.lamports.borrow_mut() = 0;
-        
         Ok(())
     }
 
-    pub fn update_funding(ctx: Context<UpdateFunding>, new_funding_index: u64) -> Result<()> {
+    pub fn settle_swept_position(ctx: Context<SettleSweptPosition>) -> Result<()> {
+        require!(ctx.accounts.market.emergency_swept, AsterDexError::MarketNotSwept);
+        require!(ctx.accounts.position.size > 0, AsterDexError::InvalidPosition);
+
+        // Price the position at the last good price before the sweep; the result is what the recovery account owes
+        settle_position_funding(&mut ctx.accounts.position, &mut ctx.accounts.market)?;
+        let position = &ctx.accounts.position;
+        let sweep_price = ctx.accounts.market.sweep_price;
+        let (pnl, fee) = calculate_pnl(position, &ctx.accounts.market, sweep_price)?;
+        let claim = calculate_return_amount(position, pnl, fee);
+
+        // Release the position from the market's books
+        update_open_interest(&mut ctx.accounts.market, position.is_long, position.size, false)?;
+        let entry_price = rescale_price(position.entry_price, position.price_exponent, ctx.accounts.market.oracle_exponent)?;
+        update_pnl_aggregates(&mut ctx.accounts.market, position.is_long, position.size, entry_price, false)?;
+        update_funding_weight(&mut ctx.accounts.market, position.is_long, position.size, position.last_funding_index, false)?;
+        update_position_count(&mut ctx.accounts.market, &mut ctx.accounts.config, false)?;
+
         let market = &mut ctx.accounts.market;
-        require!(market.admin == ctx.accounts.admin.key(), AsterDexError::Unauthorized);
-        
-        market.last_funding_index = new_funding_index;
-        market.last_funding_time = Clock::get()?.unix_timestamp;
-        
+        market.total_reserved_collateral = market.total_reserved_collateral.saturating_sub(position.collateral);
+        market.total_recovery_claims = market.total_recovery_claims.checked_add(claim).ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(RecoveryClaimRecorded {
+            market: market.key(),
+            trader: position.trader,
+            position: position.key(),
+            amount: claim,
+            sweep_price,
+        });
+
         Ok(())
     }
-}
 
-// Helper function to calculate PnL
-fn calculate_pnl(position: &Position, current_price: u64) -> (i64, u64) {
-    let price_delta = if position.is_long {
-        current_price as i64 - position.entry_price as i64
-    } else {
-        position.entry_price as i64 - current_price as i64
-    };
-    
-    let pnl_percentage = (price_delta * 10000) / position.entry_price as i64;
-    let raw_pnl = (pnl_percentage * position.size as i64) / 10000;
-    
-    // Calculate trading fee (0.1% of position size)
-    let fee = (position.size * 10) / 10000;
-    
-    (raw_pnl, fee)
-}
+    pub fn initialize_market(
+        ctx: Context<InitializeMarket>,
+        market_id: [u8; 32],
+        min_collateral: u64,
+        max_leverage: u16,
+        liquidation_threshold: u16,
+        contract_type: ContractType,
+    ) -> Result<()> {
+        // Make sure the feed actually parses and quotes a live price before the market depends on it
+        let (oracle_feed_id, oracle_price, oracle_exponent) = validate_oracle_feed(&ctx.accounts.price_feed)?;
 
-#[derive(Accounts)]
-#[instruction(market_id: [u8; 32])]
-pub struct InitializeMarket<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + size_of::<Market>(),
-        seeds = [b"market", &market_id],
-        bump
-    )]
-    pub market: Account<'info, Market>,
-    
-    /// CHECK: This is the Pyth price feed account
-    pub price_feed: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
+        let market = &mut ctx.accounts.market;
+        market.admin = ctx.accounts.admin.key();
+        market.oracle = ctx.accounts.price_feed.key();
+        market.oracle_feed_id = oracle_feed_id;
+        market.oracle_exponent = oracle_exponent;
+        market.market_id = market_id;
+        market.min_collateral = min_collateral;
+        market.max_leverage = max_leverage;
+        market.liquidation_threshold = liquidation_threshold;
+        market.status = MarketStatus::Active;
+        market.funding_interval = DEFAULT_FUNDING_INTERVAL;
+        market.contract_type = contract_type;
+        market.risk_weight_bps = BPS_SCALE as u16;
+        market.liquidator_share_bps = BPS_SCALE as u16;
+        market.funding_paused_secs_checkpoint = total_paused_secs(&ctx.accounts.config, Clock::get()?.unix_timestamp);
+        market.bump = ctx.bumps.market;
+        market.vault_bump = ctx.bumps.vault;
+
+        // List the market in the registry's current page; the config counter decides which page that is
+        let market_page = &mut ctx.accounts.market_page;
+        market_page.markets[market_page.count as usize] = ctx.accounts.market.key();
+        market_page.count += 1;
+        let config = &mut ctx.accounts.config;
+        config.listed_markets = config.listed_markets.checked_add(1).ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(MarketInitialized {
+            market: ctx.accounts.market.key(),
+            market_id,
+            creator: ctx.accounts.admin.key(),
+            oracle: ctx.accounts.price_feed.key(),
+            oracle_feed_id,
+            oracle_price,
+            contract_type,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_market(ctx: Context<UpdateMarket>, params: UpdateMarketParams) -> Result<()> {
+        let UpdateMarketParams {
+            min_collateral,
+            max_leverage,
+            liquidation_threshold,
+            is_active,
+            funding_rate_bps,
+            funding_interval,
+            crank_reward,
+            max_open_positions,
+            max_funding_rate_bps_per_interval,
+            post_unpause_grace_secs,
+            risk_weight_bps,
+            min_price,
+            liquidation_conf_multiplier,
+            mark_ema_half_life_secs,
+            max_mark_divergence_bps,
+            utilization_pause_threshold_bps,
+            min_publishers,
+            require_trading_status,
+            liquidation_penalty_split,
+            min_collateral_usd,
+            min_collateral_mode,
+            skew_premium_bps,
+            supports_transfer_fee,
+            liquidation_hysteresis_bps,
+            keeper_bond_amount,
+            unregistered_keeper_discount_bps,
+            max_position_liquidity_multiple_bps,
+            liquidity_updater,
+            max_liquidity_reference_change_bps,
+            open_skew_premium_bps,
+            open_skew_rebate_bps,
+            zero_collateral_liquidation_reward,
+            daily_withdrawal_limit,
+            daily_withdrawal_count_limit,
+            threshold_brackets,
+            liquidation_delay_protection,
+            max_liquidation_delay_secs,
+        } = params;
+
+        require_sole_program_instruction(&ctx.accounts.config, &ctx.accounts.instructions)?;
+        let market = &mut ctx.accounts.market;
+
+        if let Some(min_col) = min_collateral {
+            market.min_collateral = min_col;
+        }
+
+        if let Some(max_lev) = max_leverage {
+            require_gte("max_leverage", max_lev as u64, "min", 1, AsterDexError::LeverageTooLow)?;
+            require_lte("max_leverage", max_lev as u64, "max", MAX_LEVERAGE_BOUND as u64, AsterDexError::LeverageTooHigh)?;
+            market.max_leverage = max_lev;
+        }
+
+        if let Some(liq_threshold) = liquidation_threshold {
+            require_gte("liquidation_threshold", liq_threshold as u64, "min", 1, AsterDexError::InvalidLiquidationThreshold)?;
+            require_lte(
+                "liquidation_threshold",
+                liq_threshold as u64,
+                "max",
+                LIQUIDATION_THRESHOLD_SCALE as u64 - 1,
+                AsterDexError::InvalidLiquidationThreshold,
+            )?;
+            market.liquidation_threshold = liq_threshold;
+        }
+
+        if let Some(active_state) = is_active {
+            // Archiving is one-way; an archived market cannot be reactivated from here
+            require!(market.status != MarketStatus::Archived, AsterDexError::MarketArchived);
+            market.status = if active_state { MarketStatus::Active } else { MarketStatus::Inactive };
+        }
+
+        if let Some(rate) = funding_rate_bps {
+            market.funding_rate_bps = rate;
+        }
+
+        if let Some(interval) = funding_interval {
+            require!(interval > 0, AsterDexError::InvalidFundingInterval);
+            market.funding_interval = interval;
+        }
+
+        if let Some(reward) = crank_reward {
+            market.crank_reward = reward;
+        }
+
+        if let Some(position_cap) = max_open_positions {
+            market.max_open_positions = position_cap;
+        }
+
+        if let Some(rate_cap) = max_funding_rate_bps_per_interval {
+            market.max_funding_rate_bps_per_interval = rate_cap;
+        }
+
+        if let Some(grace) = post_unpause_grace_secs {
+            require!(grace >= 0, AsterDexError::InvalidGracePeriod);
+            market.post_unpause_grace_secs = grace;
+        }
+
+        if let Some(weight) = risk_weight_bps {
+            require_lte("risk_weight_bps", weight as u64, "max", BPS_SCALE, AsterDexError::InvalidRiskWeight)?;
+            market.risk_weight_bps = weight;
+        }
+
+        if let Some(price_floor) = min_price {
+            market.min_price = price_floor;
+        }
+
+        if let Some(multiplier) = liquidation_conf_multiplier {
+            market.liquidation_conf_multiplier = multiplier;
+        }
+
+        if let Some(half_life) = mark_ema_half_life_secs {
+            require!(half_life >= 0, AsterDexError::InvalidHalfLife);
+            market.mark_ema_half_life_secs = half_life;
+        }
+
+        if let Some(divergence) = max_mark_divergence_bps {
+            market.max_mark_divergence_bps = divergence;
+        }
+
+        if let Some(threshold) = utilization_pause_threshold_bps {
+            market.utilization_pause_threshold_bps = threshold;
+        }
+
+        if let Some(publishers) = min_publishers {
+            market.min_publishers = publishers;
+        }
+
+        if let Some(trading_only) = require_trading_status {
+            market.require_trading_status = trading_only;
+        }
+
+        if let Some(split) = liquidation_penalty_split {
+            let total = split.liquidator_share_bps as u64 + split.insurance_share_bps as u64 + split.treasury_share_bps as u64;
+            require!(total == BPS_SCALE, AsterDexError::InvalidPenaltySplit);
+            market.liquidator_share_bps = split.liquidator_share_bps;
+            market.insurance_share_bps = split.insurance_share_bps;
+            market.treasury_share_bps = split.treasury_share_bps;
+        }
+
+        if let Some(min_usd) = min_collateral_usd {
+            market.min_collateral_usd = min_usd;
+        }
+
+        if let Some(mode) = min_collateral_mode {
+            market.min_collateral_mode = mode;
+        }
+
+        if let Some(premium) = skew_premium_bps {
+            require_lte("skew_premium_bps", premium as u64, "max", BPS_SCALE, AsterDexError::InvalidSkewPremium)?;
+            market.skew_premium_bps = premium;
+        }
+
+        if let Some(transfer_fee) = supports_transfer_fee {
+            market.supports_transfer_fee = transfer_fee;
+        }
+
+        if let Some(hysteresis) = liquidation_hysteresis_bps {
+            require_lte("liquidation_hysteresis_bps", hysteresis as u64, "max", BPS_SCALE, AsterDexError::InvalidHysteresis)?;
+            market.liquidation_hysteresis_bps = hysteresis;
+        }
+
+        if let Some(bond) = keeper_bond_amount {
+            market.keeper_bond_amount = bond;
+        }
+
+        if let Some(discount) = unregistered_keeper_discount_bps {
+            require_lte("unregistered_keeper_discount_bps", discount as u64, "max", BPS_SCALE, AsterDexError::InvalidKeeperDiscount)?;
+            market.unregistered_keeper_discount_bps = discount;
+        }
+
+        if let Some(multiple) = max_position_liquidity_multiple_bps {
+            market.max_position_liquidity_multiple_bps = multiple;
+        }
+
+        if let Some(updater) = liquidity_updater {
+            market.liquidity_updater = updater;
+        }
+
+        if let Some(max_change) = max_liquidity_reference_change_bps {
+            market.max_liquidity_reference_change_bps = max_change;
+        }
+
+        if let Some(premium) = open_skew_premium_bps {
+            require_lte("open_skew_premium_bps", premium as u64, "max", BPS_SCALE, AsterDexError::InvalidSkewPremium)?;
+            market.open_skew_premium_bps = premium;
+        }
+
+        if let Some(rebate) = open_skew_rebate_bps {
+            require_lte("open_skew_rebate_bps", rebate as u64, "max", BPS_SCALE, AsterDexError::InvalidSkewPremium)?;
+            market.open_skew_rebate_bps = rebate;
+        }
+
+        if let Some(reward) = zero_collateral_liquidation_reward {
+            market.zero_collateral_liquidation_reward = reward;
+        }
+
+        if let Some(limit) = daily_withdrawal_limit {
+            market.daily_withdrawal_limit = limit;
+        }
+
+        if let Some(limit) = daily_withdrawal_count_limit {
+            market.daily_withdrawal_count_limit = limit;
+        }
+
+        if let Some(brackets) = threshold_brackets {
+            market.threshold_brackets = brackets;
+        }
+
+        if let Some(protection) = liquidation_delay_protection {
+            market.liquidation_delay_protection = protection;
+        }
+
+        if let Some(delay) = max_liquidation_delay_secs {
+            require!(delay >= 0, AsterDexError::InvalidLiquidationDelay);
+            market.max_liquidation_delay_secs = delay;
+        }
+
+        // A new base threshold must still sit at or below the first bracket
+        validate_threshold_brackets(market)?;
+
+        // Once a market keeps a parameter history, every update has to be written to it
+        if market.param_history_enabled {
+            let param_history = ctx.accounts.param_history.as_mut().ok_or(AsterDexError::ParamHistoryRequired)?;
+            record_param_change(param_history, market, ctx.accounts.admin.key(), Clock::get()?.slot)?;
+        }
+
+        // Market updates have no legacy event; compact mode reports the resulting risk settings
+        if compact_events_enabled(&ctx.accounts.config) {
+            emit_compact(
+                CompactEventKind::MarketUpdate,
+                &CompactMarketUpdate {
+                    market: market.key(),
+                    status: market.status,
+                    max_leverage: market.max_leverage,
+                    liquidation_threshold: market.liquidation_threshold,
+                    funding_rate_bps: market.funding_rate_bps,
+                    min_price: market.min_price,
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finalize_market_params(ctx: Context<UpdateMarket>) -> Result<()> {
+        require_sole_program_instruction(&ctx.accounts.config, &ctx.accounts.instructions)?;
+
+        // One-way: the UpdateMarket constraint rejects every later call, including this one
+        let market = &mut ctx.accounts.market;
+        market.params_immutable = true;
+
+        emit!(MarketParamsFinalized {
+            market: market.key(),
+            admin: ctx.accounts.admin.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn acknowledge_exponent_change(ctx: Context<AcknowledgeExponentChange>) -> Result<()> {
+        let price_feed: PriceFeed = load_price_feed_from_account_info(&ctx.accounts.price_feed)
+            .map_err(|_| error!(AsterDexError::InvalidOracle))?;
+        let new_exponent = price_feed.get_price_unchecked().expo;
+
+        let market = &mut ctx.accounts.market;
+        let previous_exponent = market.oracle_exponent;
+        require!(new_exponent != previous_exponent, AsterDexError::InvalidOracle);
+
+        // Market-level prices follow the feed; open positions keep their own price_exponent
+        market.min_price = rescale_price(market.min_price, previous_exponent, new_exponent)?;
+        market.mark_price_ema = rescale_price(market.mark_price_ema, previous_exponent, new_exponent)?;
+        market.last_good_price = rescale_price(market.last_good_price, previous_exponent, new_exponent)?;
+        match market.contract_type {
+            // Linear quantities are size / price, so they move opposite to the price
+            ContractType::Linear => {
+                market.aggregate_size_long = rescale_amount(market.aggregate_size_long, new_exponent, previous_exponent)?;
+                market.aggregate_size_short = rescale_amount(market.aggregate_size_short, new_exponent, previous_exponent)?;
+            }
+            ContractType::Inverse => {
+                market.aggregate_size_long = rescale_amount(market.aggregate_size_long, previous_exponent, new_exponent)?;
+                market.aggregate_size_short = rescale_amount(market.aggregate_size_short, previous_exponent, new_exponent)?;
+            }
+        }
+        market.oracle_exponent = new_exponent;
+
+        emit!(OracleExponentAcknowledged {
+            market: market.key(),
+            previous_exponent,
+            new_exponent,
+        });
+
+        Ok(())
+    }
+
+    pub fn archive_market(ctx: Context<ArchiveMarket>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        market.status = MarketStatus::Archived;
+
+        emit!(MarketArchived {
+            market: market.key(),
+            open_position_count: market.open_position_count,
+            funding_index: market.last_funding_index,
+        });
+
+        Ok(())
+    }
+
+    pub fn init_market_page(ctx: Context<InitMarketPage>, page_index: u32) -> Result<()> {
+        // Pages are allocated in order, and only once the previous one is full
+        let config = &ctx.accounts.config;
+        require!(
+            page_index as u64 == config.listed_markets / MARKET_PAGE_CAPACITY as u64,
+            AsterDexError::InvalidMarketPage
+        );
+
+        let market_page = &mut ctx.accounts.market_page;
+        market_page.page_index = page_index;
+        market_page.bump = ctx.bumps.market_page;
+
+        Ok(())
+    }
+
+    pub fn sweep_archived_market(ctx: Context<SweepArchivedMarket>) -> Result<()> {
+        // Once the last position is gone, what the protocol still holds in the vault (fees, the skew premium and
+        // funding pools, the insurance fund and rounding dust) goes to the fee treasury. Pending trader claims
+        // stay behind until they are paid out.
+        let market = &ctx.accounts.market;
+        require!(market.status == MarketStatus::Archived, AsterDexError::MarketNotArchived);
+        require!(market.open_position_count == 0, AsterDexError::MarketHasOpenPositions);
+        let sweepable = ctx.accounts.vault.amount.saturating_sub(market.pending_trader_claims);
+        if sweepable == 0 {
+            return Ok(());
+        }
+
+        // Without the guardian's co-signature a sweep only moves what is left of the daily allowance
+        let guardian_cosigned = ctx.accounts.guardian.is_some();
+        let now = Clock::get()?.unix_timestamp;
+        let (amount, remaining_allowance) =
+            consume_withdrawal_allowance(&mut ctx.accounts.market, sweepable, now, guardian_cosigned)?;
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.fee_treasury.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+
+        let market = &mut ctx.accounts.market;
+        drain_protocol_balances(market, amount);
+
+        emit!(ArchivedMarketSwept {
+            market: market.key(),
+            fee_treasury: ctx.accounts.fee_treasury.key(),
+            amount,
+            retained: sweepable - amount,
+            guardian_cosigned,
+            remaining_allowance,
+            remaining_withdrawals: remaining_withdrawal_count(market),
+        });
+
+        Ok(())
+    }
+
+    pub fn archive_market_finalize(ctx: Context<ArchiveMarketFinalize>) -> Result<()> {
+        // Positions derive their market from seeds, so a market can only go away once nothing references it
+        let market = &ctx.accounts.market;
+        require!(market.status == MarketStatus::Archived, AsterDexError::MarketNotArchived);
+        require!(market.open_position_count == 0, AsterDexError::MarketHasOpenPositions);
+
+        // sweep_archived_market must have moved the protocol's balances out and traders claimed theirs, so nothing
+        // is left behind in the vault
+        require!(ctx.accounts.vault.amount == 0, AsterDexError::VaultNotEmpty);
+
+        // Delist from the registry. The slot is cleared rather than compacted so other entries keep their page;
+        // markets created before the registry have no page to pass.
+        if let Some(market_page) = ctx.accounts.market_page.as_mut() {
+            let market_key = ctx.accounts.market.key();
+            let slot = market_page
+                .markets
+                .iter_mut()
+                .find(|listed| **listed == market_key)
+                .ok_or(AsterDexError::MarketNotListed)?;
+            *slot = Pubkey::default();
+        }
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.vault.to_account_info(),
+                destination: ctx.accounts.admin.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::close_account(close_ctx)?;
+
+        Ok(())
+    }
+
+    pub fn recover_orphaned_position(ctx: Context<RecoverOrphanedPosition>) -> Result<()> {
+        let market_info = &ctx.accounts.market;
+        let orphaned = market_info.data_is_empty()
+            || market_info.owner != &crate::ID
+            || Market::try_deserialize(&mut &market_info.data.borrow()[..]).is_err();
+        require!(orphaned, AsterDexError::MarketNotOrphaned);
+
+        let position = &ctx.accounts.position;
+        let collateral = position.collateral;
+
+        // The market's counters are gone with it, but the global position count still includes this position
+        let config = &mut ctx.accounts.config;
+        config.total_open_positions = config.total_open_positions.saturating_sub(1);
+
+        // Return the collateral only; without a market there is no trusted price to settle PnL against
+        if collateral > 0 {
+            let vault_bump = ctx.bumps.vault;
+            let seeds = &[
+                b"vault".as_ref(),
+                market_info.key.as_ref(),
+                &[vault_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.vault.to_account_info(),
+                    mint: ctx.accounts.collateral_mint.to_account_info(),
+                    to: ctx.accounts.trader_token_account.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, collateral, ctx.accounts.collateral_mint.decimals)?;
+        }
+
+        emit!(PositionRescued {
+            position: ctx.accounts.position.key(),
+            trader: ctx.accounts.trader.key(),
+            market: market_info.key(),
+            collateral,
+            authority: ctx.accounts.super_admin.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn set_liquidity_reference(ctx: Context<SetLiquidityReference>, liquidity_reference: u64) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let previous_reference = market.liquidity_reference;
+
+        // Once a reference is in place, each update can only move it by max_liquidity_reference_change_bps
+        if previous_reference > 0 && market.max_liquidity_reference_change_bps > 0 {
+            let max_change = mul_div_floor(previous_reference, market.max_liquidity_reference_change_bps as u64, BPS_SCALE)?;
+            require_lte(
+                "liquidity_reference_change",
+                liquidity_reference.abs_diff(previous_reference),
+                "max",
+                max_change,
+                AsterDexError::LiquidityReferenceChangeTooLarge,
+            )?;
+        }
+        market.liquidity_reference = liquidity_reference;
+        market.liquidity_reference_updated_at = Clock::get()?.unix_timestamp;
+
+        emit!(LiquidityReferenceUpdated {
+            market: market.key(),
+            authority: ctx.accounts.authority.key(),
+            previous_reference,
+            liquidity_reference,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_fee_treasury(ctx: Context<SetFeeTreasury>, allow_user_owned: bool) -> Result<()> {
+        require_sole_program_instruction(&ctx.accounts.config, &ctx.accounts.instructions)?;
+        let fee_treasury = &ctx.accounts.fee_treasury;
+
+        // A wallet-owned account is usually a mistake for a treasury, so it needs to be asked for explicitly
+        require!(
+            allow_user_owned || !is_wallet_address(&fee_treasury.owner),
+            AsterDexError::InvalidFeeTreasury
+        );
+
+        let market = &mut ctx.accounts.market;
+        let previous_treasury = market.fee_treasury;
+        market.fee_treasury = fee_treasury.key();
+
+        emit!(FeeTreasuryChanged {
+            market: market.key(),
+            previous_treasury,
+            new_treasury: market.fee_treasury,
+            authority: ctx.accounts.admin.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn sweep_fees(ctx: Context<SweepFees>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        let accrued = ctx.accounts.market.accumulated_fees;
+        if accrued == 0 {
+            return Ok(());
+        }
+
+        // Without the guardian's co-signature a sweep only moves what is left of the daily allowance
+        let guardian_cosigned = ctx.accounts.guardian.is_some();
+        let now = Clock::get()?.unix_timestamp;
+        let (amount, remaining_allowance) =
+            consume_withdrawal_allowance(&mut ctx.accounts.market, accrued, now, guardian_cosigned)?;
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.fee_treasury.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+
+        // Swept fees leave the vault; the lifetime total keeps the history
+        let market = &mut ctx.accounts.market;
+        market.accumulated_fees = accrued - amount;
+        market.total_fees_swept = market.total_fees_swept.checked_add(amount).ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(FeesSwept {
+            market: market.key(),
+            fee_treasury: ctx.accounts.fee_treasury.key(),
+            amount,
+            retained: market.accumulated_fees,
+            guardian_cosigned,
+            remaining_allowance,
+            remaining_withdrawals: remaining_withdrawal_count(market),
+        });
+
+        Ok(())
+    }
+
+    pub fn distribute_fees(ctx: Context<DistributeFees>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        let config = &ctx.accounts.config;
+        require!(config.distribution_bps > 0, AsterDexError::FeeDistributionDisabled);
+        validate_distribution_destination(
+            config,
+            ctx.accounts.distribution_destination.key(),
+            ctx.accounts.distribution_destination.mint,
+            ctx.accounts.vault.mint,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let market = &ctx.accounts.market;
+        require!(
+            market.last_fee_distribution == 0 || now >= market.last_fee_distribution + config.distribution_interval,
+            AsterDexError::DistributionTooSoon
+        );
+
+        // The share is floored; whatever it leaves behind stays accrued for the treasury sweep
+        let accrued = market.accumulated_fees;
+        let (amount, retained) = split_fees(accrued, config.distribution_bps)?;
+
+        // Distributions draw on the same daily allowance as treasury sweeps; what the allowance trims stays accrued
+        let guardian_cosigned = ctx.accounts.guardian.is_some();
+        let (amount, retained, remaining_allowance) = if amount > 0 {
+            let (allowed, remaining) =
+                consume_withdrawal_allowance(&mut ctx.accounts.market, amount, now, guardian_cosigned)?;
+            (allowed, retained + (amount - allowed), remaining)
+        } else {
+            (0, retained, remaining_withdrawal_allowance(&ctx.accounts.market))
+        };
+
+        if amount > 0 {
+            let seeds = &[
+                b"vault".as_ref(),
+                ctx.accounts.market.to_account_info().key.as_ref(),
+                &[ctx.accounts.market.vault_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.vault.to_account_info(),
+                    mint: ctx.accounts.collateral_mint.to_account_info(),
+                    to: ctx.accounts.distribution_destination.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+        }
+
+        let market = &mut ctx.accounts.market;
+        market.accumulated_fees = retained;
+        market.last_fee_distribution = now;
+        market.total_fees_distributed = market
+            .total_fees_distributed
+            .checked_add(amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(FeesDistributed {
+            market: market.key(),
+            destination: ctx.accounts.distribution_destination.key(),
+            accrued,
+            amount,
+            retained,
+            total_fees_distributed: market.total_fees_distributed,
+            total_fees_swept: market.total_fees_swept,
+            timestamp: now,
+            guardian_cosigned,
+            remaining_allowance,
+            remaining_withdrawals: remaining_withdrawal_count(market),
+        });
+
+        Ok(())
+    }
+
+    pub fn init_trader_account(ctx: Context<InitTraderAccount>) -> Result<()> {
+        let trader_account = &mut ctx.accounts.trader_account;
+        trader_account.owner = ctx.accounts.user.key();
+        trader_account.position_nonce = 0;
+        trader_account.bump = ctx.bumps.trader_account;
+
+        Ok(())
+    }
+
+    pub fn claim_pending(ctx: Context<ClaimPending>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        let market_key = ctx.accounts.market.key();
+        let trader_account = &mut ctx.accounts.trader_account;
+        let claim = trader_account
+            .pending_claims
+            .iter_mut()
+            .find(|claim| claim.amount > 0 && claim.market == market_key)
+            .ok_or(AsterDexError::NoPendingClaim)?;
+        let amount = claim.amount;
+        *claim = PendingClaim::default();
+
+        let market = &mut ctx.accounts.market;
+        market.pending_trader_claims = market
+            .pending_trader_claims
+            .checked_sub(amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.trader_token_account.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+
+        emit!(PendingClaimPaid {
+            market: market_key,
+            trader: ctx.accounts.trader.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_withdrawal_delay(ctx: Context<SetWithdrawalDelay>, delay_secs: i64) -> Result<()> {
+        require!(delay_secs >= 0, AsterDexError::InvalidWithdrawalDelay);
+        let now = Clock::get()?.unix_timestamp;
+        let trader_account = &mut ctx.accounts.trader_account;
+        let current_delay = effective_withdrawal_delay(trader_account, now);
+
+        // Lengthening applies at once; shortening only once the current delay has run, so a stolen key
+        // cannot switch the delay off and withdraw straight away
+        if delay_secs >= current_delay {
+            trader_account.withdrawal_delay_secs = delay_secs;
+            trader_account.pending_withdrawal_delay_secs = 0;
+            trader_account.withdrawal_delay_effective_at = 0;
+        } else {
+            trader_account.withdrawal_delay_secs = current_delay;
+            trader_account.pending_withdrawal_delay_secs = delay_secs;
+            trader_account.withdrawal_delay_effective_at = now.saturating_add(current_delay);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_escrow_guardian(ctx: Context<SetEscrowGuardian>, guardian: Pubkey) -> Result<()> {
+        // Once set, only the guardian itself can step down or hand over
+        let trader_account = &mut ctx.accounts.trader_account;
+        if trader_account.escrow_guardian != Pubkey::default() {
+            let current = ctx.accounts.current_guardian.as_ref().ok_or(AsterDexError::Unauthorized)?;
+            require_keys_eq!(current.key(), trader_account.escrow_guardian, AsterDexError::Unauthorized);
+        }
+        trader_account.escrow_guardian = guardian;
+
+        Ok(())
+    }
+
+    pub fn init_withdrawal_escrow(ctx: Context<InitWithdrawalEscrow>) -> Result<()> {
+        let escrow = &mut ctx.accounts.withdrawal_escrow;
+        escrow.trader = ctx.accounts.trader.key();
+        escrow.market = ctx.accounts.market.key();
+        escrow.bump = ctx.bumps.withdrawal_escrow;
+
+        Ok(())
+    }
+
+    pub fn claim_escrow(ctx: Context<ClaimEscrow>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        let escrow = &mut ctx.accounts.withdrawal_escrow;
+        let amount = escrow.amount;
+        require!(amount > 0, AsterDexError::EscrowEmpty);
+        require!(Clock::get()?.unix_timestamp >= escrow.release_time, AsterDexError::EscrowLocked);
+        escrow.amount = 0;
+
+        let market = &mut ctx.accounts.market;
+        market.pending_trader_claims = market
+            .pending_trader_claims
+            .checked_sub(amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.trader_token_account.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+
+        emit!(EscrowClaimed {
+            trader: ctx.accounts.trader.key(),
+            market: ctx.accounts.market.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_escrow(ctx: Context<CancelEscrow>) -> Result<()> {
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        // The trader or their guardian can send escrowed funds back into an open position as collateral;
+        // nothing here can release them early
+        let escrow = &mut ctx.accounts.withdrawal_escrow;
+        let amount = escrow.amount;
+        require!(amount > 0, AsterDexError::EscrowEmpty);
+        escrow.amount = 0;
+
+        let position = &mut ctx.accounts.position;
+        position.collateral = position.collateral.checked_add(amount).ok_or(AsterDexError::MathOverflow)?;
+
+        let market = &mut ctx.accounts.market;
+        market.pending_trader_claims = market
+            .pending_trader_claims
+            .checked_sub(amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+        market.total_reserved_collateral = market
+            .total_reserved_collateral
+            .checked_add(amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(EscrowCancelled {
+            trader: escrow.trader,
+            market: market.key(),
+            position: position.key(),
+            amount,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn close_withdrawal_escrow(ctx: Context<CloseWithdrawalEscrow>) -> Result<()> {
+        require!(ctx.accounts.withdrawal_escrow.amount == 0, AsterDexError::EscrowNotEmpty);
+
+        Ok(())
+    }
+
+    pub fn register_keeper(ctx: Context<RegisterKeeper>) -> Result<()> {
+        let bond = ctx.accounts.market.keeper_bond_amount;
+        require!(bond > 0, AsterDexError::KeeperRegistryDisabled);
+
+        let keeper_account = &mut ctx.accounts.keeper_account;
+        keeper_account.keeper = ctx.accounts.keeper.key();
+        keeper_account.market = ctx.accounts.market.key();
+        keeper_account.bond = bond;
+        keeper_account.registered_at = Clock::get()?.unix_timestamp;
+        keeper_account.bump = ctx.bumps.keeper_account;
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.keeper_token_account.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.keeper_bond.to_account_info(),
+                authority: ctx.accounts.keeper.to_account_info(),
+            },
+        );
+        token::transfer_checked(transfer_ctx, bond, ctx.accounts.collateral_mint.decimals)?;
+
+        emit!(KeeperRegistered {
+            keeper: ctx.accounts.keeper.key(),
+            market: ctx.accounts.market.key(),
+            bond,
+        });
+
+        Ok(())
+    }
+
+    pub fn unregister_keeper(ctx: Context<UnregisterKeeper>) -> Result<()> {
+        // Hand the bond back, then close the bond account; the keeper account is closed by its constraint
+        let keeper_account = &ctx.accounts.keeper_account;
+        let market_key = keeper_account.market;
+        let keeper_key = keeper_account.keeper;
+        let seeds = &[
+            b"keeper".as_ref(),
+            market_key.as_ref(),
+            keeper_key.as_ref(),
+            &[keeper_account.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let bond = ctx.accounts.keeper_bond.amount;
+        if bond > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.keeper_bond.to_account_info(),
+                    mint: ctx.accounts.collateral_mint.to_account_info(),
+                    to: ctx.accounts.keeper_token_account.to_account_info(),
+                    authority: keeper_account.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, bond, ctx.accounts.collateral_mint.decimals)?;
+        }
+
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.keeper_bond.to_account_info(),
+                destination: ctx.accounts.keeper.to_account_info(),
+                authority: keeper_account.to_account_info(),
+            },
+            signer,
+        );
+        token::close_account(close_ctx)?;
+
+        emit!(KeeperUnregistered {
+            keeper: keeper_key,
+            market: market_key,
+            bond,
+            successful_executions: keeper_account.successful_executions,
+            failed_executions: keeper_account.failed_executions,
+        });
+
+        Ok(())
+    }
+
+    pub fn init_trader_history(ctx: Context<InitTraderHistory>) -> Result<()> {
+        let history = &mut ctx.accounts.trader_history;
+        history.owner = ctx.accounts.user.key();
+        history.total_records = 0;
+        history.bump = ctx.bumps.trader_history;
+
+        Ok(())
+    }
+
+    pub fn close_trader_history(_ctx: Context<CloseTraderHistory>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        market_id: [u8; 32],
+        is_long: bool,
+        collateral_amount: u64,
+        leverage: u16,
+        max_slippage_bps: u16,
+        client_order_id: Option<u64>,
+        accept_liquidation_risk: Option<bool>,
+        base_order: Option<BaseSizeOrder>,
+        dry_run: Option<bool>,
+        memo: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        execute_open(
+            OpenAccounts {
+                user: &accounts.user,
+                config: &mut accounts.config,
+                market: &mut accounts.market,
+                trader_account: &mut accounts.trader_account,
+                position: &mut accounts.position,
+                user_token_account: &accounts.user_token_account,
+                vault: &mut accounts.vault,
+                collateral_mint: &accounts.collateral_mint,
+                price_feed: &accounts.price_feed,
+                token_program: &accounts.token_program,
+                trader_history: accounts.trader_history.as_mut(),
+                collateral_delegate: accounts.collateral_delegate.as_ref(),
+                instructions: &accounts.instructions,
+                into_slot: false,
+            },
+            ctx.program_id,
+            OpenRequest {
+                market_id,
+                is_long,
+                collateral_amount,
+                leverage,
+                client_order_id,
+                accept_liquidation_risk,
+                base_order,
+                dry_run,
+                memo,
+            },
+        )
+    }
+
+    pub fn open_into_slot(
+        ctx: Context<OpenIntoSlot>,
+        market_id: [u8; 32],
+        is_long: bool,
+        collateral_amount: u64,
+        leverage: u16,
+        max_slippage_bps: u16,
+        client_order_id: Option<u64>,
+        accept_liquidation_risk: Option<bool>,
+        base_order: Option<BaseSizeOrder>,
+        dry_run: Option<bool>,
+        memo: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        execute_open(
+            OpenAccounts {
+                user: &accounts.user,
+                config: &mut accounts.config,
+                market: &mut accounts.market,
+                trader_account: &mut accounts.trader_account,
+                position: &mut accounts.position,
+                user_token_account: &accounts.user_token_account,
+                vault: &mut accounts.vault,
+                collateral_mint: &accounts.collateral_mint,
+                price_feed: &accounts.price_feed,
+                token_program: &accounts.token_program,
+                trader_history: accounts.trader_history.as_mut(),
+                collateral_delegate: accounts.collateral_delegate.as_ref(),
+                instructions: &accounts.instructions,
+                into_slot: true,
+            },
+            ctx.program_id,
+            OpenRequest {
+                market_id,
+                is_long,
+                collateral_amount,
+                leverage,
+                client_order_id,
+                accept_liquidation_risk,
+                base_order,
+                dry_run,
+                memo,
+            },
+        )
+    }
+
+    pub fn create_position_slot(ctx: Context<CreatePositionSlot>, market_id: [u8; 32]) -> Result<()> {
+        // An empty shell at the trader's next position address, rent paid now so a later open skips the init
+        let trader_account = &mut ctx.accounts.trader_account;
+        let nonce = trader_account.position_nonce;
+        trader_account.position_nonce = nonce.checked_add(1).ok_or(AsterDexError::MathOverflow)?;
+
+        let position = &mut ctx.accounts.position;
+        position.trader = ctx.accounts.user.key();
+        position.market_id = market_id;
+        position.nonce = nonce;
+
+        Ok(())
+    }
+
+    pub fn close_empty_slot(_ctx: Context<CloseEmptySlot>) -> Result<()> {
+        // The slot's rent goes back to the trader through the close constraint
+        Ok(())
+    }
+
+    pub fn close_position(ctx: Context<ClosePosition>, min_return_amount: u64, dry_run: Option<bool>) -> Result<()> {
+        let return_amount = settle_close_position(ctx.accounts, min_return_amount)?;
+
+        if dry_run.unwrap_or(false) {
+            return finish_dry_run(DryRunInstruction::Close, ctx.accounts.position.key(), &return_amount);
+        }
+
+        Ok(())
+    }
+
+    pub fn close_position_and_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClosePositionAndSwap<'info>>,
+        min_out: u64,
+    ) -> Result<()> {
+        let swap_program = ctx.accounts.swap_program.key();
+        require!(
+            swap_program != Pubkey::default() && ctx.accounts.close.config.swap_programs.contains(&swap_program),
+            AsterDexError::SwapProgramNotWhitelisted
+        );
+
+        // Escrowed proceeds never reach the user's account, so there would be nothing to swap
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            effective_withdrawal_delay(&ctx.accounts.close.trader_account, now) == 0,
+            AsterDexError::WithdrawalDelayActive
+        );
+
+        // Settle into the user's collateral account first, then swap the proceeds out of it
+        // Slippage is enforced on the swap output below rather than on the collateral returned
+        let return_amount = settle_close_position(&mut ctx.accounts.close, 0)?;
+        let balance_before = ctx.accounts.destination_token_account.amount;
+
+        if return_amount > 0 {
+            ConstantProductSwap.swap(
+                &ctx.accounts.swap_program,
+                &ctx.accounts.close.user.to_account_info(),
+                &ctx.accounts.close.user_token_account.to_account_info(),
+                &ctx.accounts.destination_token_account.to_account_info(),
+                ctx.remaining_accounts,
+                return_amount,
+                min_out,
+            )?;
+        }
+
+        // Enforce the user's bound ourselves rather than trusting the AMM to honour it
+        ctx.accounts.destination_token_account.reload()?;
+        let amount_out = ctx
+            .accounts
+            .destination_token_account
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(AsterDexError::MathOverflow)?;
+        require_gte("amount_out", amount_out, "min_out", min_out, AsterDexError::SlippageExceeded)?;
+
+        emit!(PositionProceedsSwapped {
+            position: ctx.accounts.close.position.key(),
+            trader: ctx.accounts.close.user.key(),
+            swap_program,
+            amount_in: return_amount,
+            amount_out,
+        });
+
+        Ok(())
+    }
+
+    pub fn liquidate_position(
+        ctx: Context<LiquidatePosition>,
+        expected_price: u64,
+        max_deviation_bps: u16,
+    ) -> Result<()> {
+        require_not_reentrant(&ctx.accounts.instructions)?;
+        require!(!ctx.accounts.config.paused, AsterDexError::ProgramPaused);
+        require!(!ctx.accounts.market.emergency_swept, AsterDexError::MarketSwept);
+        require!(!ctx.accounts.market.liquidations_frozen, AsterDexError::LiquidationsFrozen);
+        require!(ctx.accounts.position.size > 0, AsterDexError::InvalidPosition);
+
+        // Settle accrued funding so the health check sees the collateral the position actually has
+        settle_position_funding(&mut ctx.accounts.position, &mut ctx.accounts.market)?;
+        let position = &ctx.accounts.position;
+
+        // Get price from Pyth oracle, bailing out if it moved since the liquidator built the transaction
+        let (current_price, publish_time) = load_oracle_quote(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+        require_price_within(current_price, expected_price, max_deviation_bps)?;
+        require!(
+            oracle_quality_ok(&ctx.accounts.price_feed, &ctx.accounts.market)?,
+            AsterDexError::OracleQualityTooLow
+        );
+
+        // A print far from the mark only liquidates if an earlier publish, recorded by a flag or close, diverged too
+        if is_mark_divergent(&ctx.accounts.market, current_price) {
+            let divergent_since = ctx.accounts.market.divergent_publish_time;
+            require!(
+                divergent_since != 0 && divergent_since < publish_time,
+                AsterDexError::MarkPriceDivergence
+            );
+        }
+
+        // Calculate PnL
+        let (pnl, fee) = calculate_pnl(position, &ctx.accounts.market, current_price)?;
+
+        // Health is judged at the mark price; the payout below still settles at the index. A flagged position
+        // stays liquidatable until it recovers past the hysteresis band
+        let mark_price = calculate_mark_price(&ctx.accounts.market, current_price);
+        let liquidatable = if position.liquidatable_flagged_at != 0 {
+            !clears_liquidation_band(position, &ctx.accounts.market, mark_price)?
+        } else {
+            is_liquidatable(position, &ctx.accounts.market, mark_price)?
+        };
+        require!(liquidatable, AsterDexError::CannotLiquidateYet);
+
+        // The liquidator is paid from the collateral; equity left after the close fee and the reward goes
+        // back to the trader, never more than the collateral still covers
+        let (_, liquidation_penalty_bps, _) = position_terms(position, &ctx.accounts.market);
+        let liquidation_fee = mul_div_floor(position.collateral, liquidation_penalty_bps, BPS_SCALE)?;
+        let (full_liquidator_reward, insurance_share, treasury_share) =
+            split_liquidation_penalty(&ctx.accounts.market, liquidation_fee)?;
+
+        // Unregistered liquidators earn a reduced reward; what they forgo goes to the insurance fund
+        let (liquidator_reward, withheld_reward) = discount_unregistered_keeper(
+            &ctx.accounts.market,
+            full_liquidator_reward,
+            ctx.accounts.keeper_account.is_some(),
+        )?;
+        let insurance_share = insurance_share + withheld_reward;
+
+        // Funding can drain the collateral entirely, leaving no penalty to pay anyone. The liquidator then gets a
+        // flat reward out of the insurance fund instead, as far as the fund covers it
+        let zero_collateral = position.collateral == 0;
+        let insurance_reward = if zero_collateral {
+            let market = &ctx.accounts.market;
+            let reward = market.zero_collateral_liquidation_reward.min(market.insurance_fund_balance);
+            discount_unregistered_keeper(market, reward, ctx.accounts.keeper_account.is_some())?.0
+        } else {
+            0
+        };
+        let fee_collected = fee.min((position.collateral as i64 + pnl).max(0) as u64);
+        let remainder = (compute_equity(position, pnl, fee) - liquidation_fee as i64)
+            .max(0)
+            .min(position.collateral.saturating_sub(liquidation_fee + fee_collected) as i64) as u64;
+
+        // Give traders a window to top up after an unpause, unless the position was already flagged before the pause
+        let now = Clock::get()?.unix_timestamp;
+        let config = &ctx.accounts.config;
+        let in_grace_period = now < config.last_unpause_time.saturating_add(ctx.accounts.market.post_unpause_grace_secs);
+        let flagged_before_pause =
+            position.liquidatable_flagged_at != 0 && position.liquidatable_flagged_at < config.last_pause_time;
+        require!(!in_grace_period || flagged_before_pause, AsterDexError::UnpauseGracePeriod);
+
+        // A liquidation left too long after the flag owes the trader what they would have kept at the breach
+        let (liquidator_reward, delay_compensation, insurance_compensation) = late_liquidation_compensation(
+            position,
+            &ctx.accounts.market,
+            liquidator_reward,
+            remainder,
+            liquidation_fee,
+            ctx.accounts.market.insurance_fund_balance - insurance_reward,
+            now,
+        )?;
+        let remainder = remainder + delay_compensation;
+
+        // Release open interest
+        update_open_interest(&mut ctx.accounts.market, position.is_long, position.size, false)?;
+        let entry_price = rescale_price(position.entry_price, position.price_exponent, ctx.accounts.market.oracle_exponent)?;
+        update_pnl_aggregates(&mut ctx.accounts.market, position.is_long, position.size, entry_price, false)?;
+        update_funding_weight(&mut ctx.accounts.market, position.is_long, position.size, position.last_funding_index, false)?;
+        update_position_count(&mut ctx.accounts.market, &mut ctx.accounts.config, false)?;
+
+        // Record the liquidation in the trader's history when they keep one
+        if let Some(history) = ctx.accounts.trader_history.as_mut() {
+            append_history(history, HistoryAction::Liquidate, position.market_id, position.size, current_price, pnl, now);
+        }
+
+        // Everything but the remainder is lost to the trader on liquidation
+        let epoch = roll_epoch(&mut ctx.accounts.config, now)?;
+        record_epoch_activity(
+            &mut ctx.accounts.trader_account,
+            &mut ctx.accounts.config,
+            remainder as i64 - position.collateral as i64,
+            position.size,
+        )?;
+        record_market_volume(&mut ctx.accounts.market, position.size, now)?;
+        update_mark_price_ema(&mut ctx.accounts.market, current_price, now);
+        ctx.accounts.market.last_good_price = current_price;
+        refresh_mark_price(&mut ctx.accounts.market, current_price);
+
+        // The insurance share leaves the trader's side of the books into the fund and the treasury share is kept
+        // as fees for the next sweep; both stay in the vault, only the liquidator reward is transferred
+        record_settlement(
+            &mut ctx.accounts.market,
+            position.collateral,
+            liquidator_reward + insurance_share + remainder - insurance_compensation,
+            fee_collected + treasury_share,
+        )?;
+        ctx.accounts.market.insurance_fund_balance = ctx
+            .accounts
+            .market
+            .insurance_fund_balance
+            .checked_add(insurance_share)
+            .ok_or(AsterDexError::MathOverflow)?
+            - insurance_reward
+            - insurance_compensation;
+        let liquidator_reward = liquidator_reward + insurance_reward;
+
+        // When conversion is on and the liquidator passed the reward accounts, pay the reward in the reward mint
+        // from the treasury and keep the in-kind reward as insurance backing it. An underfunded treasury falls
+        // back to paying in kind
+        let converted_reward = match (
+            ctx.accounts.reward_treasury.as_ref(),
+            ctx.accounts.reward_mint.as_ref(),
+            ctx.accounts.liquidator_reward_account.as_ref(),
+        ) {
+            (Some(reward_treasury), Some(reward_mint), Some(_)) if ctx.accounts.config.convert_liquidation_rewards => {
+                let converted = convert_liquidation_reward(
+                    &ctx.accounts.config,
+                    &ctx.accounts.market,
+                    liquidator_reward,
+                    ctx.accounts.collateral_mint.decimals,
+                    current_price,
+                    reward_mint.decimals,
+                )?;
+                if converted <= reward_treasury.amount {
+                    converted
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+        if converted_reward > 0 {
+            let market = &mut ctx.accounts.market;
+            market.insurance_fund_balance = market
+                .insurance_fund_balance
+                .checked_add(liquidator_reward)
+                .ok_or(AsterDexError::MathOverflow)?;
+            market.total_converted_penalties = market
+                .total_converted_penalties
+                .checked_add(liquidator_reward)
+                .ok_or(AsterDexError::MathOverflow)?;
+            market.total_converted_rewards_paid = market
+                .total_converted_rewards_paid
+                .checked_add(converted_reward)
+                .ok_or(AsterDexError::MathOverflow)?;
+        }
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.market.to_account_info().key.as_ref(),
+            &[ctx.accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Transfer reward to liquidator
+        if converted_reward > 0 {
+            // Both accounts are present whenever a reward was converted
+            let reward_treasury = ctx.accounts.reward_treasury.as_ref().ok_or(AsterDexError::InvalidTokenAccount)?;
+            let reward_mint = ctx.accounts.reward_mint.as_ref().ok_or(AsterDexError::InvalidMint)?;
+            let liquidator_reward_account =
+                ctx.accounts.liquidator_reward_account.as_ref().ok_or(AsterDexError::InvalidTokenAccount)?;
+            let treasury_seeds = &[b"reward_treasury".as_ref(), &[ctx.accounts.config.reward_treasury_bump]];
+            let treasury_signer = &[&treasury_seeds[..]];
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: reward_treasury.to_account_info(),
+                    mint: reward_mint.to_account_info(),
+                    to: liquidator_reward_account.to_account_info(),
+                    authority: reward_treasury.to_account_info(),
+                },
+                treasury_signer,
+            );
+            token::transfer_checked(transfer_ctx, converted_reward, reward_mint.decimals)?;
+        } else if liquidator_reward > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.vault.to_account_info(),
+                    mint: ctx.accounts.collateral_mint.to_account_info(),
+                    to: ctx.accounts.liquidator_token_account.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, liquidator_reward, ctx.accounts.collateral_mint.decimals)?;
+        }
+
+        // Pay the remainder to the trader, or hold it as a claim if their token account is closed or wrong.
+        // A missing trader account must never block the liquidation.
+        if remainder > 0 {
+            let trader_token_account = ctx.accounts.trader_token_account.to_account_info();
+            if is_valid_token_account(&trader_token_account, &position.collateral_mint, &position.trader) {
+                let transfer_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.vault.to_account_info(),
+                        mint: ctx.accounts.collateral_mint.to_account_info(),
+                        to: trader_token_account,
+                        authority: ctx.accounts.vault.to_account_info(),
+                    },
+                    signer,
+                );
+                token::transfer_checked(transfer_ctx, remainder, ctx.accounts.collateral_mint.decimals)?;
+            } else {
+                let market_key = ctx.accounts.market.key();
+                let recorded =
+                    add_pending_claim(&mut ctx.accounts.trader_account, market_key, position.collateral_mint, remainder)?;
+                let market = &mut ctx.accounts.market;
+                if recorded {
+                    market.pending_trader_claims = market
+                        .pending_trader_claims
+                        .checked_add(remainder)
+                        .ok_or(AsterDexError::MathOverflow)?;
+                } else {
+                    // Every claim slot is taken by other markets; the remainder goes to the insurance fund and the
+                    // event leaves a record the trader can be made whole from
+                    market.insurance_fund_balance = market
+                        .insurance_fund_balance
+                        .checked_add(remainder)
+                        .ok_or(AsterDexError::MathOverflow)?;
+                    emit!(RemainderToInsurance {
+                        market: market_key,
+                        position: ctx.accounts.position.key(),
+                        trader: position.trader,
+                        amount: remainder,
+                    });
+                }
+            }
+        }
+
+        if compact_events_enabled(&ctx.accounts.config) {
+            emit_compact(
+                CompactEventKind::Liquidate,
+                &CompactLiquidate {
+                    position: ctx.accounts.position.key(),
+                    trader: position.trader,
+                    liquidator: ctx.accounts.liquidator.key(),
+                    liquidation_price: current_price,
+                    fee: liquidator_reward,
+                    insurance_share,
+                    treasury_share,
+                    remainder,
+                    epoch,
+                    zero_collateral,
+                },
+            )?;
+        } else {
+            emit!(PositionLiquidated {
+                position: ctx.accounts.position.key(),
+                trader: position.trader,
+                liquidator: ctx.accounts.liquidator.key(),
+                liquidation_price: current_price,
+                price_expo: ctx.accounts.market.oracle_exponent,
+                normalized_liquidation_price: normalize_price(current_price, ctx.accounts.market.oracle_exponent)?,
+                price_format: PRICE_FORMAT_VERSION,
+                mark_price,
+                publish_time,
+                fee: liquidator_reward,
+                insurance_share,
+                treasury_share,
+                remainder,
+                epoch,
+                zero_collateral,
+                memo: position.memo,
+                converted_reward,
+                delay_compensation,
+            });
+        }
+
+        if let Some(keeper_account) = ctx.accounts.keeper_account.as_mut() {
+            record_keeper_execution(keeper_account, true, current_price, now);
+        }
+
+        // The position account is closed to the liquidator by its close constraint
+        Ok(())
+    }
+
+    pub fn flag_liquidatable(ctx: Context<FlagLiquidatable>) -> Result<()> {
+        require!(!ctx.accounts.config.paused, AsterDexError::ProgramPaused);
+        let (current_price, publish_time) = load_oracle_quote(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+        record_mark_divergence(&mut ctx.accounts.market, current_price, publish_time);
+        let mark_price = calculate_mark_price(&ctx.accounts.market, current_price);
+        require!(
+            is_liquidatable(&ctx.accounts.position, &ctx.accounts.market, mark_price)?,
+            AsterDexError::CannotLiquidateYet
+        );
+
+        // Keep the earliest flag; it is what exempts the position from a later grace period
+        let position = &mut ctx.accounts.position;
+        if position.liquidatable_flagged_at == 0 {
+            position.liquidatable_flagged_at = Clock::get()?.unix_timestamp;
+            position.breach_price = current_price;
+        }
+
+        Ok(())
+    }
+
+    pub fn clear_liquidatable_flag(ctx: Context<FlagLiquidatable>) -> Result<()> {
+        require!(!ctx.accounts.config.paused, AsterDexError::ProgramPaused);
+        let current_price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+
+        // Only a position whose health has been restored past the hysteresis band loses its flag
+        let mark_price = calculate_mark_price(&ctx.accounts.market, current_price);
+        require!(
+            clears_liquidation_band(&ctx.accounts.position, &ctx.accounts.market, mark_price)?,
+            AsterDexError::PositionStillLiquidatable
+        );
+        clear_liquidation_flag(&mut ctx.accounts.position);
+
+        Ok(())
+    }
+
+    pub fn update_funding(ctx: Context<UpdateFunding>, new_funding_index: i64) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        require!(market.admin == ctx.accounts.admin.key(), AsterDexError::Unauthorized);
+        require!(market.status != MarketStatus::Archived, AsterDexError::MarketArchived);
+        
+        let now = Clock::get()?.unix_timestamp;
+        market.last_funding_index = new_funding_index;
+        market.last_funding_time = now;
+        market.funding_paused_secs_checkpoint = total_paused_secs(&ctx.accounts.config, now);
+        
+        Ok(())
+    }
+
+    pub fn crank_funding(ctx: Context<CrankFunding>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let market = &mut ctx.accounts.market;
+
+        // Nothing to do (and nothing to pay) while paused or once archived; neither is the keeper's fault
+        if market.status == MarketStatus::Archived || ctx.accounts.config.paused {
+            return Ok(());
+        }
+
+        // Nor until a full unpaused funding interval has elapsed; a registered keeper's early crank counts against it
+        if unpaused_secs_since_funding(&ctx.accounts.config, market, now) < market.funding_interval {
+            if let Some(keeper_account) = ctx.accounts.keeper_account.as_mut() {
+                record_keeper_execution(keeper_account, false, 0, now);
+            }
+            return Ok(());
+        }
+
+        let raw_rate = calculate_funding_rate(market)?;
+        let applied_rate = clamp_funding_rate(market, raw_rate);
+
+        market.last_funding_index = market
+            .last_funding_index
+            .checked_add(applied_rate)
+            .ok_or(AsterDexError::MathOverflow)?;
+        market.last_funding_time = now;
+        market.funding_paused_secs_checkpoint = total_paused_secs(&ctx.accounts.config, now);
+
+        if compact_events_enabled(&ctx.accounts.config) {
+            emit_compact(
+                CompactEventKind::Funding,
+                &CompactFunding {
+                    market: market.key(),
+                    applied_rate,
+                    open_interest_long: market.open_interest_long,
+                    open_interest_short: market.open_interest_short,
+                    funding_index: market.last_funding_index,
+                },
+            )?;
+        } else {
+            emit!(FundingRateApplied {
+                market: market.key(),
+                raw_rate,
+                applied_rate,
+                interval: market.funding_interval,
+                open_interest_long: market.open_interest_long,
+                open_interest_short: market.open_interest_short,
+                funding_index: market.last_funding_index,
+            });
+        }
+
+        // Pay the keeper from bounty lamports held on the market above its rent-exempt minimum
+        let market_info = ctx.accounts.market.to_account_info();
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(market_info.data_len());
+        let bounty = market_info.lamports().saturating_sub(rent_exempt_minimum);
+        let (reward, _) = discount_unregistered_keeper(
+            &ctx.accounts.market,
+            ctx.accounts.market.crank_reward.min(bounty),
+            ctx.accounts.keeper_account.is_some(),
+        )?;
+
+        if reward > 0 {
+            let keeper_info = ctx.accounts.keeper.to_account_info();
+            **market_info.try_borrow_mut_lamports()? -= reward;
+            **keeper_info.try_borrow_mut_lamports()? += reward;
+
+            let market = &mut ctx.accounts.market;
+            market.total_crank_payouts = market
+                .total_crank_payouts
+                .checked_add(reward)
+                .ok_or(AsterDexError::MathOverflow)?;
+        }
+
+        // Keepers that pass the feed also refresh the mark price, its EMA and the published prices
+        let index_price = match ctx.accounts.price_feed.as_ref() {
+            Some(price_feed) => {
+                let price = load_oracle_price(price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+                update_mark_price_ema(&mut ctx.accounts.market, price, now);
+                refresh_mark_price(&mut ctx.accounts.market, price);
+                Some(price)
+            }
+            None => None,
+        };
+        if let Some(keeper_account) = ctx.accounts.keeper_account.as_mut() {
+            record_keeper_execution(keeper_account, true, index_price.unwrap_or(0), now);
+        }
+
+        // Keep the published stats in step with the new funding rate
+        if let Some(market_stats) = ctx.accounts.market_stats.as_mut() {
+            write_market_stats(market_stats, &ctx.accounts.market, index_price, now)?;
+        }
+
+        // Stop new opens on a utilization breach; only the super admin can unfreeze them
+        if let Some(vault) = ctx.accounts.vault.as_ref() {
+            let market = &mut ctx.accounts.market;
+            let utilization_bps =
+                calculate_utilization_bps(market, vault.amount, market.open_interest_long, market.open_interest_short);
+            if market.utilization_pause_threshold_bps > 0
+                && utilization_bps > market.utilization_pause_threshold_bps as u64
+                && !market.opens_frozen
+            {
+                market.opens_frozen = true;
+
+                emit!(AutoPauseTriggered {
+                    market: market.key(),
+                    utilization_bps,
+                    threshold_bps: market.utilization_pause_threshold_bps,
+                });
+            }
+        }
+
+        emit!(FundingCranked {
+            market: ctx.accounts.market.key(),
+            keeper: ctx.accounts.keeper.key(),
+            funding_index: ctx.accounts.market.last_funding_index,
+            reward,
+        });
+
+        Ok(())
+    }
+
+    pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
+        require!(ctx.accounts.position.size > 0, AsterDexError::InvalidPosition);
+        settle_position_funding(&mut ctx.accounts.position, &mut ctx.accounts.market)?;
+
+        Ok(())
+    }
+
+    pub fn sweep_funding_dust(ctx: Context<SweepFundingDust>) -> Result<()> {
+        // Dust is only swept once per epoch, after the epoch that produced it has closed
+        let epoch = roll_epoch(&mut ctx.accounts.config, Clock::get()?.unix_timestamp)?;
+        let market = &mut ctx.accounts.market;
+        require!(epoch > market.last_funding_sweep_epoch, AsterDexError::FundingSweepTooEarly);
+
+        // Only the surplus already held in the pool can move; unsettled payments stay owed to the pool
+        let residual = calculate_funding_residual(market)?;
+        let amount = residual.min(market.funding_pool_balance).max(0);
+
+        market.funding_pool_balance -= amount;
+        market.insurance_fund_balance = market
+            .insurance_fund_balance
+            .checked_add(amount as u64)
+            .ok_or(AsterDexError::MathOverflow)?;
+        market.last_funding_sweep_epoch = epoch;
+
+        emit!(FundingDustSwept {
+            market: market.key(),
+            epoch,
+            amount: amount as u64,
+            funding_pool_balance: market.funding_pool_balance,
+            insurance_fund_balance: market.insurance_fund_balance,
+        });
+
+        Ok(())
+    }
+
+    pub fn init_market_stats(ctx: Context<InitMarketStats>) -> Result<()> {
+        let market_stats = &mut ctx.accounts.market_stats;
+        market_stats.version = MARKET_STATS_VERSION;
+        market_stats.market = ctx.accounts.market.key();
+        market_stats.bump = ctx.bumps.market_stats;
+
+        Ok(())
+    }
+
+    pub fn init_param_history(ctx: Context<InitParamHistory>, retention_slots: u64) -> Result<()> {
+        // The first entry records the parameters already in force, so every later slot resolves to a set
+        let param_history = &mut ctx.accounts.param_history;
+        param_history.market = ctx.accounts.market.key();
+        param_history.retention_slots = retention_slots;
+        param_history.bump = ctx.bumps.param_history;
+        record_param_change(param_history, &ctx.accounts.market, ctx.accounts.admin.key(), Clock::get()?.slot)?;
+        ctx.accounts.market.param_history_enabled = true;
+
+        Ok(())
+    }
+
+    pub fn update_market_stats(ctx: Context<UpdateMarketStats>) -> Result<()> {
+        let price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        write_market_stats(&mut ctx.accounts.market_stats, &ctx.accounts.market, Some(price), now)?;
+
+        Ok(())
+    }
+
+    pub fn migrate_market_stats(ctx: Context<MigrateMarketStats>) -> Result<()> {
+        // Accounts created under an older layout are grown in place; the appended fields start zeroed
+        // and are filled by the next update_market_stats
+        let market_stats = ctx.accounts.market_stats.to_account_info();
+        let new_len = 8 + MarketStats::LEN;
+        require!(market_stats.data_len() < new_len, AsterDexError::AlreadyMigrated);
+
+        let rent_shortfall = Rent::get()?.minimum_balance(new_len).saturating_sub(market_stats.lamports());
+        if rent_shortfall > 0 {
+            let transfer_ctx = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: market_stats.clone(),
+                },
+            );
+            system_program::transfer(transfer_ctx, rent_shortfall)?;
+        }
+        market_stats.realloc(new_len, true)?;
+
+        Ok(())
+    }
+
+    pub fn get_normalized_price(ctx: Context<GetNormalizedPrice>) -> Result<NormalizedPriceQuote> {
+        // Same helpers, same order as the trading paths, so bots can check their normalization against ours
+        let market = &ctx.accounts.market;
+        let price = load_oracle(&ctx.accounts.price_feed, &ctx.accounts.config, market)?;
+        let raw_price = positive_price(&price)?;
+        let now = Clock::get()?.unix_timestamp;
+
+        Ok(NormalizedPriceQuote {
+            price: raw_price,
+            conf: price.conf,
+            price_expo: price.expo,
+            normalized_price: normalize_price(raw_price, price.expo)?,
+            normalized_conf: normalize_price(price.conf, price.expo)?,
+            publish_time: price.publish_time,
+            age_secs: now.saturating_sub(price.publish_time),
+            quality_ok: oracle_quality_ok(&ctx.accounts.price_feed, market)?,
+            mark_divergent: is_mark_divergent(market, raw_price),
+            last_good_price: market.last_good_price,
+            price_format: PRICE_FORMAT_VERSION,
+        })
+    }
+
+    pub fn get_funding_rate(ctx: Context<GetFundingRate>) -> Result<i64> {
+        let market = &ctx.accounts.market;
+
+        Ok(clamp_funding_rate(market, calculate_funding_rate(market)?))
+    }
+
+    pub fn project_holding_cost(ctx: Context<ProjectHoldingCost>, horizon_secs: i64) -> Result<HoldingCostProjection> {
+        require!(horizon_secs >= 0, AsterDexError::InvalidHorizon);
+        let market = &ctx.accounts.market;
+        let position = &ctx.accounts.position;
+        let current_price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+
+        // Assume every crank lands on time at today's skew and no further pauses: one applied rate per
+        // unpaused interval
+        let funding_secs = unpaused_secs_since_funding(&ctx.accounts.config, market, now)
+            .checked_add(horizon_secs)
+            .ok_or(AsterDexError::MathOverflow)?;
+        let funding_intervals = if market.funding_interval > 0 && funding_secs > 0 {
+            (funding_secs / market.funding_interval) as u64
+        } else {
+            0
+        };
+        let funding_rate_bps = clamp_funding_rate(market, calculate_funding_rate(market)?);
+        let projected_index = i64::try_from(funding_intervals)
+            .ok()
+            .and_then(|intervals| funding_rate_bps.checked_mul(intervals))
+            .and_then(|accrued| accrued.checked_add(market.last_funding_index))
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        // Same settlement math the position will actually go through
+        let projected_funding = calculate_funding_owed(position, projected_index)?;
+        let mut projected_position = position.clone();
+        projected_position.collateral = (position.collateral as i128 - projected_funding as i128) as u64;
+
+        let (_, close_fee) = calculate_pnl(&projected_position, market, current_price)?;
+        let liquidation_price = calculate_liquidation_price(&projected_position, market)?;
+
+        Ok(HoldingCostProjection {
+            horizon_secs,
+            funding_intervals,
+            funding_rate_bps,
+            projected_funding,
+            close_fee,
+            projected_collateral: projected_position.collateral,
+            liquidation_price,
+        })
+    }
+
+    pub fn get_position_breakdown(ctx: Context<GetPositionBreakdown>) -> Result<PositionBreakdown> {
+        let market = &ctx.accounts.market;
+        let current_price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+
+        // Price the position the way close_position would: funding settled into the collateral first
+        let mut settled_position = ctx.accounts.position.clone();
+        let accrued_funding = calculate_funding_owed(&settled_position, market.last_funding_index)?;
+        settled_position.collateral = (settled_position.collateral as i128 - accrued_funding as i128) as u64;
+
+        let (close_pnl, close_fee) = calculate_pnl(&settled_position, market, current_price)?;
+
+        // Unrealized PnL is marked like the liquidation check; what a close pays settles at the index
+        let mark_price = calculate_mark_price(market, current_price);
+        let (unrealized_pnl, _) = calculate_pnl(&settled_position, market, mark_price)?;
+
+        Ok(PositionBreakdown {
+            price: current_price,
+            mark_price,
+            collateral: ctx.accounts.position.collateral,
+            accrued_funding,
+            unrealized_pnl,
+            close_fee,
+            net_withdrawable: calculate_return_amount(&settled_position, close_pnl, close_fee),
+            base_size: ctx.accounts.position.base_size,
+            unrealized_pnl_base: pnl_to_base(market, unrealized_pnl, mark_price)?,
+        })
+    }
+
+    pub fn quote_open_position(
+        ctx: Context<QuoteOpenPosition>,
+        collateral_amount: u64,
+        leverage: u16,
+        base_order: Option<BaseSizeOrder>,
+    ) -> Result<OpenQuote> {
+        let market = &ctx.accounts.market;
+        let price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, market)?;
+        require!(price > 0, AsterDexError::InvalidPrice);
+
+        // Resolve the order exactly as open_position would, without the account and balance checks
+        let (collateral_amount, leverage, position_size, base_size) = match base_order {
+            Some(order) => {
+                let (collateral_amount, leverage, position_size) = resolve_base_order(market, &order, leverage, price)?;
+                (collateral_amount, leverage, position_size, order.desired_base_size)
+            }
+            None => {
+                let position_size = u64::try_from(collateral_amount as u128 * leverage as u128)
+                    .map_err(|_| error!(AsterDexError::MathOverflow))?;
+                (collateral_amount, leverage, position_size, notional_to_base(market, position_size, price)?)
+            }
+        };
+        require_lte("leverage", leverage as u64, "max", market.max_leverage as u64, AsterDexError::LeverageTooHigh)?;
+
+        Ok(OpenQuote {
+            price,
+            collateral_amount,
+            leverage,
+            position_size,
+            base_size,
+        })
+    }
+
+    pub fn attest_position(ctx: Context<AttestPosition>) -> Result<()> {
+        let (oracle_price, publish_time) =
+            load_oracle_quote(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+        let clock = Clock::get()?;
+        let position = &ctx.accounts.position;
+        let market = &ctx.accounts.market;
+
+        let snapshot = PositionSnapshot {
+            version: ATTESTATION_VERSION,
+            position: position.key(),
+            trader: position.trader,
+            market: market.key(),
+            market_id: position.market_id,
+            is_long: position.is_long,
+            collateral: position.collateral,
+            size: position.size,
+            base_size: position.base_size,
+            entry_price: position.entry_price,
+            price_exponent: position.price_exponent,
+            leverage: position.leverage,
+            open_time: position.open_time,
+            last_funding_index: position.last_funding_index,
+            oracle_price,
+            oracle_exponent: market.oracle_exponent,
+            oracle_publish_time: publish_time,
+            contract_type: market.contract_type,
+            liquidation_threshold: position_terms(position, market).2,
+            max_leverage: market.max_leverage,
+            market_funding_index: market.last_funding_index,
+            slot: clock.slot,
+            timestamp: clock.unix_timestamp,
+        };
+        let snapshot_hash = attestation_hash(&snapshot)?;
+
+        let attestation = &mut ctx.accounts.attestation;
+        attestation.creator = ctx.accounts.creator.key();
+        attestation.hash = snapshot_hash;
+        attestation.snapshot = snapshot;
+        attestation.bump = ctx.bumps.attestation;
+
+        emit!(PositionAttested {
+            attestation: attestation.key(),
+            position: position.key(),
+            version: ATTESTATION_VERSION,
+            hash: snapshot_hash,
+            slot: clock.slot,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_attestation(_ctx: Context<CloseAttestation>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn record_shortfall(ctx: Context<RecordShortfall>, haircut_bps: u16) -> Result<()> {
+        require!(haircut_bps > 0, AsterDexError::InvalidHaircut);
+        require_lte("haircut_bps", haircut_bps as u64, "max", BPS_SCALE, AsterDexError::InvalidHaircut)?;
+
+        // Socializing is the last resort: the insurance fund has to be exhausted first
+        let market = &mut ctx.accounts.market;
+        require!(market.insurance_fund_balance == 0, AsterDexError::InsuranceFundNotEmpty);
+
+        let deficit = expected_vault_balance(market) - ctx.accounts.vault.amount as i128;
+        require!(deficit > VAULT_RECONCILIATION_TOLERANCE as i128, AsterDexError::NoShortfall);
+        let deficit = u64::try_from(deficit).map_err(|_| error!(AsterDexError::MathOverflow))?;
+
+        // Unswept fees take the loss before traders do; winners cover the rest through haircuts on their profit
+        let fees_absorbed = deficit.min(market.accumulated_fees);
+        market.accumulated_fees -= fees_absorbed;
+        let shortfall = deficit - fees_absorbed;
+        market.socialized_shortfall = market.socialized_shortfall.checked_add(shortfall).ok_or(AsterDexError::MathOverflow)?;
+        market.total_shortfall_recorded = market
+            .total_shortfall_recorded
+            .checked_add(shortfall)
+            .ok_or(AsterDexError::MathOverflow)?;
+        market.haircut_bps = if market.socialized_shortfall > 0 { haircut_bps } else { 0 };
+
+        emit!(ShortfallRecorded {
+            market: market.key(),
+            deficit,
+            fees_absorbed,
+            shortfall,
+            outstanding: market.socialized_shortfall,
+            haircut_bps: market.haircut_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn get_market_unrealized_pnl(ctx: Context<GetMarketUnrealizedPnl>) -> Result<i64> {
+        let current_price = load_oracle_price(&ctx.accounts.price_feed, &ctx.accounts.config, &ctx.accounts.market)?;
+
+        calculate_market_unrealized_pnl(&ctx.accounts.market, current_price)
+    }
+
+    pub fn verify_vault_accounting(ctx: Context<VerifyVaultAccounting>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let vault_balance = ctx.accounts.vault.amount;
+
+        let residual = vault_balance as i128 - expected_vault_balance(market);
+
+        // Once every position settles, the funding pool must still cover what the receiving side is owed
+        let funding_residual = calculate_funding_residual(market)?;
+
+        emit!(VaultReconciled {
+            market: market.key(),
+            vault_balance,
+            total_reserved_collateral: market.total_reserved_collateral,
+            accumulated_fees: market.accumulated_fees,
+            realized_pnl_paid: market.realized_pnl_paid,
+            funding_pool_balance: market.funding_pool_balance,
+            insurance_fund_balance: market.insurance_fund_balance,
+            residual: residual as i64,
+            funding_residual,
+        });
+
+        require!(
+            residual.unsigned_abs() <= VAULT_RECONCILIATION_TOLERANCE as u128,
+            AsterDexError::AccountingMismatch
+        );
+        require!(
+            funding_residual >= -(VAULT_RECONCILIATION_TOLERANCE as i64),
+            AsterDexError::AccountingMismatch
+        );
+
+        // Rebates are only ever paid out of collected premiums
+        require!(
+            market.total_skew_premiums_collected >= market.total_skew_rebates_paid
+                && market.total_skew_premiums_collected - market.total_skew_rebates_paid == market.skew_premium_pool,
+            AsterDexError::AccountingMismatch
+        );
+
+        Ok(())
+    }
+
+    pub fn fund_rebate_epoch(ctx: Context<FundRebateEpoch>, epoch: u64, amount: u64, claim_deadline: i64) -> Result<()> {
+        // Rebates are paid against the volume of the epoch that just ended
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            claim_deadline >= now.saturating_add(MIN_REBATE_CLAIM_WINDOW_SECS),
+            AsterDexError::InvalidRebateClaimDeadline
+        );
+        let current = roll_epoch(&mut ctx.accounts.config, now)?;
+        require!(epoch.checked_add(1) == Some(current), AsterDexError::RebateEpochNotFinished);
+        let total_volume = ctx.accounts.config.previous_epoch_total_volume;
+        require!(total_volume > 0, AsterDexError::NoRebateVolume);
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.funder_token_account.to_account_info(),
+                mint: ctx.accounts.rebate_mint.to_account_info(),
+                to: ctx.accounts.rebate_vault.to_account_info(),
+                authority: ctx.accounts.super_admin.to_account_info(),
+            },
+        );
+        token::transfer_checked(transfer_ctx, amount, ctx.accounts.rebate_mint.decimals)?;
+
+        let rebate_epoch = &mut ctx.accounts.rebate_epoch;
+        rebate_epoch.epoch = epoch;
+        rebate_epoch.mint = ctx.accounts.rebate_mint.key();
+        rebate_epoch.total_pool = amount;
+        rebate_epoch.remaining_pool = amount;
+        rebate_epoch.total_volume = total_volume;
+        rebate_epoch.remaining_volume = total_volume;
+        rebate_epoch.claim_deadline = claim_deadline;
+        rebate_epoch.bump = ctx.bumps.rebate_epoch;
+
+        emit!(RebateEpochFunded {
+            epoch,
+            mint: rebate_epoch.mint,
+            amount,
+            total_volume,
+            claim_deadline,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_rebate(ctx: Context<ClaimRebate>) -> Result<()> {
+        let rebate_epoch = &mut ctx.accounts.rebate_epoch;
+        require!(
+            !rebate_epoch.swept && Clock::get()?.unix_timestamp <= rebate_epoch.claim_deadline,
+            AsterDexError::RebateClaimClosed
+        );
+
+        let volume = trader_epoch_volume(&ctx.accounts.trader_account, rebate_epoch.epoch);
+        require!(volume > 0, AsterDexError::NoRebateVolume);
+
+        let amount = take_rebate_share(rebate_epoch, volume)?;
+
+        let rebate_claim = &mut ctx.accounts.rebate_claim;
+        rebate_claim.trader = ctx.accounts.trader.key();
+        rebate_claim.amount = amount;
+        rebate_claim.bump = ctx.bumps.rebate_claim;
+
+        if amount > 0 {
+            let epoch_bytes = rebate_epoch.epoch.to_le_bytes();
+            let seeds = &[b"rebate".as_ref(), epoch_bytes.as_ref(), &[rebate_epoch.bump]];
+            let signer = &[&seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.rebate_vault.to_account_info(),
+                    mint: ctx.accounts.rebate_mint.to_account_info(),
+                    to: ctx.accounts.trader_token_account.to_account_info(),
+                    authority: rebate_epoch.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, amount, ctx.accounts.rebate_mint.decimals)?;
+        }
+
+        emit!(RebateClaimed {
+            epoch: rebate_epoch.epoch,
+            trader: ctx.accounts.trader.key(),
+            volume,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn sweep_rebates(ctx: Context<SweepRebates>) -> Result<()> {
+        let rebate_epoch = &mut ctx.accounts.rebate_epoch;
+        require!(!rebate_epoch.swept, AsterDexError::RebateClaimClosed);
+        require!(
+            Clock::get()?.unix_timestamp > rebate_epoch.claim_deadline,
+            AsterDexError::RebateClaimWindowOpen
+        );
+
+        // Everything left in the vault goes back, including any rounding dust
+        let amount = ctx.accounts.rebate_vault.amount;
+        rebate_epoch.swept = true;
+        rebate_epoch.remaining_pool = 0;
+
+        if amount > 0 {
+            let epoch_bytes = rebate_epoch.epoch.to_le_bytes();
+            let seeds = &[b"rebate".as_ref(), epoch_bytes.as_ref(), &[rebate_epoch.bump]];
+            let signer = &[&seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.rebate_vault.to_account_info(),
+                    mint: ctx.accounts.rebate_mint.to_account_info(),
+                    to: ctx.accounts.treasury_token_account.to_account_info(),
+                    authority: rebate_epoch.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer_checked(transfer_ctx, amount, ctx.accounts.rebate_mint.decimals)?;
+        }
+
+        emit!(RebatesSwept {
+            epoch: rebate_epoch.epoch,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn get_epoch_stats(ctx: Context<GetEpochStats>) -> Result<EpochStats> {
+        let config = &ctx.accounts.config;
+        let trader_account = &ctx.accounts.trader_account;
+        let epoch = current_epoch(config, Clock::get()?.unix_timestamp);
+
+        // Accumulators from an earlier epoch have not been reset yet but no longer count
+        if trader_account.epoch != epoch {
+            return Ok(EpochStats {
+                epoch,
+                realized_pnl: 0,
+                volume: 0,
+            });
+        }
+
+        Ok(EpochStats {
+            epoch,
+            realized_pnl: trader_account.epoch_realized_pnl,
+            volume: trader_account.epoch_volume,
+        })
+    }
+
+    pub fn fund_crank_bounty(ctx: Context<FundCrankBounty>, amount: u64) -> Result<()> {
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.admin.to_account_info(),
+                to: ctx.accounts.market.to_account_info(),
+            },
+        );
+        system_program::transfer(transfer_ctx, amount)?;
+
+        Ok(())
+    }
+}
+
+// Helper function to check whether events should use the compact AsterEvent encoding
+fn compact_events_enabled(config: &ProgramConfig) -> bool {
+    config.enabled_features & FEATURE_COMPACT_EVENTS != 0
+}
+
+// Helper function to emit a compact event; the payload is the borsh encoding of a fixed-size Compact* struct
+fn emit_compact<T: AnchorSerialize>(kind: CompactEventKind, payload: &T) -> Result<()> {
+    let payload = payload
+        .try_to_vec()
+        .map_err(|_| error!(anchor_lang::error::ErrorCode::AccountDidNotSerialize))?;
+
+    emit!(AsterEvent {
+        version: COMPACT_EVENT_VERSION,
+        kind: kind as u8,
+        payload,
+    });
+
+    Ok(())
+}
+
+// Helper function to end a dry run: the result goes out as return data next to a marker event, then the
+// instruction fails with DryRunSuccess so the runtime rolls back every state change and transfer it made
+fn finish_dry_run<T: AnchorSerialize>(instruction: DryRunInstruction, position: Pubkey, result: &T) -> Result<()> {
+    let data = result
+        .try_to_vec()
+        .map_err(|_| error!(anchor_lang::error::ErrorCode::AccountDidNotSerialize))?;
+    set_return_data(&data);
+
+    emit!(DryRunCompleted { instruction, position });
+
+    err!(AsterDexError::DryRunSuccess)
+}
+
+// Helper function to tell a keypair wallet from a PDA; only wallets are valid points on the ed25519 curve
+fn is_wallet_address(address: &Pubkey) -> bool {
+    validate_edwards(&PodEdwardsPoint(address.to_bytes()))
+}
+
+// Helper function to read the oracle price, rejecting feeds not owned by the configured Pyth program
+fn load_oracle_price(price_feed: &AccountInfo, config: &ProgramConfig, market: &Market) -> Result<u64> {
+    let (price, _) = load_oracle_quote(price_feed, config, market)?;
+
+    Ok(price)
+}
+
+// Helper function to read the oracle price together with its publish time. A price quoted at a different exponent
+// than the market expects is rejected until the admin acknowledges the change.
+fn load_oracle_quote(price_feed: &AccountInfo, config: &ProgramConfig, market: &Market) -> Result<(u64, i64)> {
+    let price = load_oracle(price_feed, config, market)?;
+
+    Ok((positive_price(&price)?, price.publish_time))
+}
+
+// Helper function to read the full oracle price, including its confidence interval
+fn load_oracle(price_feed: &AccountInfo, config: &ProgramConfig, market: &Market) -> Result<Price> {
+    require!(price_feed.owner == &config.pyth_program_id, AsterDexError::InvalidOracleOwner);
+
+    let price_feed: PriceFeed = load_price_feed_from_account_info(price_feed)
+        .map_err(|_| error!(AsterDexError::InvalidOracle))?;
+    let price: Price = price_feed.get_price_unchecked();
+    if price.expo != market.oracle_exponent {
+        msg!("oracle exponent {} != market exponent {}", price.expo, market.oracle_exponent);
+        return err!(AsterDexError::OracleExponentChanged);
+    }
+    positive_price(&price)?;
+
+    Ok(price)
+}
+
+// Helper function to take an oracle price as unsigned. Zero and negative prints are rejected on the signed value,
+// before any cast could wrap them into a huge positive price.
+fn positive_price(price: &Price) -> Result<u64> {
+    u64::try_from(price.price)
+        .ok()
+        .filter(|price| *price > 0)
+        .ok_or_else(|| error!(AsterDexError::InvalidPrice))
+}
+
+// Helper function to check the feed's publisher count and trading status against the market's quality gate
+fn oracle_quality_ok(price_feed: &AccountInfo, market: &Market) -> Result<bool> {
+    if market.min_publishers == 0 && !market.require_trading_status {
+        return Ok(true);
+    }
+
+    let data = price_feed.try_borrow_data()?;
+    let price_account = load_price_account(&data).map_err(|_| error!(AsterDexError::InvalidOracle))?;
+    let enough_publishers = price_account.num_qt >= market.min_publishers as u32;
+    let trading = !market.require_trading_status || price_account.agg.status == PriceStatus::Trading;
+
+    Ok(enough_publishers && trading)
+}
+
+// Helper function to price a close on a feed that failed the quality gate. Taking the worse of the live print and
+// last_good_price keeps a degraded feed from being a free option on the stale price.
+fn degraded_close_price(is_long: bool, oracle_price: u64, last_good_price: u64) -> u64 {
+    if is_long {
+        oracle_price.min(last_good_price)
+    } else {
+        oracle_price.max(last_good_price)
+    }
+}
+
+// Helper function to reject a price that moved too far from what the caller expected (expected_price 0 = no check)
+fn require_price_within(current_price: u64, expected_price: u64, max_deviation_bps: u16) -> Result<()> {
+    if expected_price == 0 {
+        return Ok(());
+    }
+
+    let deviation = current_price.abs_diff(expected_price) as u128 * BPS_SCALE as u128;
+    let allowed = expected_price as u128 * max_deviation_bps as u128;
+    if deviation > allowed {
+        msg!("price {} moved more than {} bps from expected {}", current_price, max_deviation_bps, expected_price);
+        return err!(AsterDexError::PriceMovedRetry);
+    }
+
+    Ok(())
+}
+
+// Helper function to reject an admin instruction bundled with any other instruction for this program in the
+// same transaction, when strict admin mode is on. Only top-level instructions are visible in the sysvar.
+fn require_sole_program_instruction(config: &ProgramConfig, instructions: &Option<UncheckedAccount>) -> Result<()> {
+    if !config.strict_admin_mode {
+        return Ok(());
+    }
+
+    let instructions = instructions
+        .as_ref()
+        .ok_or(AsterDexError::InstructionsSysvarRequired)?
+        .to_account_info();
+    let mut program_instructions = 0;
+    let mut index = 0;
+    while let Ok(instruction) = load_instruction_at_checked(index, &instructions) {
+        if instruction.program_id == crate::ID {
+            program_instructions += 1;
+        }
+        index += 1;
+    }
+    require!(program_instructions <= 1, AsterDexError::BundledAdminInstruction);
+
+    Ok(())
+}
+
+// Helper function to tell whether this instruction is a direct CPI from an allowlisted composer program: the call
+// sits one level below the transaction and the top-level instruction it came from belongs to the composer.
+// Without the instructions sysvar the call is treated as an ordinary one.
+fn is_composer_call(config: &ProgramConfig, instructions: &Option<UncheckedAccount>) -> Result<bool> {
+    let instructions = match instructions.as_ref() {
+        Some(instructions) if get_stack_height() == TRANSACTION_LEVEL_STACK_HEIGHT + 1 => instructions.to_account_info(),
+        _ => return Ok(false),
+    };
+    let current_index = load_current_index_checked(&instructions)?;
+    let top_level = load_instruction_at_checked(current_index as usize, &instructions)?;
+
+    Ok(top_level.program_id != Pubkey::default() && config.composer_programs.contains(&top_level.program_id))
+}
+
+// Helper function to reject a call that re-enters this program through a CPI made while one of its own
+// top-level instructions is still executing, e.g. from a swap program invoked during a close.
+// CPIs from other programs' instructions are still allowed.
+fn require_not_reentrant(instructions: &AccountInfo) -> Result<()> {
+    if get_stack_height() <= TRANSACTION_LEVEL_STACK_HEIGHT {
+        return Ok(());
+    }
+
+    let current_index = load_current_index_checked(instructions)?;
+    let top_level = load_instruction_at_checked(current_index as usize, instructions)?;
+    require!(top_level.program_id != crate::ID, AsterDexError::ReentrantCall);
+
+    Ok(())
+}
+
+// Helper function to check that a price feed parses and holds a positive, fresh price, returning its feed id, price
+// and exponent
+fn validate_oracle_feed(price_feed: &AccountInfo) -> Result<([u8; 32], u64, i32)> {
+    let price_feed: PriceFeed = load_price_feed_from_account_info(price_feed)
+        .map_err(|_| error!(AsterDexError::OracleValidationFailed))?;
+    let now = Clock::get()?.unix_timestamp;
+    let price: Price = price_feed
+        .get_price_no_older_than(now, MAX_ORACLE_INIT_AGE)
+        .ok_or(AsterDexError::OracleValidationFailed)?;
+    require!(price.price > 0, AsterDexError::OracleValidationFailed);
+
+    Ok((price_feed.id.to_bytes(), price.price as u64, price.expo))
+}
+
+// Helper function to value collateral in USD at NORMALIZED_PRICE_EXPONENT. Linear markets are margined in the
+// quote token, taken at face value; inverse markets are margined in the base asset, priced by the market oracle.
+fn collateral_value_usd(market: &Market, amount: u64, decimals: u8, price: u64) -> Result<u64> {
+    let unit_price = match market.contract_type {
+        ContractType::Linear => rescale_price(1, 0, NORMALIZED_PRICE_EXPONENT)?,
+        ContractType::Inverse => normalize_price(price, market.oracle_exponent)?,
+    };
+    let value = rescale_amount(amount as u128 * unit_price as u128, -(decimals as i32), 0)?;
+    u64::try_from(value).map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to express a raw oracle price at NORMALIZED_PRICE_EXPONENT for events and MarketStats
+fn normalize_price(price: u64, exponent: i32) -> Result<u64> {
+    let normalized = FixedPoint::from_mantissa(price as i128, exponent)?.to_mantissa(NORMALIZED_PRICE_EXPONENT)?;
+    u64::try_from(normalized).map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to convert a value quoted at one oracle exponent to another, rounding down
+fn rescale_price(value: u64, from_exponent: i32, to_exponent: i32) -> MathResult<u64> {
+    u64::try_from(rescale_amount(value as u128, from_exponent, to_exponent)?).map_err(|_| MathError::Overflow)
+}
+
+fn rescale_amount(value: u128, from_exponent: i32, to_exponent: i32) -> MathResult<u128> {
+    let shift = from_exponent - to_exponent;
+    let factor = 10u128.checked_pow(shift.unsigned_abs()).ok_or(MathError::Overflow)?;
+
+    if shift >= 0 {
+        value.checked_mul(factor).ok_or(MathError::Overflow)
+    } else {
+        Ok(value / factor)
+    }
+}
+
+// Helper functions to enforce a bound, logging the offending value and the limit on failure
+fn require_gte(name: &str, value: u64, limit_name: &str, limit: u64, error: AsterDexError) -> Result<()> {
+    if value < limit {
+        msg!("{} {} < {} {}", name, value, limit_name, limit);
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+fn require_lte(name: &str, value: u64, limit_name: &str, limit: u64, error: AsterDexError) -> Result<()> {
+    if value > limit {
+        msg!("{} {} > {} {}", name, value, limit_name, limit);
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+// Helper function to settle and close a position, returning the amount paid back to the trader.
+// A non-zero min_return_amount rejects the close when the trader would receive less.
+fn settle_close_position(accounts: &mut ClosePosition, min_return_amount: u64) -> Result<u64> {
+    require_not_reentrant(&accounts.instructions)?;
+    require!(!accounts.config.paused, AsterDexError::ProgramPaused);
+    require!(!accounts.market.emergency_swept, AsterDexError::MarketSwept);
+    require!(accounts.position.size > 0, AsterDexError::InvalidPosition);
+
+    // Settle accrued funding into the collateral before pricing the close
+    settle_position_funding(&mut accounts.position, &mut accounts.market)?;
+    let position = &accounts.position;
+
+    // Get price from Pyth oracle; closes are allowed on a divergent print, which is recorded for liquidations
+    let (oracle_price, publish_time) = load_oracle_quote(&accounts.price_feed, &accounts.config, &accounts.market)?;
+
+    // A thin or halted feed still lets traders out, at whichever of the live print and the last price that
+    // passed the quality gate is worse for them
+    let current_price = if oracle_quality_ok(&accounts.price_feed, &accounts.market)? {
+        accounts.market.last_good_price = oracle_price;
+        oracle_price
+    } else {
+        require!(accounts.market.last_good_price > 0, AsterDexError::OracleQualityTooLow);
+        degraded_close_price(position.is_long, oracle_price, accounts.market.last_good_price)
+    };
+    record_mark_divergence(&mut accounts.market, current_price, publish_time);
+
+    // Calculate PnL
+    let (pnl, fee) = calculate_pnl(position, &accounts.market, current_price)?;
+
+    // Calculate return amount
+    let full_return_amount = calculate_return_amount(position, pnl, fee);
+
+    // The fee can only be collected out of what is left of the collateral
+    let fee_collected = fee.min((position.collateral as i64 + pnl).max(0) as u64);
+    let (principal_returned, profit_paid, loss_absorbed) =
+        record_settlement(&mut accounts.market, position.collateral, full_return_amount, fee_collected)?;
+
+    // While a socialized shortfall is outstanding, part of the profit stays in the vault to refill it
+    let haircut = take_haircut(&mut accounts.market, profit_paid)?;
+    let return_amount = full_return_amount - haircut;
+    if haircut > 0 {
+        emit!(HaircutApplied {
+            market: accounts.market.key(),
+            position: accounts.position.key(),
+            trader: position.trader,
+            profit: profit_paid,
+            haircut,
+            outstanding: accounts.market.socialized_shortfall,
+        });
+    }
+
+    // Reject the close if the price moved past what the trader was quoted (0 disables the check)
+    require_gte("return amount", return_amount, "minimum", min_return_amount, AsterDexError::ReturnBelowMinimum)?;
+
+    // Release open interest
+    update_open_interest(&mut accounts.market, position.is_long, position.size, false)?;
+    let entry_price = rescale_price(position.entry_price, position.price_exponent, accounts.market.oracle_exponent)?;
+    update_pnl_aggregates(&mut accounts.market, position.is_long, position.size, entry_price, false)?;
+    update_funding_weight(&mut accounts.market, position.is_long, position.size, position.last_funding_index, false)?;
+    update_position_count(&mut accounts.market, &mut accounts.config, false)?;
+
+    // Record the close in the trader's history when they keep one
+    let now = Clock::get()?.unix_timestamp;
+    if let Some(history) = accounts.trader_history.as_mut() {
+        append_history(history, HistoryAction::Close, position.market_id, position.size, current_price, pnl, now);
+    }
+
+    // Realized PnL for the epoch is what the trader actually got back, net of fees. A round trip shorter than
+    // min_volume_accrual_secs earns no volume credit, and the credit the open earned is taken back
+    let epoch = roll_epoch(&mut accounts.config, now)?;
+    let wash_filtered = now < position.open_time.saturating_add(accounts.config.min_volume_accrual_secs);
+    record_epoch_activity(
+        &mut accounts.trader_account,
+        &mut accounts.config,
+        return_amount as i64 - position.collateral as i64,
+        if wash_filtered { 0 } else { position.size },
+    )?;
+    if wash_filtered {
+        revoke_open_volume(&mut accounts.trader_account, &mut accounts.config, position.size, position.open_time);
+    }
+    record_market_volume(&mut accounts.market, position.size, now)?;
+    update_mark_price_ema(&mut accounts.market, current_price, now);
+    refresh_mark_price(&mut accounts.market, current_price);
+
+    // Traders with a withdrawal delay have the proceeds escrowed in the vault until the delay passes
+    let withdrawal_delay = effective_withdrawal_delay(&accounts.trader_account, now);
+    if return_amount > 0 && withdrawal_delay > 0 {
+        let escrow = accounts.withdrawal_escrow.as_mut().ok_or(AsterDexError::EscrowRequired)?;
+        escrow.amount = escrow.amount.checked_add(return_amount).ok_or(AsterDexError::MathOverflow)?;
+        escrow.release_time = escrow.release_time.max(now.saturating_add(withdrawal_delay));
+        accounts.market.pending_trader_claims = accounts
+            .market
+            .pending_trader_claims
+            .checked_add(return_amount)
+            .ok_or(AsterDexError::MathOverflow)?;
+
+        emit!(WithdrawalEscrowed {
+            trader: position.trader,
+            market: accounts.market.key(),
+            amount: return_amount,
+            escrowed: escrow.amount,
+            release_time: escrow.release_time,
+        });
+    } else if return_amount > 0 {
+        // Transfer funds back to user
+        let seeds = &[
+            b"vault".as_ref(),
+            accounts.market.to_account_info().key.as_ref(),
+            &[accounts.market.vault_bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        let transfer_ctx = CpiContext::new_with_signer(
+            accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: accounts.vault.to_account_info(),
+                mint: accounts.collateral_mint.to_account_info(),
+                to: accounts.user_token_account.to_account_info(),
+                authority: accounts.vault.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, return_amount, accounts.collateral_mint.decimals)?;
+    }
+
+    if compact_events_enabled(&accounts.config) {
+        emit_compact(
+            CompactEventKind::Close,
+            &CompactClose {
+                position: accounts.position.key(),
+                trader: position.trader,
+                close_price: current_price,
+                pnl,
+                fee,
+                epoch,
+            },
+        )?;
+    } else {
+        emit!(PositionClosed {
+            position: accounts.position.key(),
+            trader: position.trader,
+            close_price: current_price,
+            price_expo: accounts.market.oracle_exponent,
+            normalized_close_price: normalize_price(current_price, accounts.market.oracle_exponent)?,
+            price_format: PRICE_FORMAT_VERSION,
+            mark_price: accounts.market.mark_price,
+            pnl,
+            fee,
+            principal_returned,
+            profit_paid,
+            loss_absorbed,
+            haircut,
+            epoch,
+            memo: position.memo,
+            wash_filtered,
+        });
+    }
+
+    // The position account is closed to the user by its close constraint
+    Ok(return_amount)
+}
+
+// Interface implemented by each supported AMM so settlement swaps stay program-agnostic
+pub trait SwapAdapter {
+    fn swap<'info>(
+        &self,
+        swap_program: &AccountInfo<'info>,
+        user_authority: &AccountInfo<'info>,
+        source: &AccountInfo<'info>,
+        destination: &AccountInfo<'info>,
+        pool_accounts: &[AccountInfo<'info>],
+        amount_in: u64,
+        min_out: u64,
+    ) -> Result<()>;
+}
+
+// Adapter for SPL token-swap style constant-product pools. pool_accounts must be
+// [swap, swap_authority, pool_source, pool_destination, pool_mint, pool_fee_account, token_program]
+pub struct ConstantProductSwap;
+
+impl SwapAdapter for ConstantProductSwap {
+    fn swap<'info>(
+        &self,
+        swap_program: &AccountInfo<'info>,
+        user_authority: &AccountInfo<'info>,
+        source: &AccountInfo<'info>,
+        destination: &AccountInfo<'info>,
+        pool_accounts: &[AccountInfo<'info>],
+        amount_in: u64,
+        min_out: u64,
+    ) -> Result<()> {
+        require!(pool_accounts.len() == 7, AsterDexError::InvalidSwapAccounts);
+
+        // Swap instruction: tag 1, amount_in, minimum_amount_out
+        let mut data = Vec::with_capacity(17);
+        data.push(1);
+        data.extend_from_slice(&amount_in.to_le_bytes());
+        data.extend_from_slice(&min_out.to_le_bytes());
+
+        let instruction = Instruction {
+            program_id: swap_program.key(),
+            accounts: vec![
+                AccountMeta::new_readonly(pool_accounts[0].key(), false),
+                AccountMeta::new_readonly(pool_accounts[1].key(), false),
+                AccountMeta::new_readonly(user_authority.key(), true),
+                AccountMeta::new(source.key(), false),
+                AccountMeta::new(pool_accounts[2].key(), false),
+                AccountMeta::new(pool_accounts[3].key(), false),
+                AccountMeta::new(destination.key(), false),
+                AccountMeta::new(pool_accounts[4].key(), false),
+                AccountMeta::new(pool_accounts[5].key(), false),
+                AccountMeta::new_readonly(pool_accounts[6].key(), false),
+            ],
+            data,
+        };
+
+        let mut account_infos = vec![
+            swap_program.clone(),
+            user_authority.clone(),
+            source.clone(),
+            destination.clone(),
+        ];
+        account_infos.extend_from_slice(pool_accounts);
+        invoke(&instruction, &account_infos)?;
+
+        Ok(())
+    }
+}
+
+// Helper function to convert a base-asset quantity to notional size at a price quoted at the market's exponent.
+// Inverse markets already size positions in the base asset.
+fn base_to_notional(market: &Market, base_size: u64, price: u64) -> Result<u64> {
+    match market.contract_type {
+        ContractType::Linear => {
+            let notional = rescale_amount(base_size as u128 * price as u128, market.oracle_exponent, 0)?;
+            u64::try_from(notional).map_err(|_| error!(AsterDexError::MathOverflow))
+        }
+        ContractType::Inverse => Ok(base_size),
+    }
+}
+
+// Helper function to convert a notional size to base-asset units at a price quoted at the market's exponent,
+// rounding down; it inverts base_to_notional exactly whenever that conversion did not round
+fn notional_to_base(market: &Market, notional: u64, price: u64) -> Result<u64> {
+    match market.contract_type {
+        ContractType::Linear => {
+            require!(price > 0, AsterDexError::InvalidPrice);
+            let base = rescale_amount(notional as u128, 0, market.oracle_exponent)? / price as u128;
+            u64::try_from(base).map_err(|_| error!(AsterDexError::MathOverflow))
+        }
+        ContractType::Inverse => Ok(notional),
+    }
+}
+
+// Helper function to express a linear market's PnL in base units at the current price; inverse PnL already is
+fn pnl_to_base(market: &Market, pnl: i64, price: u64) -> Result<i64> {
+    let base = notional_to_base(market, pnl.unsigned_abs(), price)? as i64;
+    Ok(if pnl < 0 { -base } else { base })
+}
+
+// Helper function to turn a base-size order into (collateral, leverage, notional size) at the given price.
+// With a leverage, the collateral is the least that covers the notional at it; with leverage 0 all of
+// max_collateral is posted and the leverage is the smallest whole multiple that reaches the notional.
+fn resolve_base_order(market: &Market, order: &BaseSizeOrder, leverage: u16, price: u64) -> Result<(u64, u16, u64)> {
+    let position_size = base_to_notional(market, order.desired_base_size, price)?;
+    require!(position_size > 0, AsterDexError::PositionTooSmall);
+
+    let (collateral_amount, leverage) = if leverage > 0 {
+        (mul_div_ceil(position_size, 1, leverage as u64)?, leverage)
+    } else {
+        require!(order.max_collateral > 0, AsterDexError::BelowMinCollateral);
+        let leverage = mul_div_ceil(position_size, 1, order.max_collateral)?;
+        let leverage = u16::try_from(leverage).map_err(|_| error!(AsterDexError::LeverageTooHigh))?;
+        (order.max_collateral, leverage)
+    };
+    require_lte(
+        "collateral",
+        collateral_amount,
+        "max_collateral",
+        order.max_collateral,
+        AsterDexError::MaxCollateralExceeded,
+    )?;
+
+    Ok((collateral_amount, leverage, position_size))
+}
+
+// Helper function to add or remove a position's size from the market open interest
+fn update_open_interest(market: &mut Market, is_long: bool, size: u64, opening: bool) -> Result<()> {
+    let open_interest = if is_long {
+        &mut market.open_interest_long
+    } else {
+        &mut market.open_interest_short
+    };
+
+    *open_interest = if opening {
+        open_interest.checked_add(size)
+    } else {
+        open_interest.checked_sub(size)
+    }
+    .ok_or(AsterDexError::MathOverflow)?;
+
+    Ok(())
+}
+
+// Helper function to add or remove a position from the market's aggregate quantity and entry notional.
+// The quantity is derived from the stored size and entry price, so the close subtracts exactly what the open added.
+// For inverse markets the quantity is the quote notional (size * entry price) rather than the base amount.
+// After an oracle exponent change the rescaled quantities can differ by rounding, so removal stops at zero.
+fn update_pnl_aggregates(market: &mut Market, is_long: bool, size: u64, entry_price: u64, opening: bool) -> Result<()> {
+    require!(entry_price > 0, AsterDexError::InvalidOracle);
+    let quantity = match market.contract_type {
+        ContractType::Linear => size as u128 * PNL_QUANTITY_PRECISION / entry_price as u128,
+        ContractType::Inverse => size as u128 * entry_price as u128,
+    };
+
+    let (aggregate_size, aggregate_entry_notional) = if is_long {
+        (&mut market.aggregate_size_long, &mut market.aggregate_entry_notional_long)
+    } else {
+        (&mut market.aggregate_size_short, &mut market.aggregate_entry_notional_short)
+    };
+
+    if opening {
+        *aggregate_size = aggregate_size.checked_add(quantity).ok_or(AsterDexError::MathOverflow)?;
+        *aggregate_entry_notional = aggregate_entry_notional.checked_add(size).ok_or(AsterDexError::MathOverflow)?;
+    } else {
+        *aggregate_size = aggregate_size.saturating_sub(quantity);
+        *aggregate_entry_notional = aggregate_entry_notional.checked_sub(size).ok_or(AsterDexError::MathOverflow)?;
+    }
+
+    Ok(())
+}
+
+// Helper function to calculate the aggregate unrealized trader PnL of a market at the given price.
+// Matches the sum of per-position PnL up to rounding, without iterating positions.
+fn calculate_market_unrealized_pnl(market: &Market, current_price: u64) -> Result<i64> {
+    let (long_pnl, short_pnl) = match market.contract_type {
+        ContractType::Linear => {
+            let long_value = market.aggregate_size_long * current_price as u128 / PNL_QUANTITY_PRECISION;
+            let short_value = market.aggregate_size_short * current_price as u128 / PNL_QUANTITY_PRECISION;
+
+            (
+                long_value as i128 - market.aggregate_entry_notional_long as i128,
+                market.aggregate_entry_notional_short as i128 - short_value as i128,
+            )
+        }
+        ContractType::Inverse => {
+            require!(current_price > 0, AsterDexError::InvalidOracle);
+            let long_value = market.aggregate_size_long / current_price as u128;
+            let short_value = market.aggregate_size_short / current_price as u128;
+
+            (
+                market.aggregate_entry_notional_long as i128 - long_value as i128,
+                short_value as i128 - market.aggregate_entry_notional_short as i128,
+            )
+        }
+    };
+
+    i64::try_from(long_pnl + short_pnl).map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to compute what the vault should hold: collateral backing open positions + fees kept - net
+// payouts beyond released collateral + settled funding held in the pool + insurance fund + remainders awaiting a
+// trader claim + open skew premiums not yet rebated - socialized shortfall not yet recovered
+fn expected_vault_balance(market: &Market) -> i128 {
+    market.total_reserved_collateral as i128 + market.accumulated_fees as i128
+        - market.realized_pnl_paid as i128
+        + market.funding_pool_balance as i128
+        + market.insurance_fund_balance as i128
+        + market.pending_trader_claims as i128
+        + market.skew_premium_pool as i128
+        - market.socialized_shortfall as i128
+}
+
+// Helper function to take the market's haircut out of a settlement's profit while a shortfall is outstanding.
+// The last haircut is capped at what remains, so the haircuts add up to the recorded shortfall exactly.
+fn take_haircut(market: &mut Market, profit: u64) -> Result<u64> {
+    if market.socialized_shortfall == 0 || profit == 0 {
+        return Ok(0);
+    }
+
+    let haircut = mul_div_ceil(profit, market.haircut_bps as u64, BPS_SCALE)?.min(market.socialized_shortfall);
+    market.socialized_shortfall -= haircut;
+    market.total_haircuts_collected = market
+        .total_haircuts_collected
+        .checked_add(haircut)
+        .ok_or(AsterDexError::MathOverflow)?;
+    if market.socialized_shortfall == 0 {
+        market.haircut_bps = 0;
+    }
+
+    Ok(haircut)
+}
+
+// Helper function to charge a treasury withdrawal against the market's daily limits, starting a new window once
+// the current one has run WITHDRAWAL_WINDOW_SECS. The amount is trimmed to what the window still allows and a
+// withdrawal is refused once nothing is left; a guardian co-signed withdrawal is counted but never limited.
+// Returns (amount allowed, allowance left in the window).
+fn consume_withdrawal_allowance(market: &mut Market, requested: u64, now: i64, guardian_cosigned: bool) -> Result<(u64, u64)> {
+    if now >= market.withdrawal_window_start.saturating_add(WITHDRAWAL_WINDOW_SECS) {
+        market.withdrawal_window_start = now;
+        market.withdrawn_in_window = 0;
+        market.withdrawals_in_window = 0;
+    }
+
+    let amount = if guardian_cosigned {
+        requested
+    } else {
+        require!(remaining_withdrawal_count(market) > 0, AsterDexError::WithdrawalLimitExceeded);
+        let allowed = requested.min(remaining_withdrawal_allowance(market));
+        require!(allowed > 0, AsterDexError::WithdrawalLimitExceeded);
+        allowed
+    };
+
+    market.withdrawn_in_window = market.withdrawn_in_window.saturating_add(amount);
+    market.withdrawals_in_window = market.withdrawals_in_window.saturating_add(1);
+
+    Ok((amount, remaining_withdrawal_allowance(market)))
+}
+
+// Helper function to get the amount left in the current withdrawal window (u64::MAX when there is no limit)
+fn remaining_withdrawal_allowance(market: &Market) -> u64 {
+    if market.daily_withdrawal_limit == 0 {
+        return u64::MAX;
+    }
+    market.daily_withdrawal_limit.saturating_sub(market.withdrawn_in_window)
+}
+
+// Helper function to get the withdrawals left in the current window (u16::MAX when there is no limit)
+fn remaining_withdrawal_count(market: &Market) -> u16 {
+    if market.daily_withdrawal_count_limit == 0 {
+        return u16::MAX;
+    }
+    market.daily_withdrawal_count_limit.saturating_sub(market.withdrawals_in_window)
+}
+
+// Helper function to take a sweep of an archived market's vault out of the protocol balances, in the order fees,
+// skew premium pool, funding pool, insurance fund; anything beyond them was rounding dust
+fn drain_protocol_balances(market: &mut Market, amount: u64) {
+    let fees = amount.min(market.accumulated_fees);
+    market.accumulated_fees -= fees;
+    market.total_fees_swept = market.total_fees_swept.saturating_add(fees);
+    let left = amount - fees;
+
+    let skew_premiums = left.min(market.skew_premium_pool);
+    market.skew_premium_pool -= skew_premiums;
+    let left = left - skew_premiums;
+
+    let funding = left.min(market.funding_pool_balance.max(0) as u64);
+    market.funding_pool_balance -= funding as i64;
+    let left = left - funding;
+
+    market.insurance_fund_balance -= left.min(market.insurance_fund_balance);
+}
+
+// Helper function to pause or resume every market passed in, each of which must be listed on the given registry
+// page. Pausing freezes opens, and liquidations too when asked, without lifting a freeze already in place;
+// resuming lifts both. Markets already in that state, and swept markets, are skipped, so overlapping calls
+// are harmless. Returns (markets changed, markets skipped).
+fn set_markets_paused<'info>(
+    markets: &'info [AccountInfo<'info>],
+    market_page: &MarketPage,
+    authority: Pubkey,
+    pause: bool,
+    pause_liquidations: bool,
+    program_id: &Pubkey,
+) -> Result<(u16, u16)> {
+    let mut changed = 0u16;
+    let mut skipped = 0u16;
+
+    for info in markets {
+        require!(
+            *info.key != Pubkey::default() && market_page.markets.contains(info.key),
+            AsterDexError::MarketNotRegistered
+        );
+        require!(info.is_writable, AsterDexError::MarketNotRegistered);
+        let mut market = Account::<Market>::try_from(info)?;
+
+        let opens_frozen = pause;
+        let liquidations_frozen = pause && (pause_liquidations || market.liquidations_frozen);
+        if market.emergency_swept
+            || (market.opens_frozen == opens_frozen && market.liquidations_frozen == liquidations_frozen)
+        {
+            skipped += 1;
+            continue;
+        }
+
+        market.opens_frozen = opens_frozen;
+        market.liquidations_frozen = liquidations_frozen;
+        market.exit(program_id)?;
+        changed += 1;
+
+        emit!(MarketFrozenSet {
+            market: market.key(),
+            authority,
+            frozen: opens_frozen,
+            liquidations_frozen,
+        });
+    }
+
+    Ok((changed, skipped))
+}
+
+// Helper function to convert a liquidator reward in collateral into the config's reward mint, taken at $1: the
+// reward's USD value at the oracle price, less liquidation_reward_discount_bps, rounded down. The treasury can
+// therefore never pay out more than the penalty it is backed by is worth.
+fn convert_liquidation_reward(
+    config: &ProgramConfig,
+    market: &Market,
+    reward: u64,
+    collateral_decimals: u8,
+    price: u64,
+    reward_decimals: u8,
+) -> Result<u64> {
+    if reward == 0 {
+        return Ok(0);
+    }
+
+    let value_usd = collateral_value_usd(market, reward, collateral_decimals, price)?;
+    let discounted = mul_div_floor(value_usd, BPS_SCALE - config.liquidation_reward_discount_bps as u64, BPS_SCALE)?;
+    let converted = rescale_amount(discounted as u128, NORMALIZED_PRICE_EXPONENT, -(reward_decimals as i32))?;
+    u64::try_from(converted).map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to append a market's current parameters to its history. A full buffer only evicts its oldest
+// entry once that entry stopped being in force (the next entry's slot) more than retention_slots ago.
+fn record_param_change(history: &mut ParamHistory, market: &Market, changed_by: Pubkey, slot: u64) -> Result<()> {
+    if history.count as usize == PARAM_HISTORY_CAPACITY {
+        let superseded_at = history.entries[(history.head as usize + 1) % PARAM_HISTORY_CAPACITY].slot;
+        require!(
+            slot.saturating_sub(superseded_at) > history.retention_slots,
+            AsterDexError::ParamHistoryFull
+        );
+    } else {
+        history.count += 1;
+    }
+
+    history.entries[history.head as usize] = ParamHistoryEntry {
+        slot,
+        changed_by,
+        params: MarketParams::from_market(market),
+    };
+    history.head = ((history.head as usize + 1) % PARAM_HISTORY_CAPACITY) as u16;
+
+    Ok(())
+}
+
+// Helper function to update the vault accounting when a position is settled: its collateral is released,
+// paid_out leaves the vault and fee_collected stays behind as fees.
+// Returns (principal_returned, profit_paid, loss_absorbed): principal comes out of the released collateral,
+// profit out of the vault's liquidity, and the loss is collateral kept by the vault. By construction
+// principal_returned + loss_absorbed + fee_collected == collateral and profit_paid - loss_absorbed is the
+// change in realized_pnl_paid.
+fn record_settlement(market: &mut Market, collateral: u64, paid_out: u64, fee_collected: u64) -> Result<(u64, u64, u64)> {
+    market.total_reserved_collateral = market
+        .total_reserved_collateral
+        .checked_sub(collateral)
+        .ok_or(AsterDexError::MathOverflow)?;
+    market.accumulated_fees = market.accumulated_fees.checked_add(fee_collected).ok_or(AsterDexError::MathOverflow)?;
+
+    let settled_pnl = paid_out as i64 + fee_collected as i64 - collateral as i64;
+    market.realized_pnl_paid = market.realized_pnl_paid.checked_add(settled_pnl).ok_or(AsterDexError::MathOverflow)?;
+
+    let profit_paid = settled_pnl.max(0) as u64;
+    let loss_absorbed = settled_pnl.min(0).unsigned_abs();
+    let principal_returned = paid_out - profit_paid;
+    market.total_profit_paid = market.total_profit_paid.checked_add(profit_paid).ok_or(AsterDexError::MathOverflow)?;
+    market.total_loss_absorbed = market.total_loss_absorbed.checked_add(loss_absorbed).ok_or(AsterDexError::MathOverflow)?;
+
+    Ok((principal_returned, profit_paid, loss_absorbed))
+}
+
+// Helper function to compute the epoch number at a given time without mutating the config
+fn current_epoch(config: &ProgramConfig, now: i64) -> u64 {
+    if config.epoch_length == 0 || now < config.current_epoch_start {
+        return config.current_epoch;
+    }
+
+    config.current_epoch + ((now - config.current_epoch_start) / config.epoch_length) as u64
+}
+
+// Helper function to advance the program epoch if one or more epoch lengths have elapsed, returning the current epoch
+fn roll_epoch(config: &mut ProgramConfig, now: i64) -> Result<u64> {
+    let epoch = current_epoch(config, now);
+    if epoch == config.current_epoch {
+        return Ok(epoch);
+    }
+
+    let elapsed_epochs = epoch - config.current_epoch;
+    config.previous_epoch_total_volume = if elapsed_epochs == 1 { config.epoch_total_volume } else { 0 };
+    config.epoch_total_volume = 0;
+    config.current_epoch = epoch;
+    config.current_epoch_start = config
+        .current_epoch_start
+        .checked_add(elapsed_epochs as i64 * config.epoch_length)
+        .ok_or(AsterDexError::MathOverflow)?;
+
+    emit!(EpochRolled {
+        epoch,
+        epoch_start: config.current_epoch_start,
+        epoch_length: config.epoch_length,
+    });
+
+    Ok(epoch)
+}
+
+// Helper function to add realized PnL and volume to a trader's epoch stats, resetting them first if the epoch rolled over
+fn record_epoch_activity(
+    trader_account: &mut TraderAccount,
+    config: &mut ProgramConfig,
+    realized_pnl: i64,
+    volume: u64,
+) -> Result<()> {
+    if trader_account.epoch != config.current_epoch {
+        // Keep the last active epoch's volume around so it can still be claimed against
+        trader_account.previous_epoch = trader_account.epoch;
+        trader_account.previous_epoch_volume = trader_account.epoch_volume;
+        trader_account.epoch = config.current_epoch;
+        trader_account.epoch_realized_pnl = 0;
+        trader_account.epoch_volume = 0;
+    }
+
+    trader_account.epoch_realized_pnl = trader_account
+        .epoch_realized_pnl
+        .checked_add(realized_pnl)
+        .ok_or(AsterDexError::MathOverflow)?;
+    trader_account.epoch_volume = trader_account.epoch_volume.checked_add(volume).ok_or(AsterDexError::MathOverflow)?;
+    config.epoch_total_volume = config.epoch_total_volume.checked_add(volume).ok_or(AsterDexError::MathOverflow)?;
+    if realized_pnl != 0 {
+        record_realized_pnl(trader_account, realized_pnl)?;
+    }
+
+    Ok(())
+}
+
+// Helper function to take back the volume credit an open earned, once its position closes too quickly to count.
+// Only an open from the current epoch is reversed; a round trip across an epoch boundary keeps the open's credit,
+// since the previous epoch's volume may already be in use for rebates.
+fn revoke_open_volume(trader_account: &mut TraderAccount, config: &mut ProgramConfig, size: u64, open_time: i64) {
+    if open_time < config.current_epoch_start {
+        return;
+    }
+
+    trader_account.epoch_volume = trader_account.epoch_volume.saturating_sub(size);
+    config.epoch_total_volume = config.epoch_total_volume.saturating_sub(size);
+}
+
+// Helper function to get the largest position size the market's liquidity reference allows
+// (u64::MAX when either the reference or the multiple is unset)
+fn liquidity_position_cap(market: &Market) -> Result<u64> {
+    if market.liquidity_reference == 0 || market.max_position_liquidity_multiple_bps == 0 {
+        return Ok(u64::MAX);
+    }
+
+    Ok(mul_div_floor(market.liquidity_reference, market.max_position_liquidity_multiple_bps as u64, BPS_SCALE)?)
+}
+
+// Helper function to cut a keeper payout by the market's unregistered-keeper discount.
+// Returns (amount paid, amount withheld); registered keepers are paid in full.
+fn discount_unregistered_keeper(market: &Market, amount: u64, registered: bool) -> Result<(u64, u64)> {
+    if registered || market.unregistered_keeper_discount_bps == 0 {
+        return Ok((amount, 0));
+    }
+
+    let withheld = mul_div_ceil(amount, market.unregistered_keeper_discount_bps as u64, BPS_SCALE)?;
+    Ok((amount - withheld, withheld))
+}
+
+// Helper function to count a keeper execution and remember the oracle price it acted on
+fn record_keeper_execution(keeper_account: &mut KeeperAccount, success: bool, price: u64, now: i64) {
+    if success {
+        keeper_account.successful_executions = keeper_account.successful_executions.saturating_add(1);
+        keeper_account.last_execution_price = price;
+        keeper_account.last_execution_time = now;
+    } else {
+        keeper_account.failed_executions = keeper_account.failed_executions.saturating_add(1);
+    }
+}
+
+// Helper function to read the withdrawal delay in force at the given time, applying a scheduled reduction once due
+fn effective_withdrawal_delay(trader_account: &TraderAccount, now: i64) -> i64 {
+    if trader_account.withdrawal_delay_effective_at != 0 && now >= trader_account.withdrawal_delay_effective_at {
+        trader_account.pending_withdrawal_delay_secs
+    } else {
+        trader_account.withdrawal_delay_secs
+    }
+}
+
+// Helper function to add to a trader's lifetime realized PnL and track its high-water mark and worst drawdown.
+// Drawdown is measured from the high-water mark once there is a positive one, so it can exceed 10_000 bps
+// when realized PnL falls below zero.
+fn record_realized_pnl(trader_account: &mut TraderAccount, realized_pnl: i64) -> Result<()> {
+    trader_account.realized_pnl = trader_account
+        .realized_pnl
+        .checked_add(realized_pnl as i128)
+        .ok_or(AsterDexError::MathOverflow)?;
+
+    if trader_account.realized_pnl > trader_account.equity_high_water {
+        trader_account.equity_high_water = trader_account.realized_pnl;
+    } else if trader_account.equity_high_water > 0 {
+        let drawdown = (trader_account.equity_high_water - trader_account.realized_pnl) * BPS_SCALE as i128
+            / trader_account.equity_high_water;
+        let drawdown_bps = u32::try_from(drawdown).unwrap_or(u32::MAX);
+        trader_account.max_drawdown_bps = trader_account.max_drawdown_bps.max(drawdown_bps);
+    }
+
+    Ok(())
+}
+
+// Helper function to compute the attested hash of a position snapshot: SHA-256 of its canonical Borsh encoding
+fn attestation_hash(snapshot: &PositionSnapshot) -> Result<[u8; 32]> {
+    let bytes = snapshot
+        .try_to_vec()
+        .map_err(|_| error!(anchor_lang::error::ErrorCode::AccountDidNotSerialize))?;
+    Ok(hash(&bytes).to_bytes())
+}
+
+// Helper function to look up a trader's volume in a given epoch from its current or previous accumulators
+fn trader_epoch_volume(trader_account: &TraderAccount, epoch: u64) -> u64 {
+    if trader_account.epoch == epoch {
+        trader_account.epoch_volume
+    } else if trader_account.previous_epoch == epoch {
+        trader_account.previous_epoch_volume
+    } else {
+        0
+    }
+}
+
+// Helper function to take a trader's pro-rata share out of a rebate epoch. Shares are computed against what is
+// left rather than the original totals, so rounding can never pay out more than the pool holds and the last
+// claimant receives the dust
+fn take_rebate_share(rebate_epoch: &mut RebateEpoch, volume: u64) -> Result<u64> {
+    let amount = mul_div_floor(rebate_epoch.remaining_pool, volume, rebate_epoch.remaining_volume)?;
+    rebate_epoch.remaining_pool -= amount;
+    rebate_epoch.remaining_volume = rebate_epoch
+        .remaining_volume
+        .checked_sub(volume)
+        .ok_or(AsterDexError::MathOverflow)?;
+    Ok(amount)
+}
+
+// Rounding policy for all payout math: fees round up (in favor of the protocol), amounts paid to traders
+// round down, and funding rounds against the payer. Use the helpers below rather than bare division.
+
+// Errors from the fixed-point math helpers. They do not depend on Anchor, so the helpers can be shared with
+// off-chain code; handlers convert them into AsterDexError with `?`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathError {
+    Overflow,
+    DivisionByZero,
+    // A conversion would have to drop significant digits
+    PrecisionLoss,
+}
+
+pub type MathResult<T> = std::result::Result<T, MathError>;
+
+impl From<MathError> for AsterDexError {
+    fn from(error: MathError) -> Self {
+        match error {
+            MathError::Overflow => AsterDexError::MathOverflow,
+            MathError::DivisionByZero => AsterDexError::DivisionByZero,
+            MathError::PrecisionLoss => AsterDexError::PrecisionLoss,
+        }
+    }
+}
+
+impl From<MathError> for anchor_lang::error::Error {
+    fn from(error: MathError) -> Self {
+        AsterDexError::from(error).into()
+    }
+}
+
+// Decimal places carried by FixedPoint
+pub const FIXED_POINT_DECIMALS: u32 = 12;
+pub const FIXED_POINT_SCALE: i128 = 1_000_000_000_000;
+
+// Signed fixed-point number with FIXED_POINT_DECIMALS decimal places, shared by price, funding and skew math.
+// The raw i128 is the value times FIXED_POINT_SCALE; its Borsh encoding is that raw value, so the scale is part
+// of the wire format. Conversions out of FixedPoint round toward zero.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FixedPoint(i128);
+
+impl FixedPoint {
+    pub const ZERO: FixedPoint = FixedPoint(0);
+    pub const ONE: FixedPoint = FixedPoint(FIXED_POINT_SCALE);
+
+    pub const fn from_raw(raw: i128) -> Self {
+        FixedPoint(raw)
+    }
+
+    pub const fn raw(self) -> i128 {
+        self.0
+    }
+
+    pub fn from_int(value: i128) -> MathResult<Self> {
+        value.checked_mul(FIXED_POINT_SCALE).map(FixedPoint).ok_or(MathError::Overflow)
+    }
+
+    // numerator / denominator, truncated toward zero at the last decimal place
+    pub fn from_ratio(numerator: i128, denominator: i128) -> MathResult<Self> {
+        if denominator == 0 {
+            return Err(MathError::DivisionByZero);
+        }
+        numerator
+            .checked_mul(FIXED_POINT_SCALE)
+            .and_then(|scaled| scaled.checked_div(denominator))
+            .map(FixedPoint)
+            .ok_or(MathError::Overflow)
+    }
+
+    // mantissa * 10^exponent, e.g. a Pyth price or a value quoted at NORMALIZED_PRICE_EXPONENT
+    pub fn from_mantissa(mantissa: i128, exponent: i32) -> MathResult<Self> {
+        let shift = exponent + FIXED_POINT_DECIMALS as i32;
+        let factor = 10i128.checked_pow(shift.unsigned_abs()).ok_or(MathError::Overflow)?;
+        if shift >= 0 {
+            mantissa.checked_mul(factor).map(FixedPoint).ok_or(MathError::Overflow)
+        } else {
+            Ok(FixedPoint(mantissa / factor))
+        }
+    }
+
+    // The mantissa m such that m * 10^exponent approximates self, truncated toward zero
+    pub fn to_mantissa(self, exponent: i32) -> MathResult<i128> {
+        let shift = exponent + FIXED_POINT_DECIMALS as i32;
+        let factor = 10i128.checked_pow(shift.unsigned_abs()).ok_or(MathError::Overflow)?;
+        if shift >= 0 {
+            Ok(self.0 / factor)
+        } else {
+            self.0.checked_mul(factor).ok_or(MathError::Overflow)
+        }
+    }
+
+    pub fn from_pyth(price: &Price) -> MathResult<Self> {
+        Self::from_mantissa(price.price as i128, price.expo)
+    }
+
+    // A token amount in base units of a mint with `decimals` decimals
+    pub fn from_token_amount(amount: u64, decimals: u8) -> MathResult<Self> {
+        Self::from_mantissa(amount as i128, -(decimals as i32))
+    }
+
+    // Base units of a mint with `decimals` decimals, rounded down; negative values are rejected
+    pub fn to_token_amount(self, decimals: u8) -> MathResult<u64> {
+        u64::try_from(self.to_mantissa(-(decimals as i32))?).map_err(|_| MathError::Overflow)
+    }
+
+    // The integer part, truncated toward zero
+    pub fn trunc(self) -> i128 {
+        self.0 / FIXED_POINT_SCALE
+    }
+
+    pub fn checked_add(self, other: Self) -> MathResult<Self> {
+        self.0.checked_add(other.0).map(FixedPoint).ok_or(MathError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Self) -> MathResult<Self> {
+        self.0.checked_sub(other.0).map(FixedPoint).ok_or(MathError::Overflow)
+    }
+
+    pub fn checked_mul(self, other: Self) -> MathResult<Self> {
+        self.0
+            .checked_mul(other.0)
+            .map(|product| FixedPoint(product / FIXED_POINT_SCALE))
+            .ok_or(MathError::Overflow)
+    }
+
+    pub fn checked_div(self, other: Self) -> MathResult<Self> {
+        Self::from_ratio(self.0, other.0)
+    }
+
+    pub fn checked_mul_int(self, value: i128) -> MathResult<Self> {
+        self.0.checked_mul(value).map(FixedPoint).ok_or(MathError::Overflow)
+    }
+}
+
+// Helper function to compute a * b / c rounded down, rejecting overflow and division by zero
+fn mul_div_floor(a: u64, b: u64, c: u64) -> MathResult<u64> {
+    if c == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+    u64::try_from(a as u128 * b as u128 / c as u128).map_err(|_| MathError::Overflow)
+}
+
+// Helper function to compute a * b / c rounded up, rejecting overflow and division by zero
+fn mul_div_ceil(a: u64, b: u64, c: u64) -> MathResult<u64> {
+    if c == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+    let c = c as u128;
+    u64::try_from((a as u128 * b as u128 + c - 1) / c).map_err(|_| MathError::Overflow)
+}
+
+// Helper function to split accrued fees into the distributed share (rounded down) and what stays behind;
+// the two always add back up to the accrued amount
+fn split_fees(accrued: u64, distribution_bps: u16) -> Result<(u64, u64)> {
+    let share = mul_div_floor(accrued, distribution_bps as u64, BPS_SCALE)?;
+    Ok((share, accrued - share))
+}
+
+// Helper function to check that fees are distributed to the destination (e.g. a staking hook) configured for them,
+// in the vault's mint
+fn validate_distribution_destination(
+    config: &ProgramConfig,
+    destination: Pubkey,
+    destination_mint: Pubkey,
+    vault_mint: Pubkey,
+) -> Result<()> {
+    require!(config.distribution_destination != Pubkey::default(), AsterDexError::FeeDistributionDisabled);
+    require!(destination == config.distribution_destination, AsterDexError::InvalidDistributionDestination);
+    require!(destination_mint == vault_mint, AsterDexError::InvalidMint);
+    Ok(())
+}
+
+// Helper function to split a liquidation penalty into (liquidator, insurance, treasury) amounts. The protocol
+// shares round up like fees and the liquidator gets what is left, so the three always sum to the penalty.
+// Markets that never set a split pay everything to the liquidator.
+fn split_liquidation_penalty(market: &Market, penalty: u64) -> Result<(u64, u64, u64)> {
+    let insurance = mul_div_ceil(penalty, market.insurance_share_bps as u64, BPS_SCALE)?;
+    let treasury = mul_div_ceil(penalty, market.treasury_share_bps as u64, BPS_SCALE)?.min(penalty - insurance);
+    Ok((penalty - insurance - treasury, insurance, treasury))
+}
+
+// Helper function for signed division rounded toward negative infinity (the divisor must be positive)
+fn div_floor(numerator: i128, denominator: i128) -> i128 {
+    numerator.div_euclid(denominator)
+}
+
+// Helper function for signed division rounded toward positive infinity (the divisor must be positive)
+fn div_ceil(numerator: i128, denominator: i128) -> i128 {
+    -(-numerator).div_euclid(denominator)
+}
+
+// Helper function to fold a traded price into the market's mark EMA. The old mark's weight halves every
+// half-life, interpolated linearly within one; a zero half-life just tracks the last price.
+fn update_mark_price_ema(market: &mut Market, price: u64, now: i64) {
+    let half_life = market.mark_ema_half_life_secs;
+    let elapsed = now.saturating_sub(market.mark_price_ema_time).max(0);
+    let halvings = if half_life > 0 { elapsed / half_life } else { 64 };
+
+    market.mark_price_ema = if market.mark_price_ema == 0 || halvings >= 64 {
+        price
+    } else {
+        let remainder = (elapsed % half_life) as u128;
+        let kept = (BPS_SCALE as u128 >> halvings) * (2 * half_life as u128 - remainder) / (2 * half_life as u128);
+        ((market.mark_price_ema as u128 * kept + price as u128 * (BPS_SCALE as u128 - kept)) / BPS_SCALE as u128) as u64
+    };
+    market.mark_price_ema_time = now;
+}
+
+// Helper function to check whether an oracle price is further from the mark EMA than the market allows (0 = no limit)
+fn is_mark_divergent(market: &Market, price: u64) -> bool {
+    if market.max_mark_divergence_bps == 0 || market.mark_price_ema == 0 {
+        return false;
+    }
+
+    let deviation = price.abs_diff(market.mark_price_ema) as u128 * BPS_SCALE as u128;
+    deviation > market.mark_price_ema as u128 * market.max_mark_divergence_bps as u128
+}
+
+// Helper function to remember the first publish time of a run of divergent prints, cleared by any non-divergent one
+fn record_mark_divergence(market: &mut Market, price: u64, publish_time: i64) {
+    if !is_mark_divergent(market, price) {
+        market.divergent_publish_time = 0;
+    } else if market.divergent_publish_time == 0 {
+        market.divergent_publish_time = publish_time;
+    }
+}
+
+// Helper function to calculate utilization in bps: the larger side's open interest, which is what a 100% move in
+// its favour would pay out, against the vault balance not already owed back as collateral or claims
+fn calculate_utilization_bps(market: &Market, vault_balance: u64, open_interest_long: u64, open_interest_short: u64) -> u64 {
+    let exposure = open_interest_long.max(open_interest_short) as u128;
+    let available = vault_balance
+        .saturating_sub(market.total_reserved_collateral)
+        .saturating_sub(market.pending_trader_claims) as u128;
+
+    if exposure == 0 {
+        return 0;
+    }
+    if available == 0 {
+        return u64::MAX;
+    }
+
+    (exposure * BPS_SCALE as u128 / available).min(u64::MAX as u128) as u64
+}
+
+// Helper function to add traded size to the market's rolling 24h volume buckets
+fn record_market_volume(market: &mut Market, size: u64, now: i64) -> Result<()> {
+    roll_volume_window(market, now);
+    market.volume_current_window = market.volume_current_window.checked_add(size).ok_or(AsterDexError::MathOverflow)?;
+
+    Ok(())
+}
+
+// Helper function to move the volume buckets forward when the current window has ended
+fn roll_volume_window(market: &mut Market, now: i64) {
+    if now < market.volume_window_start.saturating_add(VOLUME_WINDOW) {
+        return;
+    }
+
+    // A gap of more than one window means the previous window saw no trades
+    let windows_elapsed = (now - market.volume_window_start) / VOLUME_WINDOW;
+    market.volume_previous_window = if windows_elapsed == 1 { market.volume_current_window } else { 0 };
+    market.volume_current_window = 0;
+    market.volume_window_start += windows_elapsed * VOLUME_WINDOW;
+}
+
+// Helper function to estimate the trailing 24h volume, weighting the previous window by how much of it still overlaps
+fn rolling_volume(market: &Market, now: i64) -> u64 {
+    let (previous, current, window_start) = if now >= market.volume_window_start.saturating_add(VOLUME_WINDOW) {
+        let windows_elapsed = (now - market.volume_window_start) / VOLUME_WINDOW;
+        let previous = if windows_elapsed == 1 { market.volume_current_window } else { 0 };
+        (previous, 0, market.volume_window_start + windows_elapsed * VOLUME_WINDOW)
+    } else {
+        (market.volume_previous_window, market.volume_current_window, market.volume_window_start)
+    };
+
+    let overlap = (VOLUME_WINDOW - (now - window_start)) as u128;
+    current.saturating_add((previous as u128 * overlap / VOLUME_WINDOW as u128) as u64)
+}
+
+// Helper function to refresh the published MarketStats from the market, leaving prices untouched when none is given
+fn write_market_stats(market_stats: &mut MarketStats, market: &Market, price: Option<u64>, now: i64) -> Result<()> {
+    if let Some(price) = price {
+        let mark_price = calculate_mark_price(market, price);
+        market_stats.mark_price = mark_price;
+        market_stats.index_price = price;
+        market_stats.price_expo = market.oracle_exponent;
+        market_stats.normalized_mark_price = normalize_price(mark_price, market.oracle_exponent)?;
+        market_stats.normalized_index_price = normalize_price(price, market.oracle_exponent)?;
+        market_stats.price_format = PRICE_FORMAT_VERSION;
+    }
+    market_stats.open_interest_long = market.open_interest_long;
+    market_stats.open_interest_short = market.open_interest_short;
+    market_stats.version = MARKET_STATS_VERSION;
+    market_stats.funding_rate_bps = clamp_funding_rate(market, calculate_funding_rate(market)?);
+    market_stats.volume_24h = rolling_volume(market, now);
+    market_stats.last_update = now;
+    market_stats.params_immutable = market.params_immutable;
+
+    Ok(())
+}
+
+// Helper function to track open position counts, enforcing the market and global caps (0 = unlimited)
+fn update_position_count(market: &mut Market, config: &mut ProgramConfig, opening: bool) -> Result<()> {
+    if opening {
+        require!(
+            market.max_open_positions == 0 || market.open_position_count < market.max_open_positions,
+            AsterDexError::MarketPositionCapReached
+        );
+        require!(
+            config.max_total_open_positions == 0 || config.total_open_positions < config.max_total_open_positions,
+            AsterDexError::GlobalPositionCapReached
+        );
+
+        market.open_position_count = market.open_position_count.checked_add(1).ok_or(AsterDexError::MathOverflow)?;
+        config.total_open_positions = config.total_open_positions.checked_add(1).ok_or(AsterDexError::MathOverflow)?;
+    } else {
+        market.open_position_count = market.open_position_count.checked_sub(1).ok_or(AsterDexError::MathOverflow)?;
+        config.total_open_positions = config.total_open_positions.checked_sub(1).ok_or(AsterDexError::MathOverflow)?;
+    }
+
+    Ok(())
+}
+
+// Helper function to remember a client order id, rejecting one already seen in the recent window (0 = no id)
+fn register_client_order_id(trader_account: &mut TraderAccount, client_order_id: u64) -> Result<()> {
+    if client_order_id == 0 {
+        return Ok(());
+    }
+
+    require!(
+        !trader_account.recent_client_order_ids.contains(&client_order_id),
+        AsterDexError::DuplicateClientOrderId
+    );
+
+    let index = (trader_account.total_client_order_ids % CLIENT_ORDER_ID_WINDOW as u64) as usize;
+    trader_account.recent_client_order_ids[index] = client_order_id;
+    trader_account.total_client_order_ids = trader_account.total_client_order_ids.wrapping_add(1);
+
+    Ok(())
+}
+
+// Helper function to check that an account is an initialized, unfrozen token account of the given mint and owner
+fn is_valid_token_account(account: &AccountInfo, mint: &Pubkey, owner: &Pubkey) -> bool {
+    if account.owner != &token::ID {
+        return false;
+    }
+
+    let data = match account.try_borrow_data() {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    match TokenAccount::try_deserialize(&mut &data[..]) {
+        Ok(token_account) => token_account.mint == *mint && token_account.owner == *owner && !token_account.is_frozen(),
+        Err(_) => false,
+    }
+}
+
+// Helper function to credit an amount the trader can claim later, merging with an existing claim on the same market.
+// Returns false, recording nothing, when every claim slot is taken by other markets.
+fn add_pending_claim(trader_account: &mut TraderAccount, market: Pubkey, mint: Pubkey, amount: u64) -> Result<bool> {
+    if let Some(claim) = trader_account
+        .pending_claims
+        .iter_mut()
+        .find(|claim| claim.amount > 0 && claim.market == market)
+    {
+        claim.amount = claim.amount.checked_add(amount).ok_or(AsterDexError::MathOverflow)?;
+        return Ok(true);
+    }
+
+    match trader_account.pending_claims.iter_mut().find(|claim| claim.amount == 0) {
+        Some(slot) => {
+            *slot = PendingClaim { market, mint, amount };
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Helper function to write a record into the trader's history ring buffer, overwriting the oldest entry
+fn append_history(
+    history: &mut TraderHistory,
+    action: HistoryAction,
+    market_id: [u8; 32],
+    size: u64,
+    price: u64,
+    pnl: i64,
+    timestamp: i64,
+) {
+    let index = (history.total_records % TRADER_HISTORY_CAPACITY as u64) as usize;
+    history.records[index] = HistoryRecord {
+        action,
+        market_id,
+        size,
+        price,
+        pnl,
+        timestamp,
+    };
+    history.total_records = history.total_records.wrapping_add(1);
+}
+
+// Helper function to add or remove a position's size weighted by its funding index, so the funding
+// still owed by all open positions on a side is size * current index - weight
+fn update_funding_weight(market: &mut Market, is_long: bool, size: u64, funding_index: i64, opening: bool) -> Result<()> {
+    let weight = size as i128 * funding_index as i128;
+    let funding_weight = if is_long {
+        &mut market.funding_weight_long
+    } else {
+        &mut market.funding_weight_short
+    };
+
+    *funding_weight = if opening {
+        funding_weight.checked_add(weight)
+    } else {
+        funding_weight.checked_sub(weight)
+    }
+    .ok_or(AsterDexError::MathOverflow)?;
+
+    Ok(())
+}
+
+// Helper function to get the total seconds the program has spent under a global pause, including an ongoing one
+fn total_paused_secs(config: &ProgramConfig, now: i64) -> i64 {
+    if config.paused {
+        config
+            .cumulative_paused_secs
+            .saturating_add(now.saturating_sub(config.last_pause_time).max(0))
+    } else {
+        config.cumulative_paused_secs
+    }
+}
+
+// Helper function to get the time since the market's last funding update, less any time spent paused since then
+fn unpaused_secs_since_funding(config: &ProgramConfig, market: &Market, now: i64) -> i64 {
+    let paused = total_paused_secs(config, now).saturating_sub(market.funding_paused_secs_checkpoint);
+    now.saturating_sub(market.last_funding_time).saturating_sub(paused).max(0)
+}
+
+// Accounts execute_open works on, borrowed from either OpenPosition or OpenIntoSlot
+struct OpenAccounts<'a, 'info> {
+    user: &'a Signer<'info>,
+    config: &'a mut Account<'info, ProgramConfig>,
+    market: &'a mut Account<'info, Market>,
+    trader_account: &'a mut Account<'info, TraderAccount>,
+    position: &'a mut Account<'info, Position>,
+    user_token_account: &'a Account<'info, TokenAccount>,
+    vault: &'a mut Account<'info, TokenAccount>,
+    collateral_mint: &'a Account<'info, Mint>,
+    price_feed: &'a AccountInfo<'info>,
+    token_program: &'a Program<'info, Token>,
+    trader_history: Option<&'a mut Account<'info, TraderHistory>>,
+    collateral_delegate: Option<&'a UncheckedAccount<'info>>,
+    instructions: &'a Option<UncheckedAccount<'info>>,
+    // The position is a pre-created slot that already holds its nonce
+    into_slot: bool,
+}
+
+// Order parameters shared by open_position and open_into_slot
+struct OpenRequest {
+    market_id: [u8; 32],
+    is_long: bool,
+    collateral_amount: u64,
+    leverage: u16,
+    client_order_id: Option<u64>,
+    accept_liquidation_risk: Option<bool>,
+    base_order: Option<BaseSizeOrder>,
+    dry_run: Option<bool>,
+    memo: Option<[u8; 32]>,
+}
+
+// Helper function to open a position into an account that is either freshly created by open_position or an
+// empty slot pre-created by create_position_slot, whose nonce is already taken
+fn execute_open(mut accounts: OpenAccounts, program_id: &Pubkey, request: OpenRequest) -> Result<()> {
+    let OpenRequest {
+        market_id,
+        is_long,
+        collateral_amount,
+        leverage,
+        client_order_id,
+        accept_liquidation_risk,
+        base_order,
+        dry_run,
+        memo,
+    } = request;
+
+    // A base-size order fixes the notional at the oracle price and derives the collateral or leverage from it
+    let (collateral_amount, leverage, position_size) = match base_order {
+        Some(order) => {
+            let price = positive_price(&load_oracle(&accounts.price_feed, &accounts.config, &accounts.market)?)?;
+            resolve_base_order(&accounts.market, &order, leverage, price)?
+        }
+        None => {
+            let size = u64::try_from(collateral_amount as u128 * leverage as u128)
+                .map_err(|_| error!(AsterDexError::MathOverflow))?;
+            (collateral_amount, leverage, size)
+        }
+    };
+
+    let market = &accounts.market;
+    require!(!accounts.config.paused, AsterDexError::ProgramPaused);
+    require!(market.status == MarketStatus::Active, AsterDexError::MarketInactive);
+    require!(!market.emergency_swept, AsterDexError::MarketSwept);
+    require!(!market.opens_frozen, AsterDexError::MarketFrozen);
+    require_not_under_liquidation(&accounts.position)?;
+    require_gte("leverage", leverage as u64, "min", 1, AsterDexError::LeverageTooLow)?;
+    require_lte("leverage", leverage as u64, "max", market.max_leverage as u64, AsterDexError::LeverageTooHigh)?;
+    if market.min_collateral_mode != MinCollateralMode::Usd {
+        require_gte("collateral", collateral_amount, "min", market.min_collateral, AsterDexError::BelowMinCollateral)?;
+    }
+    require_lte(
+        "collateral",
+        collateral_amount,
+        "balance",
+        accounts.user_token_account.amount,
+        AsterDexError::InsufficientBalance,
+    )?;
+    require_gte(
+        "size",
+        position_size,
+        "min",
+        accounts.config.min_position_size,
+        AsterDexError::PositionTooSmall,
+    )?;
+    require_lte(
+        "size",
+        position_size,
+        "liquidity_cap",
+        liquidity_position_cap(market)?,
+        AsterDexError::PositionTooLarge,
+    )?;
+
+    // Cap how many positions a single trader can open within one slot; allowlisted composers are exempt
+    let slot = Clock::get()?.slot;
+    let max_opens_per_slot = if is_composer_call(&accounts.config, &accounts.instructions)? {
+        0
+    } else {
+        accounts.config.max_opens_per_slot
+    };
+    let trader_account = &mut accounts.trader_account;
+    if trader_account.last_open_slot != slot {
+        trader_account.last_open_slot = slot;
+        trader_account.opens_in_slot = 0;
+    }
+    require!(
+        max_opens_per_slot == 0 || trader_account.opens_in_slot < max_opens_per_slot,
+        AsterDexError::RateLimited
+    );
+    trader_account.opens_in_slot += 1;
+
+    // Refuse new risk that would push the market's worst-case payout past its utilization threshold
+    if market.utilization_pause_threshold_bps > 0 {
+        let (open_interest_long, open_interest_short) = if is_long {
+            (market.open_interest_long.saturating_add(position_size), market.open_interest_short)
+        } else {
+            (market.open_interest_long, market.open_interest_short.saturating_add(position_size))
+        };
+        let utilization_bps =
+            calculate_utilization_bps(market, accounts.vault.amount, open_interest_long, open_interest_short);
+        require_lte(
+            "utilization_bps",
+            utilization_bps,
+            "threshold",
+            market.utilization_pause_threshold_bps as u64,
+            AsterDexError::UtilizationTooHigh,
+        )?;
+    }
+
+    // Get price from Pyth oracle; a zeroed or halted feed must never become an entry price
+    let oracle_price = load_oracle(&accounts.price_feed, &accounts.config, &accounts.market)?;
+    let current_price = positive_price(&oracle_price)?;
+    require_gte("price", current_price, "min_price", accounts.market.min_price, AsterDexError::InvalidPrice)?;
+
+    // The USD minimum is checked at the open-time price, so a falling collateral price raises the token amount needed
+    if accounts.market.min_collateral_mode != MinCollateralMode::Token {
+        let collateral_usd = collateral_value_usd(
+            &accounts.market,
+            collateral_amount,
+            accounts.collateral_mint.decimals,
+            current_price,
+        )?;
+        require_gte(
+            "collateral_usd",
+            collateral_usd,
+            "min",
+            accounts.market.min_collateral_usd,
+            AsterDexError::BelowMinCollateral,
+        )?;
+    }
+
+    // Never open on a print far from the market's own recent mark, or from a thin or halted feed
+    require!(!is_mark_divergent(&accounts.market, current_price), AsterDexError::MarkPriceDivergence);
+    require!(
+        oracle_quality_ok(&accounts.price_feed, &accounts.market)?,
+        AsterDexError::OracleQualityTooLow
+    );
+
+    // Transfer collateral from user to vault, through the program's delegate when the user approved one
+    let vault_balance_before = accounts.vault.amount;
+    if let Some(delegate) = accounts.collateral_delegate.as_ref() {
+        let user_token_account = &accounts.user_token_account;
+        require!(
+            user_token_account.delegate == COption::Some(delegate.key()),
+            AsterDexError::InsufficientDelegation
+        );
+        require_lte(
+            "collateral",
+            collateral_amount,
+            "delegated_amount",
+            user_token_account.delegated_amount,
+            AsterDexError::InsufficientDelegation,
+        )?;
+
+        let (_, delegate_bump) = Pubkey::find_program_address(&[b"delegate"], program_id);
+        let seeds = &[b"delegate".as_ref(), &[delegate_bump]];
+        let signer = &[&seeds[..]];
+        let transfer_ctx = CpiContext::new_with_signer(
+            accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: accounts.user_token_account.to_account_info(),
+                mint: accounts.collateral_mint.to_account_info(),
+                to: accounts.vault.to_account_info(),
+                authority: delegate.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer_checked(transfer_ctx, collateral_amount, accounts.collateral_mint.decimals)?;
+    } else {
+        let transfer_ctx = CpiContext::new(
+            accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: accounts.user_token_account.to_account_info(),
+                mint: accounts.collateral_mint.to_account_info(),
+                to: accounts.vault.to_account_info(),
+                authority: accounts.user.to_account_info(),
+            },
+        );
+        token::transfer_checked(transfer_ctx, collateral_amount, accounts.collateral_mint.decimals)?;
+    }
+
+    // A transfer-fee mint delivers less than was sent; the position is backed only by what arrived, at the
+    // same leverage. Other mints skip the reload.
+    let requested_collateral = collateral_amount;
+    let (collateral_amount, position_size) = if accounts.market.supports_transfer_fee {
+        accounts.vault.reload()?;
+        let received = accounts
+            .vault
+            .amount
+            .checked_sub(vault_balance_before)
+            .ok_or(AsterDexError::MathOverflow)?;
+        require_gte("received", received, "min", accounts.market.min_collateral, AsterDexError::BelowMinCollateral)?;
+        (received, mul_div_floor(position_size, received, requested_collateral)?)
+    } else {
+        (collateral_amount, position_size)
+    };
+
+    // Keep the size in base units too, so closes and PnL can be read that way
+    let base_size = match base_order {
+        Some(order) if collateral_amount == requested_collateral => order.desired_base_size,
+        _ => notional_to_base(&accounts.market, position_size, current_price)?,
+    };
+
+    // Opens that widen the long/short imbalance pay a premium out of their collateral; opens that narrow it
+    // are credited a rebate from those premiums, never more than the pool holds
+    let (skew_premium, skew_rebate) = apply_open_skew_fee(&mut accounts.market, is_long, position_size)?;
+    require!(skew_premium < collateral_amount, AsterDexError::SkewPremiumExceedsCollateral);
+    let collateral_amount = collateral_amount - skew_premium + skew_rebate;
+
+    // Track open interest for funding
+    update_open_interest(&mut accounts.market, is_long, position_size, true)?;
+    update_pnl_aggregates(&mut accounts.market, is_long, position_size, current_price, true)?;
+    update_position_count(&mut accounts.market, &mut accounts.config, true)?;
+    accounts.market.total_reserved_collateral = accounts
+        .market
+        .total_reserved_collateral
+        .checked_add(collateral_amount)
+        .ok_or(AsterDexError::MathOverflow)?;
+    let funding_index = accounts.market.last_funding_index;
+    update_funding_weight(&mut accounts.market, is_long, position_size, funding_index, true)?;
+
+    // Create position account
+    let position = &mut accounts.position;
+    position.trader = accounts.user.key();
+    position.market_id = market_id;
+    position.collateral = collateral_amount;
+    position.size = position_size;
+    position.base_size = base_size;
+    position.is_long = is_long;
+    position.entry_price = current_price;
+    position.leverage = leverage;
+    position.open_time = Clock::get()?.unix_timestamp;
+    position.collateral_mint = accounts.collateral_mint.key();
+    position.last_funding_index = funding_index;
+    position.price_exponent = accounts.market.oracle_exponent;
+    position.memo = memo.unwrap_or_default();
+
+    // Pin the terms this position is held to; funding stays market-current
+    position.params_pinned = true;
+    position.close_fee_bps = CLOSE_FEE_BPS as u16;
+    position.liquidation_penalty_bps = LIQUIDATION_REWARD_BPS as u16;
+    position.liquidation_threshold = bracket_liquidation_threshold(&accounts.market, position_size);
+
+    // A liquidation price inside the oracle's confidence band could be hit by the next update.
+    // Traders can accept that risk explicitly; the choice is kept on the position.
+    let liquidation_risk_accepted = accept_liquidation_risk.unwrap_or(false);
+    let conf_multiplier = accounts.market.liquidation_conf_multiplier;
+    if conf_multiplier > 0 && !liquidation_risk_accepted {
+        let liquidation_price = calculate_liquidation_price(position, &accounts.market)?;
+        let min_distance = oracle_price.conf.saturating_mul(conf_multiplier as u64);
+        if current_price.abs_diff(liquidation_price) < min_distance {
+            msg!(
+                "liquidation price {} within {} of price {}",
+                liquidation_price,
+                min_distance,
+                current_price
+            );
+            return err!(AsterDexError::LiquidationPriceWithinConfidence);
+        }
+    }
+    position.liquidation_risk_accepted = liquidation_risk_accepted;
+
+    // Advance the nonce so the next position address is known before it is opened; a slot took its nonce when created
+    let trader_account = &mut accounts.trader_account;
+    let nonce = if accounts.into_slot {
+        position.nonce
+    } else {
+        let nonce = trader_account.position_nonce;
+        trader_account.position_nonce = nonce.checked_add(1).ok_or(AsterDexError::MathOverflow)?;
+        nonce
+    };
+    position.nonce = nonce;
+
+    // Reject retries of an order that already opened a position
+    let client_order_id = client_order_id.unwrap_or(0);
+    register_client_order_id(trader_account, client_order_id)?;
+    position.client_order_id = client_order_id;
+
+    // Credit the opened size to the trader's volume for the current epoch
+    let open_time = position.open_time;
+    roll_epoch(&mut accounts.config, open_time)?;
+    record_epoch_activity(trader_account, &mut accounts.config, 0, position_size)?;
+    record_market_volume(&mut accounts.market, position_size, open_time)?;
+    record_mark_divergence(&mut accounts.market, current_price, oracle_price.publish_time);
+    update_mark_price_ema(&mut accounts.market, current_price, open_time);
+    accounts.market.last_good_price = current_price;
+    refresh_mark_price(&mut accounts.market, current_price);
+
+    // Record the open in the trader's history when they keep one
+    if let Some(history) = accounts.trader_history.as_mut() {
+        append_history(history, HistoryAction::Open, market_id, position_size, current_price, 0, open_time);
+    }
+
+    if compact_events_enabled(&accounts.config) {
+        emit_compact(
+            CompactEventKind::Open,
+            &CompactOpen {
+                position: accounts.position.key(),
+                trader: accounts.user.key(),
+                nonce,
+                is_long,
+                collateral_amount,
+                position_size,
+                entry_price: current_price,
+                leverage,
+                liquidation_risk_accepted,
+            },
+        )?;
+    } else {
+        emit!(PositionOpened {
+            position: accounts.position.key(),
+            trader: accounts.user.key(),
+            market_id,
+            nonce,
+            client_order_id,
+            is_long,
+            collateral_amount,
+            position_size,
+            entry_price: current_price,
+            price_expo: accounts.market.oracle_exponent,
+            normalized_entry_price: normalize_price(current_price, accounts.market.oracle_exponent)?,
+            price_format: PRICE_FORMAT_VERSION,
+            mark_price: accounts.market.mark_price,
+            leverage,
+            liquidation_risk_accepted,
+            base_size,
+            skew_premium,
+            skew_rebate,
+            memo: accounts.position.memo,
+        });
+    }
+
+    if dry_run.unwrap_or(false) {
+        return finish_dry_run(
+            DryRunInstruction::Open,
+            accounts.position.key(),
+            &OpenQuote {
+                price: current_price,
+                collateral_amount,
+                leverage,
+                position_size,
+                base_size,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+// Helper function to settle a position's accrued funding through the market funding pool. Whichever side the
+// index moved against pays into the pool and the other side is paid out of it; a payer can never lose more
+// than its collateral.
+// Returns the amount moved from the position into the pool (negative when the position received).
+fn settle_position_funding(position: &mut Position, market: &mut Market) -> Result<i64> {
+    let entry_index = position.last_funding_index;
+    let current_index = market.last_funding_index;
+    if entry_index == current_index {
+        return Ok(0);
+    }
+
+    let payment = calculate_funding_owed(position, current_index)?;
+
+    position.collateral = (position.collateral as i128 - payment as i128) as u64;
+    market.total_reserved_collateral = (market.total_reserved_collateral as i128 - payment as i128)
+        .try_into()
+        .map_err(|_| error!(AsterDexError::MathOverflow))?;
+    market.funding_pool_balance = market.funding_pool_balance.checked_add(payment).ok_or(AsterDexError::MathOverflow)?;
+
+    // Move the position to the current index
+    update_funding_weight(market, position.is_long, position.size, entry_index, false)?;
+    update_funding_weight(market, position.is_long, position.size, current_index, true)?;
+    position.last_funding_index = current_index;
+
+    emit!(FundingSettled {
+        market_id: market.market_id,
+        trader: position.trader,
+        nonce: position.nonce,
+        is_long: position.is_long,
+        payment,
+        funding_pool_balance: market.funding_pool_balance,
+    });
+
+    Ok(payment)
+}
+
+// Helper function to calculate the funding a position owes (negative when it receives) if settled at the given index.
+// Rounding up charges payers the extra unit and shaves it off what receivers get; a payer never owes more than its collateral.
+fn calculate_funding_owed(position: &Position, funding_index: i64) -> Result<i64> {
+    let accrued = (funding_index as i128 - position.last_funding_index as i128)
+        .checked_mul(position.size as i128)
+        .ok_or(AsterDexError::MathOverflow)?;
+    let owed = div_ceil(if position.is_long { accrued } else { -accrued }, BPS_SCALE as i128);
+
+    i64::try_from(owed.min(position.collateral as i128)).map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to calculate what would be left in the funding pool if every open position settled now.
+// Payers are charged rounded up and receivers paid rounded down, so this is normally a small positive surplus.
+fn calculate_funding_residual(market: &Market) -> Result<i64> {
+    let index = market.last_funding_index as i128;
+    let unsettled_long = (index * market.open_interest_long as i128 - market.funding_weight_long as i128) / BPS_SCALE as i128;
+    let unsettled_short = (index * market.open_interest_short as i128 - market.funding_weight_short as i128) / BPS_SCALE as i128;
+
+    i64::try_from(market.funding_pool_balance as i128 + unsettled_long - unsettled_short)
+        .map_err(|_| error!(AsterDexError::MathOverflow))
+}
+
+// Helper function to calculate the mark price: the index moved against the crowded side by up to
+// skew_premium_bps, in proportion to how lopsided open interest is (0 = mark equals index). A heavy long skew
+// marks longs below the index, so their health is worse than the index alone suggests, and shorts above it.
+fn calculate_mark_price(market: &Market, index_price: u64) -> u64 {
+    let total_open_interest = market.open_interest_long as i128 + market.open_interest_short as i128;
+    if market.skew_premium_bps == 0 || total_open_interest == 0 {
+        return index_price;
+    }
+
+    let skew = market.open_interest_long as i128 - market.open_interest_short as i128;
+    let premium = index_price as i128 * market.skew_premium_bps as i128 * skew / (BPS_SCALE as i128 * total_open_interest);
+    (index_price as i128 - premium).clamp(0, u64::MAX as i128) as u64
+}
+
+// Helper function to price an open against the open interest skew before it is added. An open that leaves the
+// absolute skew smaller earns open_skew_rebate_bps of its size, paid from the premium pool and capped at its
+// balance; any other open is charged open_skew_premium_bps of its size into the pool.
+// Returns (premium charged, rebate paid); at most one is non-zero.
+fn apply_open_skew_fee(market: &mut Market, is_long: bool, size: u64) -> Result<(u64, u64)> {
+    let skew_before = (market.open_interest_long as i128 - market.open_interest_short as i128).unsigned_abs();
+    let skew_after = if is_long {
+        (market.open_interest_long as i128 + size as i128 - market.open_interest_short as i128).unsigned_abs()
+    } else {
+        (market.open_interest_long as i128 - market.open_interest_short as i128 - size as i128).unsigned_abs()
+    };
+
+    if skew_after < skew_before {
+        let rebate = mul_div_floor(size, market.open_skew_rebate_bps as u64, BPS_SCALE)?.min(market.skew_premium_pool);
+        market.skew_premium_pool -= rebate;
+        market.total_skew_rebates_paid = market
+            .total_skew_rebates_paid
+            .checked_add(rebate)
+            .ok_or(AsterDexError::MathOverflow)?;
+        return Ok((0, rebate));
+    }
+
+    let premium = mul_div_ceil(size, market.open_skew_premium_bps as u64, BPS_SCALE)?;
+    market.skew_premium_pool = market.skew_premium_pool.checked_add(premium).ok_or(AsterDexError::MathOverflow)?;
+    market.total_skew_premiums_collected = market
+        .total_skew_premiums_collected
+        .checked_add(premium)
+        .ok_or(AsterDexError::MathOverflow)?;
+    Ok((premium, 0))
+}
+
+// Helper function to store the mark price for the market's current skew after a trade or crank
+fn refresh_mark_price(market: &mut Market, index_price: u64) {
+    market.mark_price = calculate_mark_price(market, index_price);
+}
+
+// Helper function to calculate the funding rate for one interval from the open interest skew.
+// Positive when longs outweigh shorts (longs pay shorts), negative the other way round.
+fn calculate_funding_rate(market: &Market) -> Result<i64> {
+    let total_open_interest = market.open_interest_long as i128 + market.open_interest_short as i128;
+    if total_open_interest == 0 {
+        return Ok(0);
+    }
+
+    let skew = market.open_interest_long as i128 - market.open_interest_short as i128;
+
+    // funding_rate_bps scaled by the skew fraction; |rate| <= funding_rate_bps, so the narrowing cannot wrap
+    let rate = FixedPoint::from_ratio(skew * market.funding_rate_bps as i128, total_open_interest)?;
+    Ok(rate.trunc() as i64)
+}
+
+// Helper function to clamp a funding rate to the market's per-interval cap in either direction (0 = uncapped)
+fn clamp_funding_rate(market: &Market, rate: i64) -> i64 {
+    if market.max_funding_rate_bps_per_interval == 0 {
+        return rate;
+    }
+
+    let cap = market.max_funding_rate_bps_per_interval as i64;
+    rate.clamp(-cap, cap)
+}
+
+// Helper function to work out what a late liquidation owes the trader on a market with delay protection. Once
+// max_liquidation_delay_secs have passed since the flag, the trader is owed the remainder a liquidation at the
+// breach price would have left; the shortfall comes out of the liquidator's reward first and then the insurance
+// fund, as far as each covers it.
+// Returns (liquidator reward left, compensation added to the remainder, part of it drawn from insurance).
+fn late_liquidation_compensation(
+    position: &Position,
+    market: &Market,
+    liquidator_reward: u64,
+    remainder: u64,
+    liquidation_fee: u64,
+    insurance_available: u64,
+    now: i64,
+) -> Result<(u64, u64, u64)> {
+    if !market.liquidation_delay_protection
+        || position.liquidatable_flagged_at == 0
+        || position.breach_price == 0
+        || now <= position.liquidatable_flagged_at.saturating_add(market.max_liquidation_delay_secs)
+    {
+        return Ok((liquidator_reward, 0, 0));
+    }
+
+    let (breach_pnl, breach_fee) = calculate_pnl(position, market, position.breach_price)?;
+    let breach_fee_collected = breach_fee.min((position.collateral as i64 + breach_pnl).max(0) as u64);
+    let breach_remainder = (compute_equity(position, breach_pnl, breach_fee) - liquidation_fee as i64)
+        .max(0)
+        .min(position.collateral.saturating_sub(liquidation_fee + breach_fee_collected) as i64) as u64;
+
+    let shortfall = breach_remainder.saturating_sub(remainder);
+    let from_reward = shortfall.min(liquidator_reward);
+    let from_insurance = (shortfall - from_reward).min(insurance_available);
+
+    Ok((liquidator_reward - from_reward, from_reward + from_insurance, from_insurance))
+}
+
+// Helper function to get the liquidation threshold for a position of the given notional: that of the largest
+// size bracket it reaches, or the market's base threshold below the first bracket
+fn bracket_liquidation_threshold(market: &Market, size: u64) -> u16 {
+    market
+        .threshold_brackets
+        .iter()
+        .take_while(|bracket| bracket.size_threshold > 0 && size >= bracket.size_threshold)
+        .last()
+        .map_or(market.liquidation_threshold, |bracket| bracket.liquidation_threshold)
+}
+
+// Helper function to check a market's size brackets: the ones in use come first, with strictly increasing sizes
+// and thresholds that never drop below the base threshold or the bracket before
+fn validate_threshold_brackets(market: &Market) -> Result<()> {
+    let mut previous_size = 0u64;
+    let mut previous_threshold = market.liquidation_threshold;
+    let mut in_use = true;
+    for bracket in market.threshold_brackets.iter() {
+        if bracket.size_threshold == 0 {
+            require!(bracket.liquidation_threshold == 0, AsterDexError::InvalidThresholdBrackets);
+            in_use = false;
+            continue;
+        }
+
+        require!(in_use, AsterDexError::InvalidThresholdBrackets);
+        require!(bracket.size_threshold > previous_size, AsterDexError::InvalidThresholdBrackets);
+        require!(bracket.liquidation_threshold >= previous_threshold, AsterDexError::InvalidThresholdBrackets);
+        require_lte(
+            "liquidation_threshold",
+            bracket.liquidation_threshold as u64,
+            "max",
+            LIQUIDATION_THRESHOLD_SCALE as u64 - 1,
+            AsterDexError::InvalidLiquidationThreshold,
+        )?;
+        previous_size = bracket.size_threshold;
+        previous_threshold = bracket.liquidation_threshold;
+    }
+
+    Ok(())
+}
+
+// Helper function to get the terms a position is held to: (close fee bps, liquidation penalty bps, liquidation
+// threshold). Pinned positions keep the values from their open; older ones use the current values, with the
+// threshold taken from the size bracket the position falls in.
+fn position_terms(position: &Position, market: &Market) -> (u64, u64, u16) {
+    if position.params_pinned {
+        (
+            position.close_fee_bps as u64,
+            position.liquidation_penalty_bps as u64,
+            position.liquidation_threshold,
+        )
+    } else {
+        (CLOSE_FEE_BPS, LIQUIDATION_REWARD_BPS, bracket_liquidation_threshold(market, position.size))
+    }
+}
+
+// Helper function to check a position's health, using the equity left after the close fee
+fn is_liquidatable(position: &Position, market: &Market, current_price: u64) -> Result<bool> {
+    // Funding can drain a position's collateral completely
+    if position.collateral == 0 {
+        return Ok(true);
+    }
+
+    let (pnl, fee) = calculate_pnl(position, market, current_price)?;
+    let equity_percentage = (compute_equity(position, pnl, fee) * LIQUIDATION_THRESHOLD_SCALE) / position.collateral as i64;
+
+    let (_, _, liquidation_threshold) = position_terms(position, market);
+
+    Ok(equity_percentage <= liquidation_threshold as i64)
+}
+
+// Helper function to reject trader-side changes to a flagged position; a full close stays open to the trader and
+// clear_liquidatable_flag lifts the block once health is restored
+fn require_not_under_liquidation(position: &Position) -> Result<()> {
+    require!(position.liquidatable_flagged_at == 0, AsterDexError::PositionUnderLiquidation);
+    Ok(())
+}
+
+// Helper function to drop a position's liquidatable flag along with the breach price recorded with it
+fn clear_liquidation_flag(position: &mut Position) {
+    position.liquidatable_flagged_at = 0;
+    position.breach_price = 0;
+}
+
+// Helper function to check whether a flagged position has recovered far enough to lose its flag: equity must
+// clear the liquidation threshold by liquidation_hysteresis_bps of collateral (0 = any healthy position clears)
+fn clears_liquidation_band(position: &Position, market: &Market, current_price: u64) -> Result<bool> {
+    if position.collateral == 0 {
+        return Ok(false);
+    }
+
+    let (pnl, fee) = calculate_pnl(position, market, current_price)?;
+    let equity_bps = compute_equity(position, pnl, fee) as i128 * BPS_SCALE as i128 / position.collateral as i128;
+    let (_, _, liquidation_threshold) = position_terms(position, market);
+    let threshold_bps = liquidation_threshold as i128 * BPS_SCALE as i128 / LIQUIDATION_THRESHOLD_SCALE as i128;
+
+    let clears_band = equity_bps > threshold_bps + market.liquidation_hysteresis_bps as i128;
+
+    Ok(clears_band && !is_liquidatable(position, market, current_price)?)
+}
+
+// Helper function to find the price at which a position becomes liquidatable, by bisecting is_liquidatable itself
+// so the answer always agrees with the liquidation check. Returns 0 for a long (u64::MAX for a short) that
+// can never be liquidated.
+fn calculate_liquidation_price(position: &Position, market: &Market) -> Result<u64> {
+    // Leverage is at least 1x, so a linear position is wiped out before the price doubles or drops to zero.
+    // Inverse short losses are capped at the size, so they can be searched up to the largest usable price.
+    let mut low = 1u64;
+    let mut high = match (market.contract_type, position.is_long) {
+        (ContractType::Inverse, false) => i64::MAX as u64,
+        _ => rescale_price(position.entry_price, position.price_exponent, market.oracle_exponent)?
+            .saturating_mul(2)
+            .min(i64::MAX as u64),
+    };
+
+    if position.is_long {
+        // Longs are liquidatable at and below the answer: find the highest such price
+        if !is_liquidatable(position, market, low)? {
+            return Ok(0);
+        }
+        while low < high {
+            let mid = low + (high - low + 1) / 2;
+            if is_liquidatable(position, market, mid)? {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        Ok(low)
+    } else {
+        // Shorts are liquidatable at and above the answer: find the lowest such price
+        if !is_liquidatable(position, market, high)? {
+            return Ok(u64::MAX);
+        }
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if is_liquidatable(position, market, mid)? {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Ok(high)
+    }
+}
+
+// Helper function to calculate the equity recoverable from a position if it were closed now
+fn compute_equity(position: &Position, pnl: i64, close_fee: u64) -> i64 {
+    position.collateral as i64 + pnl - close_fee as i64
+}
+
+// Helper function to calculate what a close pays back to the trader: the equity after the close fee, never below zero
+fn calculate_return_amount(position: &Position, pnl: i64, close_fee: u64) -> u64 {
+    compute_equity(position, pnl, close_fee).max(0) as u64
+}
+
+// Helper function to calculate PnL, denominated in the collateral token
+fn calculate_pnl(position: &Position, market: &Market, current_price: u64) -> Result<(i64, u64)> {
+    // Positions keep the price scale they were opened at, even across an oracle exponent change
+    let current_price = rescale_price(current_price, market.oracle_exponent, position.price_exponent)?;
+    let price_delta = if position.is_long {
+        current_price as i64 - position.entry_price as i64
+    } else {
+        position.entry_price as i64 - current_price as i64
+    };
+    
+    let raw_pnl = match market.contract_type {
+        ContractType::Linear => {
+            require!(position.entry_price > 0, AsterDexError::InvalidPrice);
+            let pnl = div_floor(price_delta as i128 * position.size as i128, position.entry_price as i128);
+            i64::try_from(pnl).map_err(|_| error!(AsterDexError::MathOverflow))?
+        }
+        // Inverse PnL is size * entry * (1/entry - 1/exit), i.e. size * delta / exit in the base asset.
+        // A zero or near-zero exit price is rejected rather than allowed to blow up the division.
+        ContractType::Inverse => {
+            require!(current_price > 0, AsterDexError::InvalidOracle);
+            let pnl = div_floor(price_delta as i128 * position.size as i128, current_price as i128);
+            i64::try_from(pnl).map_err(|_| error!(AsterDexError::MathOverflow))?
+        }
+    };
+    
+    // Calculate trading fee on the position size
+    let (close_fee_bps, _, _) = position_terms(position, market);
+    let fee = mul_div_ceil(position.size, close_fee_bps, BPS_SCALE)?;
+    
+    Ok((raw_pnl, fee))
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub super_admin: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = super_admin,
+        space = 8 + ProgramConfig::LEN,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    // Only the program's upgrade authority can create the config and pick the trusted Pyth program
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ AsterDexError::Unauthorized)]
+    pub program: Program<'info, crate::program::AsterDex>,
+
+    #[account(constraint = program_data.upgrade_authority_address == Some(super_admin.key()) @ AsterDexError::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub super_admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.super_admin == super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+}
+
+#[derive(Accounts)]
+pub struct InitRewardTreasury<'info> {
+    #[account(mut)]
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.super_admin == super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub reward_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = super_admin,
+        token::mint = reward_mint,
+        token::authority = reward_treasury,
+        seeds = [b"reward_treasury"],
+        bump
+    )]
+    pub reward_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct GetVersion<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SetGlobalPause<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = authority.key() == config.guardian || authority.key() == config.super_admin @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SetMarketFrozen<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = authority.key() == config.guardian || authority.key() == config.super_admin @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct PauseAllMarkets<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = authority.key() == config.guardian || authority.key() == config.super_admin @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(seeds = [b"market_page", &market_page.page_index.to_le_bytes()], bump = market_page.bump)]
+    pub market_page: Account<'info, MarketPage>,
+}
+
+#[derive(Accounts)]
+pub struct ResumeAllMarkets<'info> {
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = super_admin.key() == config.super_admin @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(seeds = [b"market_page", &market_page.page_index.to_le_bytes()], bump = market_page.bump)]
+    pub market_page: Account<'info, MarketPage>,
+}
+
+#[derive(Accounts)]
+pub struct SetRecoveryAccount<'info> {
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = super_admin.key() == config.super_admin @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut, seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencySweep<'info> {
+    pub super_admin: Signer<'info>,
+
+    pub guardian: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = super_admin.key() == config.super_admin @ AsterDexError::Unauthorized,
+        constraint = config.guardian != Pubkey::default() @ AsterDexError::Unauthorized,
+        constraint = guardian.key() == config.guardian @ AsterDexError::Unauthorized,
+        constraint = guardian.key() != super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut, seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = market.recovery_account != Pubkey::default() @ AsterDexError::InvalidRecoveryAccount,
+        constraint = recovery_account.key() == market.recovery_account @ AsterDexError::InvalidRecoveryAccount,
+        constraint = recovery_account.mint == vault.mint @ AsterDexError::InvalidMint
+    )]
+    pub recovery_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SettleSweptPosition<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        close = trader,
+        constraint = position.trader == trader.key() @ AsterDexError::Unauthorized
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(mut, seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_id: [u8; 32])]
+pub struct InitializeMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.market_creators.contains(&admin.key()) @ AsterDexError::MarketCreatorNotAllowed
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Market::LEN,
+        seeds = [b"market", &market_id],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_page", &((config.listed_markets / MARKET_PAGE_CAPACITY as u64) as u32).to_le_bytes()],
+        bump = market_page.bump
+    )]
+    pub market_page: Account<'info, MarketPage>,
+    
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = price_feed.owner == &config.pyth_program_id @ AsterDexError::InvalidOracleOwner)]
+    pub price_feed: AccountInfo<'info>,
+
+    pub collateral_mint: Account<'info, Mint>,
+
+    // Holds the market's collateral and signs for its own transfers
+    #[account(
+        init,
+        payer = admin,
+        token::mint = collateral_mint,
+        token::authority = vault,
+        seeds = [b"vault", market.key().as_ref()],
+        bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(page_index: u32)]
+pub struct InitMarketPage<'info> {
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.market_creators.contains(&creator.key()) @ AsterDexError::MarketCreatorNotAllowed
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + MarketPage::LEN,
+        seeds = [b"market_page", &page_index.to_le_bytes()],
+        bump
+    )]
+    pub market_page: Account<'info, MarketPage>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderAccount<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + TraderAccount::LEN,
+        seeds = [b"trader", user.key().as_ref()],
+        bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimPending<'info> {
+    pub trader: Signer<'info>,
+
+    #[account(mut, seeds = [b"trader", trader.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(mut, seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = trader_token_account.owner == trader.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = trader_token_account.mint == vault.mint @ AsterDexError::InvalidMint
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterKeeper<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = keeper,
+        space = 8 + KeeperAccount::LEN,
+        seeds = [b"keeper", market.key().as_ref(), keeper.key().as_ref()],
+        bump
+    )]
+    pub keeper_account: Account<'info, KeeperAccount>,
+
+    #[account(
+        init,
+        payer = keeper,
+        token::mint = collateral_mint,
+        token::authority = keeper_account,
+        seeds = [b"keeper_bond", keeper_account.key().as_ref()],
+        bump
+    )]
+    pub keeper_bond: Account<'info, TokenAccount>,
+
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.owner == keeper.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = keeper_token_account.mint == collateral_mint.key() @ AsterDexError::InvalidMint
+    )]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UnregisterKeeper<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        close = keeper,
+        seeds = [b"keeper", keeper_account.market.as_ref(), keeper.key().as_ref()],
+        bump = keeper_account.bump
+    )]
+    pub keeper_account: Account<'info, KeeperAccount>,
+
+    #[account(mut, seeds = [b"keeper_bond", keeper_account.key().as_ref()], bump)]
+    pub keeper_bond: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == keeper_bond.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.owner == keeper.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = keeper_token_account.mint == keeper_bond.mint @ AsterDexError::InvalidMint
+    )]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetWithdrawalDelay<'info> {
+    pub trader: Signer<'info>,
+
+    #[account(mut, seeds = [b"trader", trader.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetEscrowGuardian<'info> {
+    pub trader: Signer<'info>,
+
+    #[account(mut, seeds = [b"trader", trader.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    pub current_guardian: Option<Signer<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct InitWithdrawalEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = trader,
+        space = 8 + WithdrawalEscrow::LEN,
+        seeds = [b"escrow", trader.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub withdrawal_escrow: Account<'info, WithdrawalEscrow>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimEscrow<'info> {
+    pub trader: Signer<'info>,
+
+    #[account(mut, seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", trader.key().as_ref(), market.key().as_ref()],
+        bump = withdrawal_escrow.bump
+    )]
+    pub withdrawal_escrow: Account<'info, WithdrawalEscrow>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = trader_token_account.owner == trader.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = trader_token_account.mint == vault.mint @ AsterDexError::InvalidMint
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelEscrow<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"trader", withdrawal_escrow.trader.as_ref()],
+        bump = trader_account.bump,
+        constraint = authority.key() == trader_account.owner
+            || (trader_account.escrow_guardian != Pubkey::default() && authority.key() == trader_account.escrow_guardian)
+            @ AsterDexError::Unauthorized
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(mut, seeds = [b"market", &market.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", withdrawal_escrow.trader.as_ref(), market.key().as_ref()],
+        bump = withdrawal_escrow.bump
+    )]
+    pub withdrawal_escrow: Account<'info, WithdrawalEscrow>,
+
+    #[account(
+        mut,
+        constraint = position.trader == withdrawal_escrow.trader @ AsterDexError::InvalidPosition,
+        constraint = position.size > 0 @ AsterDexError::InvalidPosition,
+        constraint = position.market_id == market.market_id @ AsterDexError::InvalidPosition
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+pub struct CloseWithdrawalEscrow<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(
+        mut,
+        close = trader,
+        constraint = withdrawal_escrow.trader == trader.key() @ AsterDexError::Unauthorized
+    )]
+    pub withdrawal_escrow: Account<'info, WithdrawalEscrow>,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderHistory<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + TraderHistory::LEN,
+        seeds = [b"history", user.key().as_ref()],
+        bump
+    )]
+    pub trader_history: Account<'info, TraderHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseTraderHistory<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        close = user,
+        constraint = trader_history.owner == user.key() @ AsterDexError::Unauthorized
+    )]
+    pub trader_history: Account<'info, TraderHistory>,
+}
+
+#[derive(Accounts)]
+pub struct AttestPosition<'info> {
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub position: Account<'info, Position>,
+
+    #[account(seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + Attestation::LEN,
+        seeds = [b"attestation", position.key().as_ref(), creator.key().as_ref()],
+        bump
+    )]
+    pub attestation: Account<'info, Attestation>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseAttestation<'info> {
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    #[account(
+        mut,
+        close = creator,
+        constraint = attestation.creator == creator.key() @ AsterDexError::Unauthorized
+    )]
+    pub attestation: Account<'info, Attestation>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+    
+    #[account(
+        mut,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: Instructions sysvar, required when strict admin mode is on
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: Option<UncheckedAccount<'info>>,
+
+    // Required once the market keeps a parameter history
+    #[account(mut, seeds = [b"param_history", market.key().as_ref()], bump = param_history.bump)]
+    pub param_history: Option<Account<'info, ParamHistory>>,
+}
+
+#[derive(Accounts)]
+pub struct InitParamHistory<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + ParamHistory::LEN,
+        seeds = [b"param_history", market.key().as_ref()],
+        bump
+    )]
+    pub param_history: Account<'info, ParamHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcknowledgeExponentChange<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ArchiveMarket<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct SweepArchivedMarket<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        seeds = [b"market", &market.market_id],
+        bump = market.bump,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = market.fee_treasury != Pubkey::default() @ AsterDexError::InvalidFeeTreasury,
+        constraint = fee_treasury.key() == market.fee_treasury @ AsterDexError::InvalidFeeTreasury
+    )]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    // Lifts the daily withdrawal limits for this sweep
+    #[account(
+        constraint = config.guardian != Pubkey::default() @ AsterDexError::Unauthorized,
+        constraint = guardian.key() == config.guardian @ AsterDexError::Unauthorized
+    )]
+    pub guardian: Option<Signer<'info>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ArchiveMarketFinalize<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        close = admin,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"market_stats", market.key().as_ref()],
+        bump = market_stats.bump
+    )]
+    pub market_stats: Option<Account<'info, MarketStats>>,
+
+    #[account(mut, seeds = [b"market_page", &market_page.page_index.to_le_bytes()], bump = market_page.bump)]
+    pub market_page: Option<Account<'info, MarketPage>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RecoverOrphanedPosition<'info> {
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.super_admin == super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    /// CHECK: Position owner, receives the collateral and the position rent
+    pub trader: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        close = trader,
+        constraint = position.trader == trader.key() @ AsterDexError::InvalidPosition
+    )]
+    pub position: Account<'info, Position>,
+
+    /// CHECK: Expected to be missing or undeserializable; checked in the handler
+    #[account(seeds = [b"market", &position.market_id], bump)]
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = trader_token_account.owner == trader.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = trader_token_account.mint == position.collateral_mint @ AsterDexError::InvalidMint
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == position.collateral_mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetLiquidityReference<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = authority.key() == market.admin
+            || (market.liquidity_updater != Pubkey::default() && authority.key() == market.liquidity_updater)
+            @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeTreasury<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        constraint = fee_treasury.mint == vault.mint @ AsterDexError::InvalidMint,
+        constraint = fee_treasury.key() != vault.key() @ AsterDexError::InvalidFeeTreasury
+    )]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    /// CHECK: Instructions sysvar, required when strict admin mode is on
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct SweepFees<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = market.fee_treasury != Pubkey::default() @ AsterDexError::InvalidFeeTreasury,
+        constraint = fee_treasury.key() == market.fee_treasury @ AsterDexError::InvalidFeeTreasury
+    )]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    // Lifts the daily withdrawal limits for this sweep
+    #[account(
+        constraint = config.guardian != Pubkey::default() @ AsterDexError::Unauthorized,
+        constraint = guardian.key() == config.guardian @ AsterDexError::Unauthorized
+    )]
+    pub guardian: Option<Signer<'info>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeFees<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = collateral_mint.key() == vault.mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    // Checked against config by validate_distribution_destination
+    #[account(mut)]
+    pub distribution_destination: Account<'info, TokenAccount>,
+
+    // Lifts the daily withdrawal limits for this distribution
+    #[account(
+        constraint = config.guardian != Pubkey::default() @ AsterDexError::Unauthorized,
+        constraint = guardian.key() == config.guardian @ AsterDexError::Unauthorized
+    )]
+    pub guardian: Option<Signer<'info>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_id: [u8; 32])]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        seeds = [b"market", &market_id],
+        bump = market.bump,
+        constraint = market.status == MarketStatus::Active @ AsterDexError::MarketInactive
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"trader", user.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Position::LEN,
+        seeds = [b"position", user.key().as_ref(), &market_id, &trader_account.position_nonce.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = user_token_account.mint == collateral_mint.key() @ AsterDexError::InvalidMint
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub collateral_mint: Account<'info, Mint>,
+    
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    
+    #[account(
+        mut,
+        constraint = trader_history.owner == user.key() @ AsterDexError::Unauthorized
+    )]
+    pub trader_history: Option<Account<'info, TraderHistory>>,
+
+    /// CHECK: Program PDA the user approved as delegate on their token account; pulls the collateral when given
+    #[account(seeds = [b"delegate"], bump)]
+    pub collateral_delegate: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Instructions sysvar, needed for allowlisted composer programs to be recognized
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_id: [u8; 32])]
+pub struct OpenIntoSlot<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        seeds = [b"market", &market_id],
+        bump = market.bump,
+        constraint = market.status == MarketStatus::Active @ AsterDexError::MarketInactive
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"trader", user.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"position", user.key().as_ref(), &market_id, &position.nonce.to_le_bytes()],
+        bump,
+        constraint = position.trader == user.key() @ AsterDexError::Unauthorized,
+        constraint = position.size == 0 && position.collateral == 0 @ AsterDexError::PositionSlotNotEmpty
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = user_token_account.mint == collateral_mint.key() @ AsterDexError::InvalidMint
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub collateral_mint: Account<'info, Mint>,
+    
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    
+    #[account(
+        mut,
+        constraint = trader_history.owner == user.key() @ AsterDexError::Unauthorized
+    )]
+    pub trader_history: Option<Account<'info, TraderHistory>>,
+
+    /// CHECK: Program PDA the user approved as delegate on their token account; pulls the collateral when given
+    #[account(seeds = [b"delegate"], bump)]
+    pub collateral_delegate: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Instructions sysvar, needed for allowlisted composer programs to be recognized
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_id: [u8; 32])]
+pub struct CreatePositionSlot<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"market", &market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"trader", user.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Position::LEN,
+        seeds = [b"position", user.key().as_ref(), &market_id, &trader_account.position_nonce.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseEmptySlot<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        close = user,
+        constraint = position.trader == user.key() @ AsterDexError::Unauthorized,
+        constraint = position.size == 0 && position.collateral == 0 @ AsterDexError::PositionSlotNotEmpty
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        close = user,
+        constraint = position.trader == user.key() @ AsterDexError::Unauthorized
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(mut, seeds = [b"trader", user.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"market", &position.market_id],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = user_token_account.mint == position.collateral_mint @ AsterDexError::InvalidMint
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    #[account(constraint = collateral_mint.key() == position.collateral_mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+    
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: Instructions sysvar, used to reject re-entrant calls
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        constraint = trader_history.owner == user.key() @ AsterDexError::Unauthorized
+    )]
+    pub trader_history: Option<Account<'info, TraderHistory>>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", user.key().as_ref(), market.key().as_ref()],
+        bump = withdrawal_escrow.bump
+    )]
+    pub withdrawal_escrow: Option<Account<'info, WithdrawalEscrow>>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePositionAndSwap<'info> {
+    pub close: ClosePosition<'info>,
+
+    #[account(
+        mut,
+        constraint = destination_token_account.owner == close.user.key() @ AsterDexError::InvalidTokenAccount
+    )]
+    pub destination_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Checked against the ProgramConfig swap whitelist in the handler
+    #[account(executable)]
+    pub swap_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidatePosition<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+    
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    /// CHECK: Position owner, doesn't need to sign for liquidation
+    pub trader: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        close = liquidator,
+        constraint = position.trader == trader.key() @ AsterDexError::InvalidPosition
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(mut, seeds = [b"trader", trader.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"market", &position.market_id],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        mut,
+        constraint = liquidator_token_account.owner == liquidator.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = liquidator_token_account.mint == position.collateral_mint @ AsterDexError::InvalidMint
+    )]
+    pub liquidator_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Trader's collateral token account for the remainder; may be closed or invalid, in which
+    /// case the remainder is held as a pending claim instead
+    #[account(mut)]
+    pub trader_token_account: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    #[account(constraint = collateral_mint.key() == position.collateral_mint @ AsterDexError::InvalidMint)]
+    pub collateral_mint: Account<'info, Mint>,
+    
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: Instructions sysvar, used to reject re-entrant calls
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        constraint = trader_history.owner == trader.key() @ AsterDexError::Unauthorized
+    )]
+    pub trader_history: Option<Account<'info, TraderHistory>>,
+
+    #[account(
+        mut,
+        seeds = [b"keeper", market.key().as_ref(), liquidator.key().as_ref()],
+        bump = keeper_account.bump
+    )]
+    pub keeper_account: Option<Account<'info, KeeperAccount>>,
+
+    // Passing all three asks for the reward in the config's reward mint
+    #[account(mut, seeds = [b"reward_treasury"], bump = config.reward_treasury_bump)]
+    pub reward_treasury: Option<Account<'info, TokenAccount>>,
+
+    #[account(constraint = reward_mint.key() == config.reward_mint @ AsterDexError::InvalidMint)]
+    pub reward_mint: Option<Account<'info, Mint>>,
+
+    #[account(
+        mut,
+        constraint = liquidator_reward_account.owner == liquidator.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = liquidator_reward_account.mint == config.reward_mint @ AsterDexError::InvalidMint
+    )]
+    pub liquidator_reward_account: Option<Account<'info, TokenAccount>>,
+}
 
 #[derive(Accounts)]
-pub struct UpdateMarket<'info> {
+pub struct FlagLiquidatable<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    // Empty slots read as liquidatable (no collateral) but can never be cleared, so only open positions are flagged
+    #[account(mut, constraint = position.size > 0 @ AsterDexError::InvalidPosition)]
+    pub position: Account<'info, Position>,
+
+    #[account(mut, seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFunding<'info> {
     #[account(mut)]
     pub admin: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
     
+    #[account(
+        mut,
+        seeds = [b"market", &market.market_id],
+        bump = market.bump,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct CrankFunding<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        mut,
+        seeds = [b"market", &market.market_id],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_stats", market.key().as_ref()],
+        bump = market_stats.bump
+    )]
+    pub market_stats: Option<Account<'info, MarketStats>>,
+
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: Option<AccountInfo<'info>>,
+
+    #[account(
+        mut,
+        seeds = [b"keeper", market.key().as_ref(), keeper.key().as_ref()],
+        bump = keeper_account.bump
+    )]
+    pub keeper_account: Option<Account<'info, KeeperAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct InitMarketStats<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + MarketStats::LEN,
+        seeds = [b"market_stats", market.key().as_ref()],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarketStats<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_stats", market.key().as_ref()],
+        bump = market_stats.bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarketStats<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: MarketStats PDA under an older, shorter layout, which cannot be deserialized as the current one
+    #[account(mut, seeds = [b"market_stats", market.key().as_ref()], bump, owner = crate::ID)]
+    pub market_stats: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetNormalizedPrice<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetFundingRate<'info> {
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct GetMarketUnrealizedPnl<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(epoch: u64)]
+pub struct FundRebateEpoch<'info> {
+    #[account(mut)]
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.super_admin == super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(
+        init,
+        payer = super_admin,
+        space = 8 + RebateEpoch::LEN,
+        seeds = [b"rebate", epoch.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub rebate_epoch: Account<'info, RebateEpoch>,
+
+    #[account(
+        init,
+        payer = super_admin,
+        token::mint = rebate_mint,
+        token::authority = rebate_epoch,
+        seeds = [b"rebate_vault", rebate_epoch.key().as_ref()],
+        bump
+    )]
+    pub rebate_vault: Account<'info, TokenAccount>,
+
+    pub rebate_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = funder_token_account.owner == super_admin.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = funder_token_account.mint == rebate_mint.key() @ AsterDexError::InvalidMint
+    )]
+    pub funder_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRebate<'info> {
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    #[account(seeds = [b"trader", trader.key().as_ref()], bump = trader_account.bump)]
+    pub trader_account: Account<'info, TraderAccount>,
+
+    #[account(mut, seeds = [b"rebate", rebate_epoch.epoch.to_le_bytes().as_ref()], bump = rebate_epoch.bump)]
+    pub rebate_epoch: Account<'info, RebateEpoch>,
+
+    // Its existence marks the trader as paid for this epoch
+    #[account(
+        init,
+        payer = trader,
+        space = 8 + RebateClaim::LEN,
+        seeds = [b"rebate_claim", rebate_epoch.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub rebate_claim: Account<'info, RebateClaim>,
+
+    #[account(mut, seeds = [b"rebate_vault", rebate_epoch.key().as_ref()], bump)]
+    pub rebate_vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = rebate_mint.key() == rebate_epoch.mint @ AsterDexError::InvalidMint)]
+    pub rebate_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = trader_token_account.owner == trader.key() @ AsterDexError::InvalidTokenAccount,
+        constraint = trader_token_account.mint == rebate_epoch.mint @ AsterDexError::InvalidMint
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SweepRebates<'info> {
+    pub super_admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.super_admin == super_admin.key() @ AsterDexError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut, seeds = [b"rebate", rebate_epoch.epoch.to_le_bytes().as_ref()], bump = rebate_epoch.bump)]
+    pub rebate_epoch: Account<'info, RebateEpoch>,
+
+    #[account(mut, seeds = [b"rebate_vault", rebate_epoch.key().as_ref()], bump)]
+    pub rebate_vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = rebate_mint.key() == rebate_epoch.mint @ AsterDexError::InvalidMint)]
+    pub rebate_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = treasury_token_account.mint == rebate_epoch.mint @ AsterDexError::InvalidMint)]
+    pub treasury_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct GetEpochStats<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub trader_account: Account<'info, TraderAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ProjectHoldingCost<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub position: Account<'info, Position>,
+
+    #[account(seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetPositionBreakdown<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub position: Account<'info, Position>,
+
+    #[account(seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteOpenPosition<'info> {
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: This is the Pyth price feed account
+    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFunding<'info> {
+    #[account(mut)]
+    pub position: Account<'info, Position>,
+
+    #[account(mut, seeds = [b"market", &position.market_id], bump = market.bump)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct SweepFundingDust<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct RecordShortfall<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market", &market.market_id],
+        bump = market.bump,
+        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized,
+        constraint = !market.params_immutable @ AsterDexError::MarketImmutable
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyVaultAccounting<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct FundCrankBounty<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
     #[account(
         mut,
         constraint = market.admin == admin.key() @ AsterDexError::Unauthorized
     )]
     pub market: Account<'info, Market>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+#[derive(Default)]
+pub struct ProgramConfig {
+    pub super_admin: Pubkey,
+    pub pyth_program_id: Pubkey,
+    pub total_open_positions: u32,
+    pub max_total_open_positions: u32,
+    pub guardian: Pubkey,
+    pub paused: bool,
+    pub swap_programs: [Pubkey; MAX_SWAP_PROGRAMS],
+    pub market_creators: [Pubkey; MAX_MARKET_CREATORS],
+    pub epoch_length: i64,
+    pub current_epoch: u64,
+    pub current_epoch_start: i64,
+    pub last_pause_time: i64,
+    pub last_unpause_time: i64,
+    // Seconds spent under global pause, summed over completed pauses (an ongoing one runs from last_pause_time)
+    pub cumulative_paused_secs: i64,
+    pub enabled_features: u64,
+    pub epoch_total_volume: u64,
+    pub previous_epoch_total_volume: u64,
+    pub strict_admin_mode: bool,
+    pub max_opens_per_slot: u16,
+    pub min_position_size: u64,
+    pub distribution_destination: Pubkey,
+    pub distribution_bps: u16,
+    pub distribution_interval: i64,
+    pub listed_markets: u64,
+    pub composer_programs: [Pubkey; MAX_COMPOSER_PROGRAMS],
+    // Liquidation rewards paid in reward_mint from the reward treasury, valued at the oracle less a discount
+    pub reward_mint: Pubkey,
+    pub reward_treasury_bump: u8,
+    pub convert_liquidation_rewards: bool,
+    pub liquidation_reward_discount_bps: u16,
+    // Closes within this many seconds of the open earn no volume credit toward rebates (0 = off)
+    pub min_volume_accrual_secs: i64,
+    pub bump: u8,
+}
+
+impl ProgramConfig {
+    pub const LEN: usize = 32 // super_admin
+        + 32 // pyth_program_id
+        + 4 // total_open_positions
+        + 4 // max_total_open_positions
+        + 32 // guardian
+        + 1 // paused
+        + 32 * MAX_SWAP_PROGRAMS // swap_programs
+        + 32 * MAX_MARKET_CREATORS // market_creators
+        + 8 // epoch_length
+        + 8 // current_epoch
+        + 8 // current_epoch_start
+        + 8 // last_pause_time
+        + 8 // last_unpause_time
+        + 8 // cumulative_paused_secs
+        + 8 // enabled_features
+        + 8 // epoch_total_volume
+        + 8 // previous_epoch_total_volume
+        + 1 // strict_admin_mode
+        + 2 // max_opens_per_slot
+        + 8 // min_position_size
+        + 32 // distribution_destination
+        + 2 // distribution_bps
+        + 8 // distribution_interval
+        + 8 // listed_markets
+        + 32 * MAX_COMPOSER_PROGRAMS // composer_programs
+        + 32 // reward_mint
+        + 1 // reward_treasury_bump
+        + 1 // convert_liquidation_rewards
+        + 2 // liquidation_reward_discount_bps
+        + 8 // min_volume_accrual_secs
+        + 1; // bump
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ProgramVersion {
+    pub version: String,
+    pub git_hash: String,
+    pub supported_features: u64,
+    pub enabled_features: u64,
+}
+
+#[account]
+#[derive(Default)]
+pub struct Market {
+    pub admin: Pubkey,
+    pub oracle: Pubkey,
+    pub oracle_feed_id: [u8; 32],
+    pub market_id: [u8; 32],
+    pub min_collateral: u64,
+    pub max_leverage: u16,
+    pub liquidation_threshold: u16,
+    pub status: MarketStatus,
+    pub last_funding_index: i64,
+    pub last_funding_time: i64,
+    pub open_interest_long: u64,
+    pub open_interest_short: u64,
+    pub funding_rate_bps: u16,
+    pub funding_interval: i64,
+    pub crank_reward: u64,
+    pub total_crank_payouts: u64,
+    pub open_position_count: u32,
+    pub max_open_positions: u32,
+    pub opens_frozen: bool,
+    pub max_funding_rate_bps_per_interval: u16,
+    pub aggregate_size_long: u128,
+    pub aggregate_size_short: u128,
+    pub aggregate_entry_notional_long: u64,
+    pub aggregate_entry_notional_short: u64,
+    pub total_reserved_collateral: u64,
+    pub accumulated_fees: u64,
+    pub realized_pnl_paid: i64,
+    pub contract_type: ContractType,
+    pub post_unpause_grace_secs: i64,
+    pub funding_pool_balance: i64,
+    pub funding_weight_long: i128,
+    pub funding_weight_short: i128,
+    pub insurance_fund_balance: u64,
+    pub last_funding_sweep_epoch: u64,
+    pub volume_window_start: i64,
+    pub volume_current_window: u64,
+    pub volume_previous_window: u64,
+    pub risk_weight_bps: u16,
+    pub fee_treasury: Pubkey,
+    pub total_fees_swept: u64,
+    pub min_price: u64,
+    pub pending_trader_claims: u64,
+    pub oracle_exponent: i32,
+    pub liquidation_conf_multiplier: u16,
+    pub mark_price_ema: u64,
+    pub mark_price_ema_time: i64,
+    pub mark_ema_half_life_secs: i64,
+    pub max_mark_divergence_bps: u16,
+    pub divergent_publish_time: i64,
+    pub utilization_pause_threshold_bps: u16,
+    pub min_publishers: u16,
+    pub require_trading_status: bool,
+    pub last_good_price: u64,
+    pub last_fee_distribution: i64,
+    pub total_fees_distributed: u64,
+    pub liquidator_share_bps: u16,
+    pub insurance_share_bps: u16,
+    pub treasury_share_bps: u16,
+    // Set once by finalize_market_params; the admin can no longer change the market's parameters
+    pub params_immutable: bool,
+    // Minimum collateral value in USD at NORMALIZED_PRICE_EXPONENT, applied according to min_collateral_mode
+    pub min_collateral_usd: u64,
+    pub min_collateral_mode: MinCollateralMode,
+    // Largest discount (or premium) of the mark price to the index, reached when all open interest is on one side
+    pub skew_premium_bps: u16,
+    // Mark price at the oracle exponent as of the last trade or crank; see calculate_mark_price
+    pub mark_price: u64,
+    // Collateral mint may withhold a transfer fee. Deposits are credited with what the vault actually received;
+    // payouts leave the vault in full and arrive net of the mint's fee.
+    pub supports_transfer_fee: bool,
+    // ProgramConfig paused seconds as of the last funding update; funding intervals exclude paused time after it
+    pub funding_paused_secs_checkpoint: i64,
+    // Extra equity, in bps of collateral above the threshold, a flagged position needs before the flag can be cleared
+    pub liquidation_hysteresis_bps: u16,
+    // Break-glass destination for emergency_sweep; usable RECOVERY_ACCOUNT_TIMELOCK after recovery_account_set_at
+    pub recovery_account: Pubkey,
+    pub recovery_account_set_at: i64,
+    // Set once the vault has been swept; positions then settle into claims against the recovery account
+    pub emergency_swept: bool,
+    pub swept_at: i64,
+    pub swept_amount: u64,
+    pub sweep_price: u64,
+    pub total_recovery_claims: u64,
+    // Bond a keeper locks to register for this market (0 = registration closed)
+    pub keeper_bond_amount: u64,
+    // Share of keeper payouts withheld from unregistered keepers (0 = paid in full)
+    pub unregistered_keeper_discount_bps: u16,
+    // Admin- or updater-maintained liquidity figure in position size units; a single position may be at most
+    // max_position_liquidity_multiple_bps of it (0 = uncapped)
+    pub liquidity_reference: u64,
+    pub liquidity_reference_updated_at: i64,
+    pub max_position_liquidity_multiple_bps: u32,
+    pub liquidity_updater: Pubkey,
+    // Largest move of liquidity_reference allowed per update (0 = unlimited)
+    pub max_liquidity_reference_change_bps: u16,
+    // Split of realized_pnl_paid since these counters were added: profit paid out of the vault and losses kept by it
+    pub total_profit_paid: u64,
+    pub total_loss_absorbed: u64,
+    // Trader-side vault deficit left after the insurance fund and fees, recovered from winners' profit at haircut_bps
+    pub socialized_shortfall: u64,
+    pub haircut_bps: u16,
+    pub total_shortfall_recorded: u64,
+    pub total_haircuts_collected: u64,
+    // Open fee against the skew (0 = disabled): premiums from skew-widening opens fund rebates on skew-narrowing ones
+    pub open_skew_premium_bps: u16,
+    pub open_skew_rebate_bps: u16,
+    pub skew_premium_pool: u64,
+    pub total_skew_premiums_collected: u64,
+    pub total_skew_rebates_paid: u64,
+    // Flat reward paid from the insurance fund for liquidating a position with no collateral left (0 = none)
+    pub zero_collateral_liquidation_reward: u64,
+    // Treasury withdrawal limits per WITHDRAWAL_WINDOW_SECS (0 = unlimited); the guardian can co-sign past them
+    pub daily_withdrawal_limit: u64,
+    pub daily_withdrawal_count_limit: u16,
+    pub withdrawal_window_start: i64,
+    pub withdrawn_in_window: u64,
+    pub withdrawals_in_window: u16,
+    // Set by pause_all_markets during an incident; lifted by resume_all_markets
+    pub liquidations_frozen: bool,
+    // In-kind liquidator rewards moved to insurance when paid in the reward mint, and what the treasury paid
+    pub total_converted_penalties: u64,
+    pub total_converted_rewards_paid: u64,
+    // Set once a ParamHistory exists; update_market must then write to it
+    pub param_history_enabled: bool,
+    // Higher liquidation thresholds for larger positions, in increasing size order
+    pub threshold_brackets: [ThresholdBracket; MAX_THRESHOLD_BRACKETS],
+    // When set, liquidations later than max_liquidation_delay_secs after the flag leave the trader the breach-time
+    // remainder, paid for out of the liquidator's reward and then the insurance fund
+    pub liquidation_delay_protection: bool,
+    pub max_liquidation_delay_secs: i64,
+    pub vault_bump: u8,
+    pub bump: u8,
+}
+
+impl Market {
+    pub const LEN: usize = 32 // admin
+        + 32 // oracle
+        + 32 // oracle_feed_id
+        + 32 // market_id
+        + 8 // min_collateral
+        + 2 // max_leverage
+        + 2 // liquidation_threshold
+        + 1 // status
+        + 8 // last_funding_index
+        + 8 // last_funding_time
+        + 8 // open_interest_long
+        + 8 // open_interest_short
+        + 2 // funding_rate_bps
+        + 8 // funding_interval
+        + 8 // crank_reward
+        + 8 // total_crank_payouts
+        + 4 // open_position_count
+        + 4 // max_open_positions
+        + 1 // opens_frozen
+        + 2 // max_funding_rate_bps_per_interval
+        + 16 // aggregate_size_long
+        + 16 // aggregate_size_short
+        + 8 // aggregate_entry_notional_long
+        + 8 // aggregate_entry_notional_short
+        + 8 // total_reserved_collateral
+        + 8 // accumulated_fees
+        + 8 // realized_pnl_paid
+        + 1 // contract_type
+        + 8 // post_unpause_grace_secs
+        + 8 // funding_pool_balance
+        + 16 // funding_weight_long
+        + 16 // funding_weight_short
+        + 8 // insurance_fund_balance
+        + 8 // last_funding_sweep_epoch
+        + 8 // volume_window_start
+        + 8 // volume_current_window
+        + 8 // volume_previous_window
+        + 2 // risk_weight_bps
+        + 32 // fee_treasury
+        + 8 // total_fees_swept
+        + 8 // min_price
+        + 8 // pending_trader_claims
+        + 4 // oracle_exponent
+        + 2 // liquidation_conf_multiplier
+        + 8 // mark_price_ema
+        + 8 // mark_price_ema_time
+        + 8 // mark_ema_half_life_secs
+        + 2 // max_mark_divergence_bps
+        + 8 // divergent_publish_time
+        + 2 // utilization_pause_threshold_bps
+        + 2 // min_publishers
+        + 1 // require_trading_status
+        + 8 // last_good_price
+        + 8 // last_fee_distribution
+        + 8 // total_fees_distributed
+        + 2 // liquidator_share_bps
+        + 2 // insurance_share_bps
+        + 2 // treasury_share_bps
+        + 1 // params_immutable
+        + 8 // min_collateral_usd
+        + 1 // min_collateral_mode
+        + 2 // skew_premium_bps
+        + 8 // mark_price
+        + 1 // supports_transfer_fee
+        + 8 // funding_paused_secs_checkpoint
+        + 2 // liquidation_hysteresis_bps
+        + 32 // recovery_account
+        + 8 // recovery_account_set_at
+        + 1 // emergency_swept
+        + 8 // swept_at
+        + 8 // swept_amount
+        + 8 // sweep_price
+        + 8 // total_recovery_claims
+        + 8 // keeper_bond_amount
+        + 2 // unregistered_keeper_discount_bps
+        + 8 // liquidity_reference
+        + 8 // liquidity_reference_updated_at
+        + 4 // max_position_liquidity_multiple_bps
+        + 32 // liquidity_updater
+        + 2 // max_liquidity_reference_change_bps
+        + 8 // total_profit_paid
+        + 8 // total_loss_absorbed
+        + 8 // socialized_shortfall
+        + 2 // haircut_bps
+        + 8 // total_shortfall_recorded
+        + 8 // total_haircuts_collected
+        + 2 // open_skew_premium_bps
+        + 2 // open_skew_rebate_bps
+        + 8 // skew_premium_pool
+        + 8 // total_skew_premiums_collected
+        + 8 // total_skew_rebates_paid
+        + 8 // zero_collateral_liquidation_reward
+        + 8 // daily_withdrawal_limit
+        + 2 // daily_withdrawal_count_limit
+        + 8 // withdrawal_window_start
+        + 8 // withdrawn_in_window
+        + 2 // withdrawals_in_window
+        + 1 // liquidations_frozen
+        + 8 // total_converted_penalties
+        + 8 // total_converted_rewards_paid
+        + 1 // param_history_enabled
+        + ThresholdBracket::LEN * MAX_THRESHOLD_BRACKETS // threshold_brackets
+        + 1 // liquidation_delay_protection
+        + 8 // max_liquidation_delay_secs
+        + 1 // vault_bump
+        + 1; // bump
+}
+
+/// Public, versioned market summary for other protocols to read without depending on the Market layout.
+/// Fields are only ever appended. Byte offsets after the 8-byte discriminator, for versions 1 and 2:
+/// version 8, market 9, mark_price 41, index_price 49, open_interest_long 57, open_interest_short 65,
+/// funding_rate_bps 73, volume_24h 81, last_update 89, bump 97. Version 3 appends price_expo 98,
+/// normalized_mark_price 102, normalized_index_price 110, price_format 118. Version 4 appends
+/// params_immutable 119. Older accounts are grown with migrate_market_stats.
+#[account]
+#[derive(Default)]
+pub struct MarketStats {
+    pub version: u8,
+    pub market: Pubkey,
+    // Index price adjusted against the open interest skew; used for unrealized PnL and liquidation health
+    pub mark_price: u64,
+    // Oracle price; closes settle at it
+    pub index_price: u64,
+    pub open_interest_long: u64,
+    pub open_interest_short: u64,
+    // Signed from version 2: positive when longs pay shorts
+    pub funding_rate_bps: i64,
+    pub volume_24h: u64,
+    pub last_update: i64,
+    pub bump: u8,
+    // Exponent of the raw mark and index prices above
+    pub price_expo: i32,
+    pub normalized_mark_price: u64,
+    pub normalized_index_price: u64,
+    pub price_format: u8,
+    pub params_immutable: bool,
+}
+
+impl MarketStats {
+    pub const LEN: usize = 1 // version
+        + 32 // market
+        + 8 // mark_price
+        + 8 // index_price
+        + 8 // open_interest_long
+        + 8 // open_interest_short
+        + 8 // funding_rate_bps
+        + 8 // volume_24h
+        + 8 // last_update
+        + 1 // bump
+        + 4 // price_expo
+        + 8 // normalized_mark_price
+        + 8 // normalized_index_price
+        + 1 // price_format
+        + 1; // params_immutable
+}
+
+/// Canonical position snapshot attested by attest_position: the Borsh (little-endian, no padding) encoding of
+/// this struct, always PositionSnapshot::LEN bytes. Byte offsets for version 1:
+/// version 0, position 1, trader 33, market 65, market_id 97, is_long 129, collateral 130, size 138,
+/// base_size 146, entry_price 154, price_exponent 162, leverage 166, open_time 168, last_funding_index 176,
+/// oracle_price 184, oracle_exponent 192, oracle_publish_time 196, contract_type 204, liquidation_threshold 205,
+/// max_leverage 207, market_funding_index 209, slot 217, timestamp 225.
+/// The attested hash is the SHA-256 of these bytes. Fields are only ever appended under a new version.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PositionSnapshot {
+    pub version: u8,
+    pub position: Pubkey,
+    pub trader: Pubkey,
+    pub market: Pubkey,
+    pub market_id: [u8; 32],
+    pub is_long: bool,
+    pub collateral: u64,
+    pub size: u64,
+    pub base_size: u64,
+    pub entry_price: u64,
+    pub price_exponent: i32,
+    pub leverage: u16,
+    pub open_time: i64,
+    pub last_funding_index: i64,
+    pub oracle_price: u64,
+    pub oracle_exponent: i32,
+    pub oracle_publish_time: i64,
+    pub contract_type: ContractType,
+    pub liquidation_threshold: u16,
+    pub max_leverage: u16,
+    pub market_funding_index: i64,
+    pub slot: u64,
+    pub timestamp: i64,
+}
+
+impl PositionSnapshot {
+    pub const LEN: usize = 1 // version
+        + 32 // position
+        + 32 // trader
+        + 32 // market
+        + 32 // market_id
+        + 1 // is_long
+        + 8 // collateral
+        + 8 // size
+        + 8 // base_size
+        + 8 // entry_price
+        + 4 // price_exponent
+        + 2 // leverage
+        + 8 // open_time
+        + 8 // last_funding_index
+        + 8 // oracle_price
+        + 4 // oracle_exponent
+        + 8 // oracle_publish_time
+        + 1 // contract_type
+        + 2 // liquidation_threshold
+        + 2 // max_leverage
+        + 8 // market_funding_index
+        + 8 // slot
+        + 8; // timestamp
+}
+
+const _: () = assert!(PositionSnapshot::LEN == 233);
+
+/// Short-lived account holding one position attestation for relayers; the creator closes it after use
+#[account]
+#[derive(Default)]
+pub struct Attestation {
+    pub creator: Pubkey,
+    pub hash: [u8; 32],
+    pub snapshot: PositionSnapshot,
+    pub bump: u8,
+}
+
+impl Attestation {
+    pub const LEN: usize = 32 // creator
+        + 32 // hash
+        + PositionSnapshot::LEN // snapshot
+        + 1; // bump
+}
+
+/// One page of the market registry. Market i (in creation order) is at slot i % MARKET_PAGE_CAPACITY of
+/// page i / MARKET_PAGE_CAPACITY; a delisted market leaves Pubkey::default() in its slot. Clients list every
+/// market by walking pages from 0 until ProgramConfig.listed_markets entries have been seen.
+#[account]
+#[derive(Default)]
+pub struct MarketPage {
+    pub page_index: u32,
+    pub count: u16,
+    pub markets: [Pubkey; MARKET_PAGE_CAPACITY],
+    pub bump: u8,
+}
+
+impl MarketPage {
+    pub const LEN: usize = 4 // page_index
+        + 2 // count
+        + 32 * MARKET_PAGE_CAPACITY // markets
+        + 1; // bump
+}
+
+/// The last PARAM_HISTORY_CAPACITY parameter sets of a market, each with the slot it took effect and who set it.
+/// entries is a ring buffer: head is the next slot to write and the count entries before it are live, oldest first.
+#[account]
+#[derive(Default)]
+pub struct ParamHistory {
+    pub market: Pubkey,
+    pub retention_slots: u64,
+    pub head: u16,
+    pub count: u16,
+    pub entries: [ParamHistoryEntry; PARAM_HISTORY_CAPACITY],
+    pub bump: u8,
+}
+
+impl ParamHistory {
+    pub const LEN: usize = 32 // market
+        + 8 // retention_slots
+        + 2 // head
+        + 2 // count
+        + ParamHistoryEntry::LEN * PARAM_HISTORY_CAPACITY // entries
+        + 1; // bump
+
+    /// Parameters in force at the given slot: the newest entry at or before it. None when the slot predates
+    /// every entry still held.
+    pub fn params_at(&self, slot: u64) -> Option<MarketParams> {
+        (1..=self.count as usize)
+            .map(|age| &self.entries[(self.head as usize + PARAM_HISTORY_CAPACITY - age) % PARAM_HISTORY_CAPACITY])
+            .find(|entry| entry.slot <= slot)
+            .map(|entry| entry.params)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ParamHistoryEntry {
+    pub slot: u64,
+    pub changed_by: Pubkey,
+    pub params: MarketParams,
+}
+
+impl ParamHistoryEntry {
+    pub const LEN: usize = 8 // slot
+        + 32 // changed_by
+        + MarketParams::LEN; // params
+}
+
+// Risk and fee parameters set through update_market
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct MarketParams {
+    pub min_collateral: u64,
+    pub max_leverage: u16,
+    pub liquidation_threshold: u16,
+    pub funding_rate_bps: u16,
+    pub funding_interval: i64,
+    pub max_funding_rate_bps_per_interval: u16,
+    pub risk_weight_bps: u16,
+    pub min_price: u64,
+    pub skew_premium_bps: u16,
+    pub liquidation_hysteresis_bps: u16,
+    pub open_skew_premium_bps: u16,
+    pub open_skew_rebate_bps: u16,
+}
+
+impl MarketParams {
+    pub const LEN: usize = 8 // min_collateral
+        + 2 // max_leverage
+        + 2 // liquidation_threshold
+        + 2 // funding_rate_bps
+        + 8 // funding_interval
+        + 2 // max_funding_rate_bps_per_interval
+        + 2 // risk_weight_bps
+        + 8 // min_price
+        + 2 // skew_premium_bps
+        + 2 // liquidation_hysteresis_bps
+        + 2 // open_skew_premium_bps
+        + 2; // open_skew_rebate_bps
+
+    pub fn from_market(market: &Market) -> Self {
+        Self {
+            min_collateral: market.min_collateral,
+            max_leverage: market.max_leverage,
+            liquidation_threshold: market.liquidation_threshold,
+            funding_rate_bps: market.funding_rate_bps,
+            funding_interval: market.funding_interval,
+            max_funding_rate_bps_per_interval: market.max_funding_rate_bps_per_interval,
+            risk_weight_bps: market.risk_weight_bps,
+            min_price: market.min_price,
+            skew_premium_bps: market.skew_premium_bps,
+            liquidation_hysteresis_bps: market.liquidation_hysteresis_bps,
+            open_skew_premium_bps: market.open_skew_premium_bps,
+            open_skew_rebate_bps: market.open_skew_rebate_bps,
+        }
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct Position {
+    pub trader: Pubkey,
+    pub market_id: [u8; 32],
+    pub collateral: u64,
+    pub size: u64,
+    pub is_long: bool,
+    pub entry_price: u64,
+    pub leverage: u16,
+    pub open_time: i64,
+    pub collateral_mint: Pubkey,
+    pub last_funding_index: i64,
+    pub nonce: u64,
+    pub liquidatable_flagged_at: i64,
+    pub client_order_id: u64,
+    // Oracle exponent entry_price is quoted at; fixed for the life of the position
+    pub price_exponent: i32,
+    pub liquidation_risk_accepted: bool,
+    // Size in base-asset units at the collateral's precision, fixed at open
+    pub base_size: u64,
+    // Terms pinned at open: the close fee, liquidation penalty and liquidation threshold in force then apply for
+    // the position's whole life. Funding, the mark price and the liquidation hysteresis band follow the market.
+    // Positions opened before pinning have params_pinned unset and use the current values.
+    pub params_pinned: bool,
+    pub close_fee_bps: u16,
+    pub liquidation_penalty_bps: u16,
+    pub liquidation_threshold: u16,
+    // Integrator tag set at open and echoed in the position's events; no on-chain meaning (zeros when unset)
+    pub memo: [u8; 32],
+    // Index price when the position was first flagged liquidatable (0 when unflagged)
+    pub breach_price: u64,
+    pub reserved: [u8; 4],
+}
+
+impl Position {
+    pub const LEN: usize = 32 // trader
+        + 32 // market_id
+        + 8 // collateral
+        + 8 // size
+        + 1 // is_long
+        + 8 // entry_price
+        + 2 // leverage
+        + 8 // open_time
+        + 32 // collateral_mint
+        + 8 // last_funding_index
+        + 8 // nonce
+        + 8 // liquidatable_flagged_at
+        + 8 // client_order_id
+        + 4 // price_exponent
+        + 1 // liquidation_risk_accepted
+        + 8 // base_size
+        + 1 // params_pinned
+        + 2 // close_fee_bps
+        + 2 // liquidation_penalty_bps
+        + 2 // liquidation_threshold
+        + 32 // memo
+        + 8 // breach_price
+        + 4; // reserved
+}
+
+#[account]
+#[derive(Default)]
+pub struct TraderAccount {
+    pub owner: Pubkey,
+    pub position_nonce: u64,
+    pub epoch: u64,
+    pub epoch_realized_pnl: i64,
+    pub epoch_volume: u64,
+    pub recent_client_order_ids: [u64; CLIENT_ORDER_ID_WINDOW],
+    pub total_client_order_ids: u64,
+    pub previous_epoch: u64,
+    pub previous_epoch_volume: u64,
+    pub pending_claims: [PendingClaim; MAX_PENDING_CLAIMS],
+    pub last_open_slot: u64,
+    pub opens_in_slot: u16,
+    // Lifetime realized PnL (net of fees and funding), its highest value, and the deepest fall from that peak
+    pub realized_pnl: i128,
+    pub equity_high_water: i128,
+    pub max_drawdown_bps: u32,
+    // Opt-in delay on close proceeds (0 = paid out immediately); a shorter delay waits until effective_at
+    pub withdrawal_delay_secs: i64,
+    pub pending_withdrawal_delay_secs: i64,
+    pub withdrawal_delay_effective_at: i64,
+    // Key that can send escrowed proceeds back into a position alongside the trader
+    pub escrow_guardian: Pubkey,
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PendingClaim {
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+impl PendingClaim {
+    pub const LEN: usize = 32 // market
+        + 32 // mint
+        + 8; // amount
+}
+
+impl TraderAccount {
+    pub const LEN: usize = 32 // owner
+        + 8 // position_nonce
+        + 8 // epoch
+        + 8 // epoch_realized_pnl
+        + 8 // epoch_volume
+        + 8 * CLIENT_ORDER_ID_WINDOW // recent_client_order_ids
+        + 8 // total_client_order_ids
+        + 8 // previous_epoch
+        + 8 // previous_epoch_volume
+        + PendingClaim::LEN * MAX_PENDING_CLAIMS // pending_claims
+        + 8 // last_open_slot
+        + 2 // opens_in_slot
+        + 16 // realized_pnl
+        + 16 // equity_high_water
+        + 4 // max_drawdown_bps
+        + 8 // withdrawal_delay_secs
+        + 8 // pending_withdrawal_delay_secs
+        + 8 // withdrawal_delay_effective_at
+        + 32 // escrow_guardian
+        + 1; // bump
+}
+
+// Registration of a keeper for one market, backed by a refundable bond held in the keeper_bond token account
+#[account]
+#[derive(Default)]
+pub struct KeeperAccount {
+    pub keeper: Pubkey,
+    pub market: Pubkey,
+    pub bond: u64,
+    pub registered_at: i64,
+    pub successful_executions: u64,
+    pub failed_executions: u64,
+    // Oracle price used by the latest successful execution (0 if it used none)
+    pub last_execution_price: u64,
+    pub last_execution_time: i64,
+    pub bump: u8,
+}
+
+impl KeeperAccount {
+    pub const LEN: usize = 32 // keeper
+        + 32 // market
+        + 8 // bond
+        + 8 // registered_at
+        + 8 // successful_executions
+        + 8 // failed_executions
+        + 8 // last_execution_price
+        + 8 // last_execution_time
+        + 1; // bump
+}
+
+// Close proceeds held in the market vault for one trader until release_time; counted in
+// Market.pending_trader_claims while held
+#[account]
+#[derive(Default)]
+pub struct WithdrawalEscrow {
+    pub trader: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub release_time: i64,
+    pub bump: u8,
+}
+
+impl WithdrawalEscrow {
+    pub const LEN: usize = 32 // trader
+        + 32 // market
+        + 8 // amount
+        + 8 // release_time
+        + 1; // bump
+}
+
+#[account]
+#[derive(Default)]
+pub struct RebateEpoch {
+    pub epoch: u64,
+    pub mint: Pubkey,
+    pub total_pool: u64,
+    pub remaining_pool: u64,
+    pub total_volume: u64,
+    pub remaining_volume: u64,
+    pub claim_deadline: i64,
+    pub swept: bool,
+    pub bump: u8,
+}
+
+impl RebateEpoch {
+    pub const LEN: usize = 8 // epoch
+        + 32 // mint
+        + 8 // total_pool
+        + 8 // remaining_pool
+        + 8 // total_volume
+        + 8 // remaining_volume
+        + 8 // claim_deadline
+        + 1 // swept
+        + 1; // bump
+}
+
+#[account]
+#[derive(Default)]
+pub struct RebateClaim {
+    pub trader: Pubkey,
+    pub amount: u64,
+    pub bump: u8,
+}
+
+impl RebateClaim {
+    pub const LEN: usize = 32 // trader
+        + 8 // amount
+        + 1; // bump
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct EpochStats {
+    pub epoch: u64,
+    pub realized_pnl: i64,
+    pub volume: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct HoldingCostProjection {
+    pub horizon_secs: i64,
+    pub funding_intervals: u64,
+    pub funding_rate_bps: i64,
+    // Funding settled by the end of the horizon, including anything already accrued; negative when received
+    pub projected_funding: i64,
+    pub close_fee: u64,
+    pub projected_collateral: u64,
+    pub liquidation_price: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PositionBreakdown {
+    // Index (oracle) price, which closes settle at
+    pub price: u64,
+    // Skew-adjusted mark price, which unrealized PnL and liquidation health use
+    pub mark_price: u64,
+    // Collateral before unsettled funding is applied
+    pub collateral: u64,
+    // Funding owed since the last settlement; negative when the position is owed funding
+    pub accrued_funding: i64,
+    pub unrealized_pnl: i64,
+    pub close_fee: u64,
+    // What close_position would pay out right now
+    pub net_withdrawable: u64,
+    pub base_size: u64,
+    // Unrealized PnL converted to base units at the current price
+    pub unrealized_pnl_base: i64,
+}
+
+// Positions whose notional reaches size_threshold are liquidated at liquidation_threshold instead of the
+// market's base threshold; unused brackets are all zeros
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ThresholdBracket {
+    pub size_threshold: u64,
+    pub liquidation_threshold: u16,
+}
+
+impl ThresholdBracket {
+    pub const LEN: usize = 8 // size_threshold
+        + 2; // liquidation_threshold
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct LiquidationPenaltySplit {
+    pub liquidator_share_bps: u16,
+    pub insurance_share_bps: u16,
+    pub treasury_share_bps: u16,
+}
+
+// Arguments to update_market; each field left as None keeps the market's current value
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct UpdateMarketParams {
+    pub min_collateral: Option<u64>,
+    pub max_leverage: Option<u16>,
+    pub liquidation_threshold: Option<u16>,
+    pub is_active: Option<bool>,
+    pub funding_rate_bps: Option<u16>,
+    pub funding_interval: Option<i64>,
+    pub crank_reward: Option<u64>,
+    pub max_open_positions: Option<u32>,
+    pub max_funding_rate_bps_per_interval: Option<u16>,
+    pub post_unpause_grace_secs: Option<i64>,
+    pub risk_weight_bps: Option<u16>,
+    pub min_price: Option<u64>,
+    pub liquidation_conf_multiplier: Option<u16>,
+    pub mark_ema_half_life_secs: Option<i64>,
+    pub max_mark_divergence_bps: Option<u16>,
+    pub utilization_pause_threshold_bps: Option<u16>,
+    pub min_publishers: Option<u16>,
+    pub require_trading_status: Option<bool>,
+    pub liquidation_penalty_split: Option<LiquidationPenaltySplit>,
+    pub min_collateral_usd: Option<u64>,
+    pub min_collateral_mode: Option<MinCollateralMode>,
+    pub skew_premium_bps: Option<u16>,
+    pub supports_transfer_fee: Option<bool>,
+    pub liquidation_hysteresis_bps: Option<u16>,
+    pub keeper_bond_amount: Option<u64>,
+    pub unregistered_keeper_discount_bps: Option<u16>,
+    pub max_position_liquidity_multiple_bps: Option<u32>,
+    pub liquidity_updater: Option<Pubkey>,
+    pub max_liquidity_reference_change_bps: Option<u16>,
+    pub open_skew_premium_bps: Option<u16>,
+    pub open_skew_rebate_bps: Option<u16>,
+    pub zero_collateral_liquidation_reward: Option<u64>,
+    pub daily_withdrawal_limit: Option<u64>,
+    pub daily_withdrawal_count_limit: Option<u16>,
+    pub threshold_brackets: Option<[ThresholdBracket; MAX_THRESHOLD_BRACKETS]>,
+    pub liquidation_delay_protection: Option<bool>,
+    pub max_liquidation_delay_secs: Option<i64>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct BaseSizeOrder {
+    // Size in base-asset units at the collateral's precision
+    pub desired_base_size: u64,
+    // Most collateral the order may post; with no leverage given, exactly this much is posted
+    pub max_collateral: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OpenQuote {
+    pub price: u64,
+    pub collateral_amount: u64,
+    pub leverage: u16,
+    pub position_size: u64,
+    pub base_size: u64,
+}
+
+// Return data of get_normalized_price. Raw values are at price_expo; normalized ones at NORMALIZED_PRICE_EXPONENT,
+// rounded down exactly as in events.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct NormalizedPriceQuote {
+    pub price: u64,
+    pub conf: u64,
+    pub price_expo: i32,
+    pub normalized_price: u64,
+    pub normalized_conf: u64,
+    pub publish_time: i64,
+    // Seconds since publish_time; the trading paths impose no age limit of their own
+    pub age_secs: i64,
+    // Publisher-count and trading-status gate; opens and liquidations require it, closes settle at the worse of this and last_good_price
+    pub quality_ok: bool,
+    // Whether the price is too far from the market's mark for opens, and for liquidations without an earlier record
+    pub mark_divergent: bool,
+    pub last_good_price: u64,
+    pub price_format: u8,
+}
+
+#[account]
+#[derive(Default)]
+pub struct TraderHistory {
+    pub owner: Pubkey,
+    pub total_records: u64,
+    pub records: [HistoryRecord; TRADER_HISTORY_CAPACITY],
+    pub bump: u8,
+}
+
+impl TraderHistory {
+    pub const LEN: usize = 32 // owner
+        + 8 // total_records
+        + HistoryRecord::LEN * TRADER_HISTORY_CAPACITY // records
+        + 1; // bump
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct HistoryRecord {
+    pub action: HistoryAction,
+    pub market_id: [u8; 32],
+    pub size: u64,
+    pub price: u64,
+    pub pnl: i64,
+    pub timestamp: i64,
+}
+
+impl HistoryRecord {
+    pub const LEN: usize = 1 // action
+        + 32 // market_id
+        + 8 // size
+        + 8 // price
+        + 8 // pnl
+        + 8; // timestamp
+}
+
+// Variant order matters: Inactive and Active line up with the old is_active bool (0/1),
+// so existing markets deserialize unchanged
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarketStatus {
+    #[default]
+    Inactive,
+    Active,
+    // Sunsetting: no new opens, funding frozen; closes and liquidations still allowed
+    Archived,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContractType {
+    // Collateral and PnL in the quote token
+    #[default]
+    Linear,
+    // Collateral and PnL in the base asset (e.g. BTC-margined BTC-PERP)
+    Inverse,
+}
+
+// Instruction that finished a dry run, reported in DryRunCompleted
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DryRunInstruction {
+    Open,
+    Close,
+}
+
+// Which collateral minimum open_position enforces
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MinCollateralMode {
+    // Token amount only (min_collateral)
+    #[default]
+    Token,
+    // USD value only (min_collateral_usd)
+    Usd,
+    // Both; the stricter one decides
+    Both,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistoryAction {
+    #[default]
+    Open,
+    Close,
+    Liquidate,
+}
+
+/// Discriminant carried in AsterEvent.kind; values are stable and only ever appended.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompactEventKind {
+    Open = 0,
+    Close = 1,
+    Liquidate = 2,
+    Funding = 3,
+    MarketUpdate = 4,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CompactOpen {
+    pub position: Pubkey,
+    pub trader: Pubkey,
+    pub nonce: u64,
+    pub is_long: bool,
+    pub collateral_amount: u64,
+    pub position_size: u64,
+    pub entry_price: u64,
+    pub leverage: u16,
+    pub liquidation_risk_accepted: bool,
+}
+
+impl CompactOpen {
+    pub const LEN: usize = 32 // position
+        + 32 // trader
+        + 8 // nonce
+        + 1 // is_long
+        + 8 // collateral_amount
+        + 8 // position_size
+        + 8 // entry_price
+        + 2 // leverage
+        + 1; // liquidation_risk_accepted
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CompactClose {
+    pub position: Pubkey,
+    pub trader: Pubkey,
+    pub close_price: u64,
+    pub pnl: i64,
+    pub fee: u64,
+    pub epoch: u64,
+}
+
+impl CompactClose {
+    pub const LEN: usize = 32 // position
+        + 32 // trader
+        + 8 // close_price
+        + 8 // pnl
+        + 8 // fee
+        + 8; // epoch
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CompactLiquidate {
+    pub position: Pubkey,
+    pub trader: Pubkey,
+    pub liquidator: Pubkey,
+    pub liquidation_price: u64,
+    pub fee: u64,
+    pub insurance_share: u64,
+    pub treasury_share: u64,
+    pub remainder: u64,
+    pub epoch: u64,
+    pub zero_collateral: bool,
+}
+
+impl CompactLiquidate {
+    pub const LEN: usize = 32 // position
+        + 32 // trader
+        + 32 // liquidator
+        + 8 // liquidation_price
+        + 8 // fee
+        + 8 // insurance_share
+        + 8 // treasury_share
+        + 8 // remainder
+        + 8 // epoch
+        + 1; // zero_collateral
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CompactFunding {
+    pub market: Pubkey,
+    pub applied_rate: i64,
+    pub open_interest_long: u64,
+    pub open_interest_short: u64,
+    pub funding_index: i64,
+}
+
+impl CompactFunding {
+    pub const LEN: usize = 32 // market
+        + 8 // applied_rate
+        + 8 // open_interest_long
+        + 8 // open_interest_short
+        + 8; // funding_index
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CompactMarketUpdate {
+    pub market: Pubkey,
+    pub status: MarketStatus,
+    pub max_leverage: u16,
+    pub liquidation_threshold: u16,
+    pub funding_rate_bps: u16,
+    pub min_price: u64,
+}
+
+impl CompactMarketUpdate {
+    pub const LEN: usize = 32 // market
+        + 1 // status
+        + 2 // max_leverage
+        + 2 // liquidation_threshold
+        + 2 // funding_rate_bps
+        + 8; // min_price
+}
+
+const _: () = assert!(CompactOpen::LEN <= MAX_COMPACT_PAYLOAD);
+const _: () = assert!(CompactClose::LEN <= MAX_COMPACT_PAYLOAD);
+const _: () = assert!(CompactLiquidate::LEN <= MAX_COMPACT_PAYLOAD);
+const _: () = assert!(CompactFunding::LEN <= MAX_COMPACT_PAYLOAD);
+const _: () = assert!(CompactMarketUpdate::LEN <= MAX_COMPACT_PAYLOAD);
+
+// Error codes are matched on by clients, so variants are only ever appended. The math codes are pinned here
+// so a reordering fails the build instead of silently renumbering them.
+const _: () = assert!(AsterDexError::MathOverflow as u32 + anchor_lang::error::ERROR_CODE_OFFSET == 6012);
+const _: () = assert!(AsterDexError::DivisionByZero as u32 + anchor_lang::error::ERROR_CODE_OFFSET == 6074);
+const _: () = assert!(AsterDexError::PrecisionLoss as u32 + anchor_lang::error::ERROR_CODE_OFFSET == 6075);
+
+#[error_code]
+pub enum AsterDexError {
+    #[msg("Market is not active")]
+    MarketInactive,
+    #[msg("Leverage below the minimum")]
+    LeverageTooLow,
+    #[msg("Collateral below the market minimum")]
+    BelowMinCollateral,
+    #[msg("Invalid position")]
+    InvalidPosition,
+    #[msg("Cannot liquidate yet")]
+    CannotLiquidateYet,
+    #[msg("Unauthorized action")]
+    Unauthorized,
+    #[msg("Invalid token account")]
+    InvalidTokenAccount,
+    #[msg("Invalid mint")]
+    InvalidMint,
+    #[msg("Invalid oracle")]
+    InvalidOracle,
+    #[msg("Invalid liquidation threshold")]
+    InvalidLiquidationThreshold,
+    #[msg("Oracle account is not owned by the Pyth program")]
+    InvalidOracleOwner,
+    #[msg("Invalid funding interval")]
+    InvalidFundingInterval,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Leverage above the maximum")]
+    LeverageTooHigh,
+    #[msg("Insufficient token balance")]
+    InsufficientBalance,
+    #[msg("Market open position cap reached")]
+    MarketPositionCapReached,
+    #[msg("Global open position cap reached")]
+    GlobalPositionCapReached,
+    #[msg("Program is paused")]
+    ProgramPaused,
+    #[msg("Market is frozen for new positions")]
+    MarketFrozen,
+    #[msg("Swap program is not whitelisted")]
+    SwapProgramNotWhitelisted,
+    #[msg("Swap program whitelist is full")]
+    SwapProgramListFull,
+    #[msg("Invalid swap pool accounts")]
+    InvalidSwapAccounts,
+    #[msg("Slippage exceeded")]
+    SlippageExceeded,
+    #[msg("Oracle price feed failed validation")]
+    OracleValidationFailed,
+    #[msg("Return amount is below the requested minimum")]
+    ReturnBelowMinimum,
+    #[msg("Signer is not an allowlisted market creator")]
+    MarketCreatorNotAllowed,
+    #[msg("Market creator allowlist is full")]
+    MarketCreatorListFull,
+    #[msg("Vault balance does not match the market accounting")]
+    AccountingMismatch,
+    #[msg("Epoch length cannot be negative")]
+    InvalidEpochLength,
+    #[msg("Market still has open positions")]
+    MarketHasOpenPositions,
+    #[msg("Market account is still live")]
+    MarketNotOrphaned,
+    #[msg("Grace period cannot be negative")]
+    InvalidGracePeriod,
+    #[msg("Liquidations are held back during the post-unpause grace period")]
+    UnpauseGracePeriod,
+    #[msg("Client order id was already used for a recent open")]
+    DuplicateClientOrderId,
+    #[msg("Feature is not supported by this program build")]
+    UnsupportedFeature,
+    #[msg("Funding dust was already swept this epoch")]
+    FundingSweepTooEarly,
+    #[msg("Position is still below the liquidation threshold")]
+    PositionStillLiquidatable,
+    #[msg("Rebates can only be funded for the epoch that just ended")]
+    RebateEpochNotFinished,
+    #[msg("No trading volume to pay rebates against")]
+    NoRebateVolume,
+    #[msg("Rebate claims are closed for this epoch")]
+    RebateClaimClosed,
+    #[msg("Rebate claim window is still open")]
+    RebateClaimWindowOpen,
+    #[msg("Oracle price moved beyond the allowed deviation, retry with a fresh price")]
+    PriceMovedRetry,
+    #[msg("Risk weight cannot exceed 100%")]
+    InvalidRiskWeight,
+    #[msg("Invalid fee treasury account")]
+    InvalidFeeTreasury,
+    #[msg("Price is zero or below the market's sanity bound")]
+    InvalidPrice,
+    #[msg("Market is archived")]
+    MarketArchived,
+    #[msg("Market must be archived first")]
+    MarketNotArchived,
+    #[msg("Vault still holds tokens")]
+    VaultNotEmpty,
+    #[msg("No pending claim for this market")]
+    NoPendingClaim,
+    #[msg("Too many pending claims; claim one first")]
+    PendingClaimsFull,
+    #[msg("Projection horizon cannot be negative")]
+    InvalidHorizon,
+    #[msg("Admin instruction must be the only instruction for this program in the transaction")]
+    BundledAdminInstruction,
+    #[msg("Instructions sysvar is required in strict admin mode")]
+    InstructionsSysvarRequired,
+    #[msg("Oracle price exponent changed; the market admin must acknowledge it")]
+    OracleExponentChanged,
+    #[msg("Liquidation price is within the oracle confidence band")]
+    LiquidationPriceWithinConfidence,
+    #[msg("Oracle price diverges too far from the market's mark price")]
+    MarkPriceDivergence,
+    #[msg("Mark price half-life cannot be negative")]
+    InvalidHalfLife,
+    #[msg("Open would push market utilization past its threshold")]
+    UtilizationTooHigh,
+    #[msg("Re-entrant call into the program")]
+    ReentrantCall,
+    #[msg("Oracle feed has too few publishers or is not trading")]
+    OracleQualityTooLow,
+    #[msg("Token account has not delegated enough to the program")]
+    InsufficientDelegation,
+    #[msg("Too many opens from this trader in the current slot")]
+    RateLimited,
+    #[msg("Position size below the program minimum")]
+    PositionTooSmall,
+    #[msg("Fee distribution share cannot exceed 100%")]
+    InvalidDistributionShare,
+    #[msg("Fee distribution is not configured")]
+    FeeDistributionDisabled,
+    #[msg("Distribution destination does not match the configured account")]
+    InvalidDistributionDestination,
+    #[msg("Fee distribution interval has not elapsed")]
+    DistributionTooSoon,
+    #[msg("Order needs more collateral than its maximum")]
+    MaxCollateralExceeded,
+    #[msg("Liquidation penalty shares must sum to 10000 bps")]
+    InvalidPenaltySplit,
+    #[msg("Market page is not the next page of the registry")]
+    InvalidMarketPage,
+    #[msg("Market is not listed in this registry page")]
+    MarketNotListed,
+    #[msg("Account already uses the current layout")]
+    AlreadyMigrated,
+    #[msg("Market parameters are immutable")]
+    MarketImmutable,
+    #[msg("Skew premium cannot exceed 100%")]
+    InvalidSkewPremium,
+    #[msg("Division by zero")]
+    DivisionByZero,
+    #[msg("Math operation would lose precision")]
+    PrecisionLoss,
+    #[msg("Dry run succeeded; all changes were rolled back")]
+    DryRunSuccess,
+    #[msg("Withdrawal delay cannot be negative")]
+    InvalidWithdrawalDelay,
+    #[msg("Withdrawal delay is on; pass the trader's withdrawal escrow")]
+    EscrowRequired,
+    #[msg("Escrowed funds are not released yet")]
+    EscrowLocked,
+    #[msg("Withdrawal escrow holds nothing")]
+    EscrowEmpty,
+    #[msg("Withdrawal escrow still holds funds")]
+    EscrowNotEmpty,
+    #[msg("Not available while a withdrawal delay is on")]
+    WithdrawalDelayActive,
+    #[msg("Liquidation hysteresis exceeds 10000 bps")]
+    InvalidHysteresis,
+    #[msg("Market vault has been swept to the recovery account")]
+    MarketSwept,
+    #[msg("Market vault has not been swept")]
+    MarketNotSwept,
+    #[msg("Recovery account is not registered or does not match")]
+    InvalidRecoveryAccount,
+    #[msg("Recovery account was changed too recently to receive a sweep")]
+    RecoveryAccountTimelocked,
+    #[msg("Keeper registration is closed for this market")]
+    KeeperRegistryDisabled,
+    #[msg("Unregistered keeper discount exceeds 10000 bps")]
+    InvalidKeeperDiscount,
+    #[msg("Position size exceeds the market's liquidity cap")]
+    PositionTooLarge,
+    #[msg("Liquidity reference moved more than one update allows")]
+    LiquidityReferenceChangeTooLarge,
+    #[msg("Composer program is not allowed")]
+    ComposerProgramNotAllowed,
+    #[msg("Composer program list is full")]
+    ComposerProgramListFull,
+    #[msg("Position slot already holds a position")]
+    PositionSlotNotEmpty,
+    #[msg("Haircut must be between 1 and 10000 bps")]
+    InvalidHaircut,
+    #[msg("Losses cannot be socialized while the insurance fund has a balance")]
+    InsuranceFundNotEmpty,
+    #[msg("Vault holds everything it owes; there is no shortfall")]
+    NoShortfall,
+    #[msg("Open skew premium would consume the whole collateral")]
+    SkewPremiumExceedsCollateral,
+    #[msg("Daily withdrawal limit reached; the guardian must co-sign")]
+    WithdrawalLimitExceeded,
+    #[msg("Market is not a writable market listed on the registry page")]
+    MarketNotRegistered,
+    #[msg("Liquidations are paused on this market")]
+    LiquidationsFrozen,
+    #[msg("Reward treasury has not been initialized")]
+    RewardTreasuryNotInitialized,
+    #[msg("Liquidation reward discount must not exceed 10000 bps")]
+    InvalidRewardDiscount,
+    #[msg("Market keeps a parameter history; pass its ParamHistory account")]
+    ParamHistoryRequired,
+    #[msg("Parameter history is full and its oldest entry is still within retention")]
+    ParamHistoryFull,
+    #[msg("Volume accrual window cannot be negative")]
+    InvalidVolumeAccrualWindow,
+    #[msg("Threshold brackets must be increasing in size and threshold, starting at the base threshold")]
+    InvalidThresholdBrackets,
+    #[msg("Liquidation delay cannot be negative")]
+    InvalidLiquidationDelay,
+    #[msg("Rebate claim deadline must leave at least the minimum claim window open")]
+    InvalidRebateClaimDeadline,
+    #[msg("Position is flagged for liquidation; only a full close is allowed until the flag is cleared")]
+    PositionUnderLiquidation,
+}
+
+#[event]
+pub struct MarketInitialized {
+    #[index]
+    pub market: Pubkey,
+    pub market_id: [u8; 32],
+    pub creator: Pubkey,
+    pub oracle: Pubkey,
+    pub oracle_feed_id: [u8; 32],
+    pub oracle_price: u64,
+    pub contract_type: ContractType,
+}
+
+#[event]
+pub struct PositionOpened {
+    #[index]
+    pub position: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    pub market_id: [u8; 32],
+    pub nonce: u64,
+    pub client_order_id: u64,
+    pub is_long: bool,
+    pub collateral_amount: u64,
+    pub position_size: u64,
+    // Raw oracle mantissa at price_expo
+    pub entry_price: u64,
+    pub price_expo: i32,
+    pub normalized_entry_price: u64,
+    pub price_format: u8,
+    // Mark price after the open, at price_expo
+    pub mark_price: u64,
+    pub leverage: u16,
+    pub liquidation_risk_accepted: bool,
+    pub base_size: u64,
+    // Already reflected in collateral_amount: taken out for widening the skew, or credited for narrowing it
+    pub skew_premium: u64,
+    pub skew_rebate: u64,
+    pub memo: [u8; 32],
+}
+
+#[event]
+pub struct PositionClosed {
+    #[index]
+    pub position: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    // Raw oracle mantissa at price_expo
+    pub close_price: u64,
+    pub price_expo: i32,
+    pub normalized_close_price: u64,
+    pub price_format: u8,
+    // Mark price after the close, at price_expo
+    pub mark_price: u64,
+    pub pnl: i64,
+    pub fee: u64,
+    // The payout split into released collateral and profit from the vault; loss_absorbed is collateral
+    // kept by the vault beyond the fee
+    pub principal_returned: u64,
+    pub profit_paid: u64,
+    pub loss_absorbed: u64,
+    // Part of profit_paid kept in the vault towards a socialized shortfall; the trader received profit_paid - haircut
+    pub haircut: u64,
+    pub epoch: u64,
+    pub memo: [u8; 32],
+    // Closed within min_volume_accrual_secs of the open; the round trip earned no volume credit
+    pub wash_filtered: bool,
+}
+
+#[event]
+pub struct PositionLiquidated {
+    #[index]
+    pub position: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    pub liquidator: Pubkey,
+    // Raw oracle mantissa at price_expo
+    pub liquidation_price: u64,
+    pub price_expo: i32,
+    pub normalized_liquidation_price: u64,
+    pub price_format: u8,
+    // Mark price the liquidation health check used, at price_expo
+    pub mark_price: u64,
+    pub publish_time: i64,
+    // Liquidator's share of the penalty, or the flat insurance-funded reward when zero_collateral is set
+    pub fee: u64,
+    pub insurance_share: u64,
+    pub treasury_share: u64,
+    pub remainder: u64,
+    pub epoch: u64,
+    // Funding had drained the collateral to zero before the liquidation
+    pub zero_collateral: bool,
+    pub memo: [u8; 32],
+    // Reward paid in the config's reward mint instead of fee; fee then went to the insurance fund (0 = paid in kind)
+    pub converted_reward: u64,
+    // Added to remainder for a liquidation past the market's delay limit, from the reward and then insurance
+    pub delay_compensation: u64,
 }
 
-#[derive(Accounts)]
-#[instruction(market_id: [u8; 32])]
-pub struct OpenPosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        mut,
-        seeds = [b"market", &market_id],
-        bump = market.bump,
-        constraint = market.is_active @ AsterDexError::MarketInactive
-    )]
-    pub market: Account<'info, Market>,
-    
-    #[account(
-        init,
-        payer = user,
-        space = 8 + size_of::<Position>(),
-        seeds = [b"position", user.key().as_ref(), &market_id, &Clock::get().unwrap().unix_timestamp.to_le_bytes()],
-        bump
-    )]
-    pub position: Account<'info, Position>,
-    
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key() @ AsterDexError::InvalidTokenAccount,
-        constraint = user_token_account.mint == collateral_mint.key() @ AsterDexError::InvalidMint
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    
-    #[account(
-        mut,
-        seeds = [b"vault", market.key().as_ref()],
-        bump = market.bump
-    )]
-    pub vault: Account<'info, TokenAccount>,
-    
-    pub collateral_mint: Account<'info, Mint>,
-    
-    /// CHECK: This is the Pyth price feed account
-    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
-    pub price_feed: AccountInfo<'info>,
-    
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+#[event]
+pub struct FundingCranked {
+    #[index]
+    pub market: Pubkey,
+    pub keeper: Pubkey,
+    pub funding_index: i64,
+    pub reward: u64,
 }
 
-#[derive(Accounts)]
-pub struct ClosePosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        mut,
-        close = user,
-        constraint = position.trader == user.key() @ AsterDexError::Unauthorized
-    )]
-    pub position: Account<'info, Position>,
-    
-    #[account(
-        seeds = [b"market", &position.market_id],
-        bump = market.bump
-    )]
-    pub market: Account<'info, Market>,
-    
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key() @ AsterDexError::InvalidTokenAccount,
-        constraint = user_token_account.mint == position.collateral_mint @ AsterDexError::InvalidMint
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    
-    #[account(
-        mut,
-        seeds = [b"vault", market.key().as_ref()],
-        bump = market.bump
-    )]
-    pub vault: Account<'info, TokenAccount>,
-    
-    /// CHECK: This is the Pyth price feed account
-    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
-    pub price_feed: AccountInfo<'info>,
-    
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct FundingRateApplied {
+    #[index]
+    pub market: Pubkey,
+    pub raw_rate: i64,
+    pub applied_rate: i64,
+    pub interval: i64,
+    pub open_interest_long: u64,
+    pub open_interest_short: u64,
+    pub funding_index: i64,
 }
 
-#[derive(Accounts)]
-pub struct LiquidatePosition<'info> {
-    #[account(mut)]
-    pub liquidator: Signer<'info>,
-    
-    #[account(mut)]
-    /// CHECK: Position owner, doesn't need to sign for liquidation
-    pub trader: AccountInfo<'info>,
-    
-    #[account(
-        mut,
-        close = liquidator,
-        constraint = position.trader == trader.key() @ AsterDexError::InvalidPosition
-    )]
-    pub position: Account<'info, Position>,
-    
-    #[account(
-        seeds = [b"market", &position.market_id],
-        bump = market.bump
-    )]
-    pub market: Account<'info, Market>,
-    
-    #[account(
-        mut,
-        constraint = liquidator_token_account.owner == liquidator.key() @ AsterDexError::InvalidTokenAccount,
-        constraint = liquidator_token_account.mint == position.collateral_mint @ AsterDexError::InvalidMint
-    )]
-    pub liquidator_token_account: Account<'info, TokenAccount>,
-    
-    #[account(
-        mut,
-        seeds = [b"vault", market.key().as_ref()],
-        bump = market.bump
-    )]
-    pub vault: Account<'info, TokenAccount>,
-    
-    /// CHECK: This is the Pyth price feed account
-    #[account(constraint = market.oracle == price_feed.key() @ AsterDexError::InvalidOracle)]
-    pub price_feed: AccountInfo<'info>,
-    
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct MarketCreatorAdded {
+    pub creator: Pubkey,
+    pub authority: Pubkey,
 }
 
-#[derive(Accounts)]
-pub struct UpdateFunding<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    
-    #[account(
-        mut,
-        constraint = market.admin == admin.key() @ AsterDexError::Unauthorized
-    )]
-    pub market: Account<'info, Market>,
+#[event]
+pub struct MarketCreatorRemoved {
+    pub creator: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct GlobalPauseSet {
+    pub authority: Pubkey,
+    pub paused: bool,
+}
+
+#[event]
+pub struct MarketFrozenSet {
+    #[index]
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub frozen: bool,
+    pub liquidations_frozen: bool,
+}
+
+#[event]
+pub struct PositionProceedsSwapped {
+    #[index]
+    pub position: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    pub swap_program: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
+
+#[event]
+pub struct VaultReconciled {
+    #[index]
+    pub market: Pubkey,
+    pub vault_balance: u64,
+    pub total_reserved_collateral: u64,
+    pub accumulated_fees: u64,
+    pub realized_pnl_paid: i64,
+    pub funding_pool_balance: i64,
+    pub insurance_fund_balance: u64,
+    pub residual: i64,
+    pub funding_residual: i64,
+}
+
+#[event]
+pub struct EpochRolled {
+    pub epoch: u64,
+    pub epoch_start: i64,
+    pub epoch_length: i64,
+}
+
+#[event]
+pub struct PositionRescued {
+    #[index]
+    pub position: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    pub market: Pubkey,
+    pub collateral: u64,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct FundingSettled {
+    pub market_id: [u8; 32],
+    #[index]
+    pub trader: Pubkey,
+    pub nonce: u64,
+    pub is_long: bool,
+    pub payment: i64,
+    pub funding_pool_balance: i64,
+}
+
+#[event]
+pub struct FundingDustSwept {
+    #[index]
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub amount: u64,
+    pub funding_pool_balance: i64,
+    pub insurance_fund_balance: u64,
+}
+
+#[event]
+pub struct RebateEpochFunded {
+    pub epoch: u64,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub total_volume: u64,
+    pub claim_deadline: i64,
+}
+
+#[event]
+pub struct RebateClaimed {
+    pub epoch: u64,
+    #[index]
+    pub trader: Pubkey,
+    pub volume: u64,
+    pub amount: u64,
+}
+
+#[event]
+pub struct RebatesSwept {
+    pub epoch: u64,
+    pub amount: u64,
+}
+
+#[event]
+pub struct FeeTreasuryChanged {
+    #[index]
+    pub market: Pubkey,
+    pub previous_treasury: Pubkey,
+    pub new_treasury: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct FeesSwept {
+    #[index]
+    pub market: Pubkey,
+    pub fee_treasury: Pubkey,
+    pub amount: u64,
+    // Fees left accrued because the sweep hit the daily limit
+    pub retained: u64,
+    pub guardian_cosigned: bool,
+    // What further sweeps may still move in the current window (u64::MAX / u16::MAX = unlimited)
+    pub remaining_allowance: u64,
+    pub remaining_withdrawals: u16,
+}
+
+#[event]
+pub struct MarketArchived {
+    #[index]
+    pub market: Pubkey,
+    pub open_position_count: u32,
+    pub funding_index: i64,
+}
+
+#[event]
+pub struct ArchivedMarketSwept {
+    #[index]
+    pub market: Pubkey,
+    pub fee_treasury: Pubkey,
+    pub amount: u64,
+    // Protocol balance left in the vault because the sweep hit the daily limit
+    pub retained: u64,
+    pub guardian_cosigned: bool,
+    pub remaining_allowance: u64,
+    pub remaining_withdrawals: u16,
+}
+
+#[event]
+pub struct PendingClaimPaid {
+    #[index]
+    pub market: Pubkey,
+    #[index]
+    pub trader: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct OracleExponentAcknowledged {
+    #[index]
+    pub market: Pubkey,
+    pub previous_exponent: i32,
+    pub new_exponent: i32,
+}
+
+/// Compact event envelope used instead of the per-kind events when FEATURE_COMPACT_EVENTS is enabled.
+/// payload is the borsh encoding of the Compact* struct named by kind.
+#[event]
+pub struct AsterEvent {
+    pub version: u8,
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+#[event]
+pub struct AutoPauseTriggered {
+    #[index]
+    pub market: Pubkey,
+    pub utilization_bps: u64,
+    pub threshold_bps: u16,
+}
+
+#[event]
+pub struct FeesDistributed {
+    #[index]
+    pub market: Pubkey,
+    pub destination: Pubkey,
+    pub accrued: u64,
+    pub amount: u64,
+    pub retained: u64,
+    pub total_fees_distributed: u64,
+    pub total_fees_swept: u64,
+    pub timestamp: i64,
+    pub guardian_cosigned: bool,
+    // What further sweeps and distributions may still move in the current window (u64::MAX / u16::MAX = unlimited)
+    pub remaining_allowance: u64,
+    pub remaining_withdrawals: u16,
+}
+
+#[event]
+pub struct MarketParamsFinalized {
+    #[index]
+    pub market: Pubkey,
+    pub admin: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PositionAttested {
+    #[index]
+    pub attestation: Pubkey,
+    #[index]
+    pub position: Pubkey,
+    pub version: u8,
+    pub hash: [u8; 32],
+    pub slot: u64,
+}
+
+/// Marks the events before it in the same instruction as a dry run that was rolled back
+#[event]
+pub struct DryRunCompleted {
+    pub instruction: DryRunInstruction,
+    pub position: Pubkey,
+}
+
+#[event]
+pub struct WithdrawalEscrowed {
+    #[index]
+    pub trader: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub escrowed: u64,
+    pub release_time: i64,
 }
 
-#[account]
-pub struct Market {
-    pub admin: Pubkey,
-    pub oracle: Pubkey,
-    pub market_id: [u8; 32],
-    pub min_collateral: u64,
-    pub max_leverage: u16,
-    pub liquidation_threshold: u16,
-    pub is_active: bool,
-    pub last_funding_index: u64,
-    pub last_funding_time: i64,
-    pub bump: u8,
+#[event]
+pub struct EscrowClaimed {
+    #[index]
+    pub trader: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
 }
 
-#[account]
-pub struct Position {
+#[event]
+pub struct EscrowCancelled {
+    #[index]
     pub trader: Pubkey,
-    pub market_id: [u8; 32],
-    pub collateral: u64,
-    pub size: u64,
-    pub is_long: bool,
-    pub entry_price: u64,
-    pub leverage: u16,
-    pub open_time: i64,
-    pub collateral_mint: Pubkey,
-    pub last_funding_index: u64,
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub amount: u64,
+    pub authority: Pubkey,
 }
 
-#[error_code]
-pub enum AsterDexError {
-    #[msg("Market is not active")]
-    MarketInactive,
-    #[msg("Invalid leverage")]
-    InvalidLeverage,
-    #[msg("Insufficient collateral")]
-    InsufficientCollateral,
-    #[msg("Invalid position")]
-    InvalidPosition,
-    #[msg("Cannot liquidate yet")]
-    CannotLiquidateYet,
-    #[msg("Unauthorized action")]
-    Unauthorized,
-    #[msg("Invalid token account")]
-    InvalidTokenAccount,
-    #[msg("Invalid mint")]
-    InvalidMint,
-    #[msg("Invalid oracle")]
-    InvalidOracle,
-    #[msg("Invalid liquidation threshold")]
-    InvalidLiquidationThreshold,
+#[event]
+pub struct RecoveryAccountSet {
+    #[index]
+    pub market: Pubkey,
+    pub recovery_account: Pubkey,
+    pub usable_at: i64,
 }
 
 #[event]
-pub struct PositionOpened {
+pub struct EmergencySweepExecuted {
     #[index]
-    pub position: Pubkey,
+    pub market: Pubkey,
+    pub recovery_account: Pubkey,
+    pub amount: u64,
+    pub sweep_price: u64,
+    pub super_admin: Pubkey,
+    pub guardian: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RecoveryClaimRecorded {
     #[index]
+    pub market: Pubkey,
     pub trader: Pubkey,
-    pub market_id: [u8; 32],
-    pub is_long: bool,
-    pub collateral_amount: u64,
-    pub position_size: u64,
-    pub entry_price: u64,
-    pub leverage: u16,
+    pub position: Pubkey,
+    pub amount: u64,
+    pub sweep_price: u64,
 }
 
 #[event]
-pub struct PositionClosed {
+pub struct LiquidityReferenceUpdated {
     #[index]
-    pub position: Pubkey,
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub previous_reference: u64,
+    pub liquidity_reference: u64,
+}
+
+#[event]
+pub struct ComposerProgramChanged {
+    pub composer_program: Pubkey,
+    pub allowed: bool,
+}
+
+#[event]
+pub struct ShortfallRecorded {
+    #[index]
+    pub market: Pubkey,
+    pub deficit: u64,
+    pub fees_absorbed: u64,
+    pub shortfall: u64,
+    pub outstanding: u64,
+    pub haircut_bps: u16,
+}
+
+#[event]
+pub struct HaircutApplied {
     #[index]
+    pub market: Pubkey,
+    pub position: Pubkey,
     pub trader: Pubkey,
-    pub close_price: u64,
-    pub pnl: i64,
-    pub fee: u64,
+    pub profit: u64,
+    pub haircut: u64,
+    pub outstanding: u64,
 }
 
 #[event]
-pub struct PositionLiquidated {
+pub struct MarketsPauseSet {
+    pub page_index: u32,
+    pub authority: Pubkey,
+    pub paused: bool,
+    pub pause_liquidations: bool,
+    pub changed: u16,
+    pub skipped: u16,
+}
+
+#[event]
+pub struct RemainderToInsurance {
     #[index]
+    pub market: Pubkey,
     pub position: Pubkey,
     #[index]
     pub trader: Pubkey,
-    pub liquidator: Pubkey,
-    pub liquidation_price: u64,
-    pub fee: u64,
+    pub amount: u64,
+}
+
+/// Test fixtures: legacy Pyth price accounts built from a handful of parameters, laid out exactly as
+/// load_price_feed_from_account_info and load_price_account read them
+#[cfg(test)]
+mod mock_pyth {
+    use super::*;
+    use pyth_sdk_solana::state::{AccountType, PriceAccount, MAGIC, VERSION_2};
+
+    #[derive(Clone, Copy)]
+    pub struct MockPriceFeed {
+        pub price: i64,
+        pub conf: u64,
+        pub expo: i32,
+        pub publish_time: i64,
+        pub num_publishers: u32,
+        pub status: PriceStatus,
+    }
+
+    impl Default for MockPriceFeed {
+        fn default() -> Self {
+            Self {
+                price: 5_000_000_000_000,
+                conf: 1_000_000,
+                expo: -8,
+                publish_time: 1_700_000_000,
+                num_publishers: 10,
+                status: PriceStatus::Trading,
+            }
+        }
+    }
+
+    impl MockPriceFeed {
+        /// Raw account data in the v2 price account layout
+        pub fn data(&self) -> Vec<u8> {
+            let mut account = PriceAccount::default();
+            account.magic = MAGIC;
+            account.ver = VERSION_2;
+            account.atype = AccountType::Price as u32;
+            account.size = std::mem::size_of::<PriceAccount>() as u32;
+            account.expo = self.expo;
+            account.num = self.num_publishers;
+            account.num_qt = self.num_publishers;
+            account.timestamp = self.publish_time;
+            account.agg.price = self.price;
+            account.agg.conf = self.conf;
+            account.agg.status = self.status;
+            // Feeds that are not trading report the previous aggregate; keep it the same so both read alike
+            account.prev_price = self.price;
+            account.prev_conf = self.conf;
+            account.prev_timestamp = self.publish_time;
+
+            // SAFETY: PriceAccount is the repr(C), padding-free Pod struct Pyth itself casts account data to
+            unsafe {
+                std::slice::from_raw_parts(
+                    (&account as *const PriceAccount).cast::<u8>(),
+                    std::mem::size_of::<PriceAccount>(),
+                )
+            }
+            .to_vec()
+        }
+
+        /// An account holding this feed, owned by the given program
+        pub fn account(&self, owner: Pubkey) -> MockAccount {
+            MockAccount {
+                key: Pubkey::new_unique(),
+                owner,
+                lamports: 1_000_000_000,
+                data: self.data(),
+            }
+        }
+    }
+
+    pub struct MockAccount {
+        pub key: Pubkey,
+        pub owner: Pubkey,
+        pub lamports: u64,
+        pub data: Vec<u8>,
+    }
+
+    impl MockAccount {
+        pub fn account_info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(&self.key, false, false, &mut self.lamports, &mut self.data, &self.owner, false, 0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock_pyth::MockPriceFeed;
+    use super::*;
+
+    fn pyth_program() -> Pubkey {
+        Pubkey::new_from_array([9; 32])
+    }
+
+    fn oracle_config() -> ProgramConfig {
+        ProgramConfig {
+            pyth_program_id: pyth_program(),
+            ..Default::default()
+        }
+    }
+
+    fn oracle_market() -> Market {
+        Market {
+            oracle_exponent: -8,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mock_price_feed_parses_as_a_pyth_account() {
+        let feed = MockPriceFeed::default();
+        let mut account = feed.account(pyth_program());
+        let key = account.key;
+        let account_info = account.account_info();
+
+        let price_feed = load_price_feed_from_account_info(&account_info).unwrap();
+        assert_eq!(price_feed.id.to_bytes(), key.to_bytes());
+        let price = price_feed.get_price_unchecked();
+        assert_eq!(price.price, 5_000_000_000_000);
+        assert_eq!(price.conf, 1_000_000);
+        assert_eq!(price.expo, -8);
+        assert_eq!(price.publish_time, 1_700_000_000);
+
+        let data = account_info.try_borrow_data().unwrap();
+        let price_account = load_price_account(&data).unwrap();
+        assert_eq!(price_account.num_qt, 10);
+        assert!(price_account.agg.status == PriceStatus::Trading);
+    }
+
+    #[test]
+    fn mock_price_feed_reads_the_same_when_not_trading() {
+        let feed = MockPriceFeed {
+            status: PriceStatus::Halted,
+            ..Default::default()
+        };
+        let mut account = feed.account(pyth_program());
+        let price = load_price_feed_from_account_info(&account.account_info()).unwrap().get_price_unchecked();
+        assert_eq!(price.price, feed.price);
+        assert_eq!(price.publish_time, feed.publish_time);
+    }
+
+    #[test]
+    fn load_oracle_rejects_zeroed_and_negative_feeds() {
+        let invalid_price: Error = AsterDexError::InvalidPrice.into();
+        for price in [0, -1, i64::MIN] {
+            let mut account = MockPriceFeed { price, ..Default::default() }.account(pyth_program());
+            let result = load_oracle(&account.account_info(), &oracle_config(), &oracle_market());
+            assert_eq!(result.unwrap_err(), invalid_price, "price {price}");
+        }
+        for price in [1, i64::MAX] {
+            let mut account = MockPriceFeed { price, ..Default::default() }.account(pyth_program());
+            let quote = load_oracle_quote(&account.account_info(), &oracle_config(), &oracle_market()).unwrap();
+            assert_eq!(quote, (price as u64, 1_700_000_000));
+        }
+    }
+
+    #[test]
+    fn load_oracle_checks_owner_and_exponent() {
+        let mut account = MockPriceFeed::default().account(Pubkey::new_unique());
+        let wrong_owner: Error = AsterDexError::InvalidOracleOwner.into();
+        assert_eq!(load_oracle(&account.account_info(), &oracle_config(), &oracle_market()).unwrap_err(), wrong_owner);
+
+        let mut account = MockPriceFeed { expo: -6, ..Default::default() }.account(pyth_program());
+        let exponent_changed: Error = AsterDexError::OracleExponentChanged.into();
+        assert_eq!(
+            load_oracle(&account.account_info(), &oracle_config(), &oracle_market()).unwrap_err(),
+            exponent_changed
+        );
+    }
+
+    #[test]
+    fn quality_gate_follows_publishers_and_status() {
+        let market = Market {
+            min_publishers: 5,
+            require_trading_status: true,
+            ..oracle_market()
+        };
+        let feeds = [
+            (MockPriceFeed::default(), true),
+            (MockPriceFeed { num_publishers: 5, ..Default::default() }, true),
+            (MockPriceFeed { num_publishers: 4, ..Default::default() }, false),
+            (MockPriceFeed { status: PriceStatus::Halted, ..Default::default() }, false),
+        ];
+        for (i, (feed, expected)) in feeds.into_iter().enumerate() {
+            let mut account = feed.account(pyth_program());
+            assert_eq!(oracle_quality_ok(&account.account_info(), &market).unwrap(), expected, "feed {i}");
+        }
+
+        // With the gate off even a halted feed with no publishers passes
+        let mut account = MockPriceFeed { num_publishers: 0, status: PriceStatus::Halted, ..Default::default() }
+            .account(pyth_program());
+        assert!(oracle_quality_ok(&account.account_info(), &oracle_market()).unwrap());
+    }
+
+    #[test]
+    fn degraded_close_settles_at_the_worse_price() {
+        // Longs get the lower of the two prices, shorts the higher, whichever one is stale
+        assert_eq!(degraded_close_price(true, 9_000, 10_000), 9_000);
+        assert_eq!(degraded_close_price(true, 11_000, 10_000), 10_000);
+        assert_eq!(degraded_close_price(false, 9_000, 10_000), 10_000);
+        assert_eq!(degraded_close_price(false, 11_000, 10_000), 11_000);
+
+        // A pump on a thin feed does not pay a long more than the last good price would
+        let position = priced_position(true, 1_000, 10_000, 10_000);
+        let market = Market::default();
+        let price = degraded_close_price(true, 15_000, 10_000);
+        assert_eq!(calculate_pnl(&position, &market, price).unwrap().0, 0);
+    }
+
+    #[test]
+    fn mul_div_rounds_floor_down_and_ceil_up() {
+        // (a, b, c, a * b / c rounded down, rounded up)
+        let table: [(u64, u64, u64, u64, u64); 10] = [
+            (0, 5, 3, 0, 0),
+            (1, 1, 1, 1, 1),
+            (1, 1, 3, 0, 1),
+            (2, 1, 3, 0, 1),
+            (10, 3, 4, 7, 8),
+            (10, 4, 4, 10, 10),
+            (7, 11, 13, 5, 6),
+            (u64::MAX, 1, 2, u64::MAX / 2, u64::MAX / 2 + 1),
+            (u64::MAX, u64::MAX, u64::MAX, u64::MAX, u64::MAX),
+            (u64::MAX, 10_000, 10_000, u64::MAX, u64::MAX),
+        ];
+        for (a, b, c, floor, ceil) in table {
+            assert_eq!(mul_div_floor(a, b, c), Ok(floor), "floor({a} * {b} / {c})");
+            assert_eq!(mul_div_ceil(a, b, c), Ok(ceil), "ceil({a} * {b} / {c})");
+        }
+    }
+
+    #[test]
+    fn mul_div_rejects_division_by_zero_and_overflow() {
+        assert_eq!(mul_div_floor(1, 1, 0), Err(MathError::DivisionByZero));
+        assert_eq!(mul_div_ceil(1, 1, 0), Err(MathError::DivisionByZero));
+        assert_eq!(mul_div_floor(u64::MAX, 3, 2), Err(MathError::Overflow));
+        assert_eq!(mul_div_ceil(u64::MAX, 3, 2), Err(MathError::Overflow));
+
+        // (2^65 - 1) / 2 fits a u64 when rounded down but not when rounded up
+        let a = 1_190_112_520_884_487_201; // (2^65 - 1) / 31
+        assert_eq!(mul_div_floor(a, 31, 2), Ok(u64::MAX));
+        assert_eq!(mul_div_ceil(a, 31, 2), Err(MathError::Overflow));
+    }
+
+    #[test]
+    fn rebate_shares_pay_out_the_whole_pool_exactly() {
+        let mut rebate_epoch = RebateEpoch {
+            epoch: 1,
+            mint: Pubkey::default(),
+            total_pool: 1_000_000,
+            remaining_pool: 1_000_000,
+            total_volume: 31,
+            remaining_volume: 31,
+            claim_deadline: 0,
+            swept: false,
+            bump: 0,
+        };
+
+        // 7/31, 11/31 and 13/31 of the pool, each rounded down against what the earlier claims left
+        assert_eq!(take_rebate_share(&mut rebate_epoch, 7).unwrap(), 225_806);
+        assert_eq!(take_rebate_share(&mut rebate_epoch, 11).unwrap(), 354_838);
+        assert_eq!(take_rebate_share(&mut rebate_epoch, 13).unwrap(), 419_356);
+        assert_eq!(rebate_epoch.remaining_pool, 0);
+        assert_eq!(rebate_epoch.remaining_volume, 0);
+    }
+
+    fn sample_snapshot() -> PositionSnapshot {
+        PositionSnapshot {
+            version: ATTESTATION_VERSION,
+            position: Pubkey::new_from_array([1; 32]),
+            trader: Pubkey::new_from_array([2; 32]),
+            market: Pubkey::new_from_array([3; 32]),
+            market_id: [4; 32],
+            is_long: true,
+            collateral: 1_000_000_000,
+            size: 5_000_000_000,
+            base_size: 50_000_000,
+            entry_price: 6_500_000_000_000,
+            price_exponent: -8,
+            leverage: 5,
+            open_time: 1_700_000_000,
+            last_funding_index: -42,
+            oracle_price: 6_512_345_678_900,
+            oracle_exponent: -8,
+            oracle_publish_time: 1_700_003_600,
+            contract_type: ContractType::Inverse,
+            liquidation_threshold: 80,
+            max_leverage: 20,
+            market_funding_index: -40,
+            slot: 250_000_000,
+            timestamp: 1_700_003_601,
+        }
+    }
+
+    #[test]
+    fn position_snapshot_encoding_matches_documented_offsets() {
+        let bytes = sample_snapshot().try_to_vec().unwrap();
+        assert_eq!(bytes.len(), PositionSnapshot::LEN);
+        assert_eq!(bytes[0], ATTESTATION_VERSION);
+        assert_eq!(bytes[129], 1); // is_long
+        assert_eq!(bytes[130..138], 1_000_000_000u64.to_le_bytes()); // collateral
+        assert_eq!(bytes[162..166], (-8i32).to_le_bytes()); // price_exponent
+        assert_eq!(bytes[204], 1); // contract_type
+        assert_eq!(bytes[225..233], 1_700_003_601i64.to_le_bytes()); // timestamp
+    }
+
+    #[test]
+    fn attestation_hash_matches_golden_vectors() {
+        // Verifiers on other chains recompute these; a change here is a breaking change to ATTESTATION_VERSION
+        let zeroed = PositionSnapshot {
+            version: 0,
+            position: Pubkey::default(),
+            trader: Pubkey::default(),
+            market: Pubkey::default(),
+            market_id: [0; 32],
+            is_long: false,
+            collateral: 0,
+            size: 0,
+            base_size: 0,
+            entry_price: 0,
+            price_exponent: 0,
+            leverage: 0,
+            open_time: 0,
+            last_funding_index: 0,
+            oracle_price: 0,
+            oracle_exponent: 0,
+            oracle_publish_time: 0,
+            contract_type: ContractType::Linear,
+            liquidation_threshold: 0,
+            max_leverage: 0,
+            market_funding_index: 0,
+            slot: 0,
+            timestamp: 0,
+        };
+        assert_eq!(
+            attestation_hash(&zeroed).unwrap(),
+            [
+                0xe4, 0x3f, 0xe9, 0x6a, 0x7f, 0x7e, 0xc0, 0xa3, 0x89, 0x84, 0xf7, 0x8c, 0x06, 0x46, 0x38, 0xb2,
+                0xda, 0xa7, 0x5e, 0x26, 0x1a, 0xb4, 0x09, 0xbb, 0xbe, 0x2d, 0x3e, 0x59, 0x02, 0x65, 0xec, 0x7b,
+            ]
+        );
+        assert_eq!(
+            attestation_hash(&sample_snapshot()).unwrap(),
+            [
+                0x00, 0x5c, 0x5f, 0x7e, 0xb4, 0xc1, 0x8d, 0xeb, 0x61, 0xa6, 0xc9, 0x6c, 0x91, 0x9c, 0xc2, 0xd0,
+                0xc5, 0xc6, 0x88, 0x9a, 0x9d, 0x7f, 0xad, 0xac, 0xca, 0x38, 0x24, 0xc9, 0x91, 0x56, 0x4b, 0xcc,
+            ]
+        );
+    }
+
+    #[test]
+    fn math_errors_surface_under_their_pinned_codes() {
+        assert_eq!(u32::from(AsterDexError::MathOverflow), 6012);
+        assert_eq!(u32::from(AsterDexError::DivisionByZero), 6074);
+        assert_eq!(u32::from(AsterDexError::PrecisionLoss), 6075);
+
+        // Handlers propagate MathError with `?`, so the conversion has to land on the same codes
+        let code = |error: MathError| match Error::from(error) {
+            Error::AnchorError(error) => error.error_code_number,
+            Error::ProgramError(_) => unreachable!(),
+        };
+        assert_eq!(code(MathError::Overflow), 6012);
+        assert_eq!(code(MathError::DivisionByZero), 6074);
+        assert_eq!(code(MathError::PrecisionLoss), 6075);
+    }
+
+    #[test]
+    fn account_lens_match_their_serialized_size() {
+        // Every account layout is fixed-size, so the default instance serializes to exactly LEN bytes
+        fn serialized_len<T: AnchorSerialize + Default>() -> usize {
+            T::default().try_to_vec().unwrap().len()
+        }
+        assert_eq!(serialized_len::<ProgramConfig>(), ProgramConfig::LEN);
+        assert_eq!(serialized_len::<Market>(), Market::LEN);
+        assert_eq!(serialized_len::<MarketStats>(), MarketStats::LEN);
+        assert_eq!(serialized_len::<Attestation>(), Attestation::LEN);
+        assert_eq!(serialized_len::<MarketPage>(), MarketPage::LEN);
+        assert_eq!(serialized_len::<ParamHistory>(), ParamHistory::LEN);
+        assert_eq!(serialized_len::<Position>(), Position::LEN);
+        assert_eq!(serialized_len::<TraderAccount>(), TraderAccount::LEN);
+        assert_eq!(serialized_len::<KeeperAccount>(), KeeperAccount::LEN);
+        assert_eq!(serialized_len::<WithdrawalEscrow>(), WithdrawalEscrow::LEN);
+        assert_eq!(serialized_len::<RebateEpoch>(), RebateEpoch::LEN);
+        assert_eq!(serialized_len::<RebateClaim>(), RebateClaim::LEN);
+        assert_eq!(serialized_len::<TraderHistory>(), TraderHistory::LEN);
+    }
+
+    #[test]
+    fn flagged_position_is_blocked_until_the_flag_clears() {
+        let under_liquidation: Error = AsterDexError::PositionUnderLiquidation.into();
+        let mut position = Position {
+            liquidatable_flagged_at: 100,
+            breach_price: 45_000,
+            ..priced_position(true, 1_000, 5_000, 50_000)
+        };
+        assert_eq!(require_not_under_liquidation(&position).unwrap_err(), under_liquidation);
+
+        clear_liquidation_flag(&mut position);
+        assert!(require_not_under_liquidation(&position).is_ok());
+    }
+
+    #[test]
+    fn flag_on_an_empty_slot_does_not_carry_into_the_open() {
+        let market = Market {
+            liquidation_threshold: 10,
+            liquidation_delay_protection: true,
+            max_liquidation_delay_secs: 60,
+            ..Default::default()
+        };
+
+        // An empty slot reads as liquidatable but can never clear the band, so a flag on it would stick
+        let mut slot = Position {
+            liquidatable_flagged_at: 100,
+            breach_price: 1,
+            ..Default::default()
+        };
+        assert!(is_liquidatable(&slot, &market, 50_000).unwrap());
+        assert!(!clears_liquidation_band(&slot, &market, 50_000).unwrap());
+
+        // Opening into a flagged slot is refused rather than inheriting the stale breach price
+        let under_liquidation: Error = AsterDexError::PositionUnderLiquidation.into();
+        assert_eq!(require_not_under_liquidation(&slot).unwrap_err(), under_liquidation);
+
+        // With the flag dropped the same position earns no delay compensation off the old breach price
+        slot.collateral = 1_000;
+        slot.size = 5_000;
+        slot.entry_price = 50_000;
+        slot.leverage = 5;
+        slot.is_long = true;
+        clear_liquidation_flag(&mut slot);
+        assert_eq!(slot.liquidatable_flagged_at, 0);
+        assert_eq!(slot.breach_price, 0);
+        assert_eq!(late_liquidation_compensation(&slot, &market, 30, 0, 30, 1_000, 1_000).unwrap(), (30, 0, 0));
+    }
+
+    #[test]
+    fn archived_sweep_drains_fees_then_pools_then_insurance() {
+        let mut market = Market {
+            accumulated_fees: 100,
+            skew_premium_pool: 50,
+            funding_pool_balance: 30,
+            insurance_fund_balance: 1_000,
+            ..Default::default()
+        };
+
+        // A sweep trimmed by the daily limit leaves the insurance fund for last
+        drain_protocol_balances(&mut market, 200);
+        assert_eq!(market.accumulated_fees, 0);
+        assert_eq!(market.total_fees_swept, 100);
+        assert_eq!(market.skew_premium_pool, 0);
+        assert_eq!(market.funding_pool_balance, 0);
+        assert_eq!(market.insurance_fund_balance, 980);
+
+        // The rest of the vault, including dust no balance accounts for, empties the fund
+        drain_protocol_balances(&mut market, 985);
+        assert_eq!(market.insurance_fund_balance, 0);
+        assert_eq!(market.total_fees_swept, 100);
+    }
+
+    #[test]
+    fn haircuts_are_pro_rata_to_profit() {
+        let mut market = Market {
+            socialized_shortfall: 1_000_000,
+            haircut_bps: 1_000,
+            ..Default::default()
+        };
+
+        // 10% of each winner's profit, rounded up against the winner
+        assert_eq!(take_haircut(&mut market, 100_000).unwrap(), 10_000);
+        assert_eq!(take_haircut(&mut market, 250_000).unwrap(), 25_000);
+        assert_eq!(take_haircut(&mut market, 333_333).unwrap(), 33_334);
+        assert_eq!(take_haircut(&mut market, 0).unwrap(), 0);
+        assert_eq!(market.socialized_shortfall, 1_000_000 - 68_334);
+        assert_eq!(market.total_haircuts_collected, 68_334);
+        assert_eq!(market.haircut_bps, 1_000);
+    }
+
+    #[test]
+    fn haircuts_never_exceed_the_shortfall() {
+        let mut market = Market {
+            socialized_shortfall: 50,
+            haircut_bps: 5_000,
+            total_shortfall_recorded: 50,
+            ..Default::default()
+        };
+
+        // The second haircut is capped at what is left; after that profits are paid in full
+        assert_eq!(take_haircut(&mut market, 60).unwrap(), 30);
+        assert_eq!(take_haircut(&mut market, 60).unwrap(), 20);
+        assert_eq!(take_haircut(&mut market, 100).unwrap(), 0);
+        assert_eq!(market.socialized_shortfall, 0);
+        assert_eq!(market.haircut_bps, 0);
+        assert_eq!(market.total_haircuts_collected, market.total_shortfall_recorded);
+    }
+
+    fn funded_position(is_long: bool, size: u64, collateral: u64, last_funding_index: i64) -> Position {
+        Position {
+            is_long,
+            size,
+            collateral,
+            last_funding_index,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn funding_index_charges_opposite_sides_equally() {
+        let long = funded_position(true, 10_000, 1_000_000, 0);
+        let short = funded_position(false, 10_000, 1_000_000, 0);
+
+        // A rising index has longs pay shorts; a falling one the reverse, by the same amount
+        assert_eq!(calculate_funding_owed(&long, 5).unwrap(), 5);
+        assert_eq!(calculate_funding_owed(&short, 5).unwrap(), -5);
+        assert_eq!(calculate_funding_owed(&long, -5).unwrap(), -5);
+        assert_eq!(calculate_funding_owed(&short, -5).unwrap(), 5);
+
+        // Fractions round against the payer in either direction
+        let long = funded_position(true, 3, 1_000_000, 0);
+        let short = funded_position(false, 3, 1_000_000, 0);
+        assert_eq!(calculate_funding_owed(&long, 1).unwrap(), 1);
+        assert_eq!(calculate_funding_owed(&short, 1).unwrap(), 0);
+        assert_eq!(calculate_funding_owed(&long, -1).unwrap(), 0);
+        assert_eq!(calculate_funding_owed(&short, -1).unwrap(), 1);
+
+        // A payer never owes more than its collateral
+        assert_eq!(calculate_funding_owed(&funded_position(true, 10_000, 2, 0), 5).unwrap(), 2);
+    }
+
+    #[test]
+    fn funding_rate_sign_follows_the_skew() {
+        let mut market = Market {
+            open_interest_long: 3_000,
+            open_interest_short: 1_000,
+            funding_rate_bps: 10,
+            ..Default::default()
+        };
+        assert_eq!(calculate_funding_rate(&market).unwrap(), 5);
+
+        std::mem::swap(&mut market.open_interest_long, &mut market.open_interest_short);
+        assert_eq!(calculate_funding_rate(&market).unwrap(), -5);
+
+        market.max_funding_rate_bps_per_interval = 3;
+        assert_eq!(clamp_funding_rate(&market, 5), 3);
+        assert_eq!(clamp_funding_rate(&market, -5), -3);
+    }
+
+    #[test]
+    fn fixed_point_encoding_is_pinned() {
+        // The raw value is the number times 1e12; a scale change would break every stored value
+        assert_eq!(FixedPoint::ONE.try_to_vec().unwrap(), vec![0, 16, 165, 212, 232, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(FixedPoint::from_raw(-1).try_to_vec().unwrap(), vec![255; 16]);
+
+        let price = Price { price: 6_512_345_678_900, conf: 0, expo: -8, publish_time: 0 };
+        let fixed = FixedPoint::from_pyth(&price).unwrap();
+        assert_eq!(fixed.raw(), 65_123_456_789_000_000);
+        assert_eq!(fixed.try_to_vec().unwrap(), vec![64, 47, 116, 92, 112, 93, 231, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(FixedPoint::try_from_slice(&fixed.try_to_vec().unwrap()).unwrap(), fixed);
+    }
+
+    #[test]
+    fn fixed_point_conversions_truncate_toward_zero() {
+        let fixed = FixedPoint::from_mantissa(6_512_345_678_900, -8).unwrap();
+        assert_eq!(fixed.to_mantissa(NORMALIZED_PRICE_EXPONENT).unwrap(), 65_123_456_789);
+        assert_eq!(fixed.to_mantissa(-2).unwrap(), 6_512_345);
+        assert_eq!(fixed.trunc(), 65_123);
+        assert_eq!(FixedPoint::from_mantissa(-6_512_345_678_900, -8).unwrap().to_mantissa(-2).unwrap(), -6_512_345);
+
+        // Finer than the scale truncates, coarser is exact
+        assert_eq!(FixedPoint::from_mantissa(1_999, -15).unwrap(), FixedPoint::from_raw(1));
+        assert_eq!(FixedPoint::from_mantissa(7, 3).unwrap(), FixedPoint::from_int(7_000).unwrap());
+
+        assert_eq!(FixedPoint::from_token_amount(2_500_000, 6).unwrap(), FixedPoint::from_ratio(5, 2).unwrap());
+        assert_eq!(FixedPoint::from_ratio(5, 2).unwrap().to_token_amount(6).unwrap(), 2_500_000);
+        assert_eq!(FixedPoint::from_ratio(1, 3).unwrap().to_token_amount(9).unwrap(), 333_333_333);
+        assert_eq!(FixedPoint::from_ratio(-1, 3).unwrap(), FixedPoint::from_raw(-333_333_333_333));
+        assert_eq!(FixedPoint::from_int(-1).unwrap().to_token_amount(6), Err(MathError::Overflow));
+
+        // normalize_price goes through the same conversion
+        assert_eq!(normalize_price(6_512_345_678_900, -8).unwrap(), 65_123_456_789);
+        assert_eq!(normalize_price(65_123, -3).unwrap(), 65_123_000);
+    }
+
+    #[test]
+    fn fixed_point_arithmetic_is_checked() {
+        let half = FixedPoint::from_ratio(1, 2).unwrap();
+        let three = FixedPoint::from_int(3).unwrap();
+        assert_eq!(three.checked_mul(half).unwrap(), FixedPoint::from_ratio(3, 2).unwrap());
+        assert_eq!(three.checked_div(half).unwrap(), FixedPoint::from_int(6).unwrap());
+        assert_eq!(three.checked_sub(half).unwrap().checked_add(half).unwrap(), three);
+        assert_eq!(half.checked_mul_int(-4).unwrap(), FixedPoint::from_int(-2).unwrap());
+
+        assert_eq!(three.checked_div(FixedPoint::ZERO), Err(MathError::DivisionByZero));
+        assert_eq!(FixedPoint::from_ratio(1, 0), Err(MathError::DivisionByZero));
+        assert_eq!(FixedPoint::from_raw(i128::MAX).checked_add(FixedPoint::from_raw(1)), Err(MathError::Overflow));
+        assert_eq!(FixedPoint::from_raw(i128::MAX).checked_mul(three), Err(MathError::Overflow));
+        assert_eq!(FixedPoint::from_int(i128::MAX / 1_000), Err(MathError::Overflow));
+        assert_eq!(FixedPoint::from_mantissa(1, 40), Err(MathError::Overflow));
+    }
+
+    #[test]
+    fn funding_owed_at_the_index_boundaries() {
+        // The full i64 index range times a u64 size is past i128 and is rejected rather than wrapping
+        let overflow: Error = AsterDexError::MathOverflow.into();
+        let long = funded_position(true, u64::MAX, u64::MAX, i64::MIN);
+        assert_eq!(calculate_funding_owed(&long, i64::MAX).unwrap_err(), overflow);
+
+        // Just inside i128: the payer is capped at its collateral, a receiver owed more than an i64 is rejected
+        let long = funded_position(true, 1 << 63, 1_000, i64::MIN);
+        let short = funded_position(false, 1 << 63, 1_000, i64::MIN);
+        assert_eq!(calculate_funding_owed(&long, i64::MAX).unwrap(), 1_000);
+        assert_eq!(calculate_funding_owed(&short, i64::MAX).unwrap_err(), overflow);
+
+        // Across the whole index range on a unit position
+        let short = funded_position(false, 1, 1_000, i64::MIN);
+        assert_eq!(calculate_funding_owed(&short, i64::MAX).unwrap(), -((u64::MAX / 10_000) as i64));
+    }
+
+    fn bracket(size_threshold: u64, liquidation_threshold: u16) -> ThresholdBracket {
+        ThresholdBracket {
+            size_threshold,
+            liquidation_threshold,
+        }
+    }
+
+    #[test]
+    fn threshold_brackets_apply_from_their_exact_size() {
+        let mut market = Market {
+            liquidation_threshold: 10,
+            threshold_brackets: [bracket(1_000, 15), bracket(5_000, 20), bracket(10_000, 30), bracket(50_000, 40)],
+            ..Default::default()
+        };
+        assert!(validate_threshold_brackets(&market).is_ok());
+
+        let cases = [
+            (0, 10),
+            (999, 10),
+            (1_000, 15),
+            (4_999, 15),
+            (5_000, 20),
+            (9_999, 20),
+            (10_000, 30),
+            (49_999, 30),
+            (50_000, 40),
+            // Past the last tier the largest bracket keeps applying
+            (u64::MAX, 40),
+        ];
+        for (size, threshold) in cases {
+            assert_eq!(bracket_liquidation_threshold(&market, size), threshold, "size {size}");
+        }
+
+        // Unused brackets are ignored, so the last one in use is the fallback
+        market.threshold_brackets[2] = ThresholdBracket::default();
+        market.threshold_brackets[3] = ThresholdBracket::default();
+        assert_eq!(bracket_liquidation_threshold(&market, u64::MAX), 20);
+    }
+
+    #[test]
+    fn position_terms_pin_the_bracket_at_open() {
+        let mut market = Market {
+            liquidation_threshold: 10,
+            threshold_brackets: [bracket(1_000, 15), bracket(5_000, 20), bracket(0, 0), bracket(0, 0)],
+            ..Default::default()
+        };
+        let legacy = Position {
+            size: 5_000,
+            ..Default::default()
+        };
+        let pinned = Position {
+            size: 5_000,
+            params_pinned: true,
+            close_fee_bps: CLOSE_FEE_BPS as u16,
+            liquidation_penalty_bps: LIQUIDATION_REWARD_BPS as u16,
+            liquidation_threshold: bracket_liquidation_threshold(&market, 5_000),
+            ..Default::default()
+        };
+        assert_eq!(position_terms(&legacy, &market), (CLOSE_FEE_BPS, LIQUIDATION_REWARD_BPS, 20));
+        assert_eq!(position_terms(&pinned, &market), (CLOSE_FEE_BPS, LIQUIDATION_REWARD_BPS, 20));
+
+        // Raising the bracket later only reaches positions that were never pinned
+        market.threshold_brackets[1].liquidation_threshold = 25;
+        assert_eq!(position_terms(&legacy, &market).2, 25);
+        assert_eq!(position_terms(&pinned, &market).2, 20);
+    }
+
+    #[test]
+    fn liquidation_penalty_split_sums_to_the_penalty() {
+        let market = Market {
+            liquidator_share_bps: 7_000,
+            insurance_share_bps: 2_000,
+            treasury_share_bps: 1_000,
+            ..Default::default()
+        };
+        assert_eq!(split_liquidation_penalty(&market, 10_000).unwrap(), (7_000, 2_000, 1_000));
+
+        // The protocol shares round up, so the liquidator absorbs the rounding remainder
+        assert_eq!(split_liquidation_penalty(&market, 1_001).unwrap(), (699, 201, 101));
+        for penalty in [0, 1, 2, 3, 7, 999, 1_001, 123_457, u64::MAX / BPS_SCALE] {
+            let (liquidator, insurance, treasury) = split_liquidation_penalty(&market, penalty).unwrap();
+            assert_eq!(liquidator + insurance + treasury, penalty, "penalty {penalty}");
+        }
+
+        // Treasury is capped at what insurance leaves, even when both round up to the whole penalty
+        let protocol_only = Market {
+            insurance_share_bps: 5_000,
+            treasury_share_bps: 5_000,
+            ..Default::default()
+        };
+        assert_eq!(split_liquidation_penalty(&protocol_only, 1).unwrap(), (0, 1, 0));
+
+        // Markets that never set a split pay the liquidator everything
+        assert_eq!(split_liquidation_penalty(&Market::default(), 1_001).unwrap(), (1_001, 0, 0));
+    }
+
+    #[test]
+    fn flag_clears_only_past_the_hysteresis_band() {
+        // 1x long with no close fee: equity in bps of collateral equals the price, and the threshold is 1000 bps
+        let position = Position {
+            is_long: true,
+            collateral: 10_000,
+            size: 10_000,
+            entry_price: 10_000,
+            leverage: 1,
+            params_pinned: true,
+            liquidation_threshold: 10,
+            ..Default::default()
+        };
+        let mut market = Market {
+            liquidation_hysteresis_bps: 500,
+            ..Default::default()
+        };
+        assert!(is_liquidatable(&position, &market, 1_000).unwrap());
+        assert!(!clears_liquidation_band(&position, &market, 1_000).unwrap());
+        assert!(!clears_liquidation_band(&position, &market, 1_500).unwrap());
+        assert!(clears_liquidation_band(&position, &market, 1_501).unwrap());
+
+        // Without a band a flag clears exactly when the position stops being liquidatable
+        market.liquidation_hysteresis_bps = 0;
+        assert!(!clears_liquidation_band(&position, &market, 1_000).unwrap());
+        assert!(is_liquidatable(&position, &market, 1_099).unwrap());
+        assert!(!clears_liquidation_band(&position, &market, 1_099).unwrap());
+        assert!(!is_liquidatable(&position, &market, 1_100).unwrap());
+        assert!(clears_liquidation_band(&position, &market, 1_100).unwrap());
+    }
+
+    fn priced_position(is_long: bool, collateral: u64, size: u64, entry_price: u64) -> Position {
+        Position {
+            is_long,
+            collateral,
+            size,
+            entry_price,
+            leverage: (size / collateral) as u16,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn linear_and_inverse_pnl_vectors() {
+        let linear = Market::default();
+        let inverse = Market {
+            contract_type: ContractType::Inverse,
+            ..Default::default()
+        };
+
+        // Linear PnL is size * delta / entry in the quote token, rounded down against the trader
+        assert_eq!(calculate_pnl(&priced_position(true, 100, 1_000, 100), &linear, 110).unwrap(), (100, 1));
+        assert_eq!(calculate_pnl(&priced_position(false, 100, 1_000, 100), &linear, 110).unwrap(), (-100, 1));
+        assert_eq!(calculate_pnl(&priced_position(true, 10, 10, 3), &linear, 4).unwrap().0, 3);
+        assert_eq!(calculate_pnl(&priced_position(false, 10, 10, 3), &linear, 4).unwrap().0, -4);
+
+        // Inverse PnL is size * delta / exit in the base asset, so a move up pays longs less than a move down costs
+        assert_eq!(calculate_pnl(&priced_position(true, 100, 1_000, 100), &inverse, 125).unwrap().0, 200);
+        assert_eq!(calculate_pnl(&priced_position(false, 100, 1_000, 100), &inverse, 125).unwrap().0, -200);
+        assert_eq!(calculate_pnl(&priced_position(true, 100, 1_000, 100), &inverse, 80).unwrap().0, -250);
+        assert_eq!(calculate_pnl(&priced_position(false, 100, 1_000, 100), &inverse, 80).unwrap().0, 250);
+
+        let invalid_oracle: Error = AsterDexError::InvalidOracle.into();
+        assert_eq!(calculate_pnl(&priced_position(true, 100, 1_000, 100), &inverse, 0).unwrap_err(), invalid_oracle);
+    }
+
+    #[test]
+    fn liquidation_price_round_trips_through_is_liquidatable() {
+        let linear = Market {
+            liquidation_threshold: 10,
+            ..Default::default()
+        };
+        let inverse = Market {
+            liquidation_threshold: 10,
+            contract_type: ContractType::Inverse,
+            ..Default::default()
+        };
+
+        // 5x positions at 10_000; longs are liquidatable at and below the answer, shorts at and above it
+        for (market, is_long, expected) in [
+            (&linear, true, 8_229),
+            (&linear, false, 11_771),
+            (&inverse, true, 8_496),
+            (&inverse, false, 12_151),
+        ] {
+            let position = priced_position(is_long, 1_000, 5_000, 10_000);
+            let liquidation_price = calculate_liquidation_price(&position, market).unwrap();
+            assert_eq!(liquidation_price, expected);
+            assert!(is_liquidatable(&position, market, liquidation_price).unwrap());
+            let healthy_side = if is_long { liquidation_price + 1 } else { liquidation_price - 1 };
+            assert!(!is_liquidatable(&position, market, healthy_side).unwrap());
+        }
+    }
+
+    fn skewed_market(skew_premium_pool: u64) -> Market {
+        Market {
+            open_interest_long: 10_000,
+            open_interest_short: 0,
+            open_skew_premium_bps: 100,
+            open_skew_rebate_bps: 50,
+            skew_premium_pool,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn skew_rebate_only_on_opens_that_reduce_skew() {
+        // A short against a long-heavy book narrows the skew and earns the rebate, rounded down
+        let mut market = skewed_market(1_000);
+        assert_eq!(apply_open_skew_fee(&mut market, false, 4_000).unwrap(), (0, 20));
+        assert_eq!(market.skew_premium_pool, 980);
+        assert_eq!(market.total_skew_rebates_paid, 20);
+
+        // A long on the crowded side pays the premium, rounded up, into the pool
+        let mut market = skewed_market(1_000);
+        assert_eq!(apply_open_skew_fee(&mut market, true, 1_001).unwrap(), (11, 0));
+        assert_eq!(market.skew_premium_pool, 1_011);
+        assert_eq!(market.total_skew_premiums_collected, 11);
+
+        // A short big enough to flip the book leaves a larger skew the other way, so it pays too
+        let mut market = skewed_market(1_000);
+        assert_eq!(apply_open_skew_fee(&mut market, false, 25_000).unwrap(), (250, 0));
+
+        // On a balanced book every open widens the skew
+        let mut market = skewed_market(1_000);
+        market.open_interest_short = 10_000;
+        assert_eq!(apply_open_skew_fee(&mut market, false, 1_000).unwrap(), (10, 0));
+    }
+
+    #[test]
+    fn skew_rebate_is_capped_by_the_premium_pool() {
+        let mut market = skewed_market(5);
+        assert_eq!(apply_open_skew_fee(&mut market, false, 4_000).unwrap(), (0, 5));
+        assert_eq!(market.skew_premium_pool, 0);
+
+        // An empty pool pays nothing, and the open is not charged a premium instead
+        assert_eq!(apply_open_skew_fee(&mut market, false, 4_000).unwrap(), (0, 0));
+        assert_eq!(market.total_skew_rebates_paid, 5);
+    }
+
+    #[test]
+    fn fee_distribution_splits_by_percentage() {
+        assert_eq!(split_fees(10_000, 2_500).unwrap(), (2_500, 7_500));
+        assert_eq!(split_fees(10_000, 10_000).unwrap(), (10_000, 0));
+        assert_eq!(split_fees(0, 2_500).unwrap(), (0, 0));
+
+        // The distributed share rounds down and the remainder stays accrued, so nothing is lost or invented
+        assert_eq!(split_fees(999, 3_333).unwrap(), (332, 667));
+        for accrued in [1, 7, 999, 123_457, u64::MAX] {
+            let (share, retained) = split_fees(accrued, 3_333).unwrap();
+            assert_eq!(share + retained, accrued);
+        }
+    }
+
+    #[test]
+    fn fee_distribution_rejects_a_destination_other_than_the_configured_one() {
+        let mint = Pubkey::new_from_array([1; 32]);
+        let staking_hook = Pubkey::new_from_array([2; 32]);
+        let config = ProgramConfig {
+            distribution_destination: staking_hook,
+            distribution_bps: 2_500,
+            ..Default::default()
+        };
+        assert!(validate_distribution_destination(&config, staking_hook, mint, mint).is_ok());
+
+        let wrong_destination: Error = AsterDexError::InvalidDistributionDestination.into();
+        let other = Pubkey::new_from_array([3; 32]);
+        assert_eq!(validate_distribution_destination(&config, other, mint, mint).unwrap_err(), wrong_destination);
+
+        let wrong_mint: Error = AsterDexError::InvalidMint.into();
+        assert_eq!(validate_distribution_destination(&config, staking_hook, other, mint).unwrap_err(), wrong_mint);
+
+        let disabled: Error = AsterDexError::FeeDistributionDisabled.into();
+        let unconfigured = ProgramConfig::default();
+        assert_eq!(validate_distribution_destination(&unconfigured, Pubkey::default(), mint, mint).unwrap_err(), disabled);
+    }
 }